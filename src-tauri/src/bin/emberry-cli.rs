@@ -0,0 +1,386 @@
+//! Headless emberry client for scripts, bots and smoke tests
+//!
+//! Uses the identity, database and server profiles of the desktop app.
+//! Chat messages are read line by line from stdin and written line by line to stdout,
+//! everything else is reported on stderr.
+
+use std::{
+  collections::HashMap,
+  env,
+  io::{self, ErrorKind},
+  process::ExitCode,
+  sync::{Arc, Mutex},
+};
+
+use emberry_rs::{
+  data::{cert_gen, config, identity, servers::SERVERS, IdentifiedUserInfo, UserIdentifier},
+  network::{
+    ctrl_chnl::{self, requests::send_request, responses::send_answer, RhizomeConnection},
+    frontend::{Listener, Unlisten},
+    Frontend, Networking,
+  },
+};
+use serde_json::{json, Value};
+use smoke::messages::RoomId;
+use tokio::{
+  io::{AsyncBufReadExt, BufReader},
+  select,
+  sync::{mpsc, RwLock},
+};
+
+const USAGE: &str = "\
+usage: emberry-cli [--server <NAME>] <COMMAND>
+
+commands:
+  identity [--force]  generate a new identity (--force overwrites an existing one)
+  id                  print the bs58 identifier of the local identity
+  request <BS58>      connect to rhizome, request a room with <BS58> and chat using stdin/stdout
+  accept <BS58>       connect to rhizome, accept the room request of <BS58> and chat using stdin/stdout
+
+options:
+  --server <NAME>     use the server profile <NAME> instead of the active one
+  -h, --help          print this help
+
+environment:
+  EMBERRY_PASSPHRASE  passphrase used to unlock a protected identity";
+
+#[derive(Debug, PartialEq, Eq)]
+enum Command {
+  Help,
+  Identity { force: bool },
+  Id,
+  Request(String),
+  Accept(String),
+}
+
+/// [Frontend] forwarding every event to the chat session and dispatching stdin to listeners
+struct Cli {
+  events: mpsc::UnboundedSender<(String, Value)>,
+  listeners: Arc<Mutex<HashMap<String, Listener>>>,
+}
+
+impl Frontend for Cli {
+  fn emit_json(&self, event: &str, payload: Value) -> io::Result<()> {
+    self
+      .events
+      .send((event.to_string(), payload))
+      .map_err(|_| io::Error::new(ErrorKind::BrokenPipe, "chat session ended"))
+  }
+
+  fn notify(&self, title: &str, body: Option<&str>) {
+    log::info!("{}: {}", title, body.unwrap_or_default());
+  }
+
+  fn listen(&self, event: String, listener: Listener) -> Unlisten {
+    self
+      .listeners
+      .lock()
+      .unwrap()
+      .insert(event.clone(), listener);
+    let listeners = self.listeners.clone();
+    Box::new(move || {
+      listeners.lock().unwrap().remove(&event);
+    })
+  }
+}
+
+impl Cli {
+  /// Hands `payload` to the listener of `event` as if it was sent by a webview
+  fn dispatch(&self, event: &str, payload: &str) -> bool {
+    match self.listeners.lock().unwrap().get(event) {
+      Some(listener) => {
+        listener(Some(payload));
+        true
+      }
+      None => false,
+    }
+  }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> ExitCode {
+  env_logger::init();
+
+  let (server, command) = match parse_args(env::args().skip(1)) {
+    Ok(parsed) => parsed,
+    Err(msg) => {
+      eprintln!("{}\n\n{}", msg, USAGE);
+      return ExitCode::from(2);
+    }
+  };
+
+  if let Some(name) = server {
+    if let Err(err) = SERVERS.write().unwrap().select(&name) {
+      eprintln!("{}", err);
+      return ExitCode::FAILURE;
+    }
+  }
+
+  let res = match command {
+    Command::Help => {
+      println!("{}", USAGE);
+      Ok(())
+    }
+    Command::Identity { force } => identity(force),
+    Command::Id => print_id(),
+    command => chat(command).await,
+  };
+
+  match res {
+    Ok(()) => ExitCode::SUCCESS,
+    Err(err) => {
+      eprintln!("{}", err);
+      ExitCode::FAILURE
+    }
+  }
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<(Option<String>, Command), String> {
+  let mut server = None;
+  loop {
+    let arg = args.next().ok_or("missing command")?;
+    let command = match arg.as_str() {
+      "--server" => {
+        server = Some(args.next().ok_or("--server needs a profile name")?);
+        continue;
+      }
+      "identity" => match args.next().as_deref() {
+        None => Command::Identity { force: false },
+        Some("--force") => Command::Identity { force: true },
+        Some(arg) => return Err(format!("unexpected argument '{}'", arg)),
+      },
+      "id" => Command::Id,
+      "request" => Command::Request(args.next().ok_or("request needs a bs58 identifier")?),
+      "accept" => Command::Accept(args.next().ok_or("accept needs a bs58 identifier")?),
+      "-h" | "--help" => Command::Help,
+      cmd => return Err(format!("unknown command '{}'", cmd)),
+    };
+
+    if let Some(arg) = args.next() {
+      return Err(format!("unexpected argument '{}'", arg));
+    }
+    return Ok((server, command));
+  }
+}
+
+fn identity(force: bool) -> io::Result<()> {
+  let path = &config::PEM.filepath;
+  if path.exists() && !force {
+    return Err(io::Error::new(
+      ErrorKind::AlreadyExists,
+      format!(
+        "identity '{}' already exists, use --force to replace it",
+        path.to_string_lossy()
+      ),
+    ));
+  }
+
+  cert_gen::generate_cert(path)?;
+  print_id()
+}
+
+fn print_id() -> io::Result<()> {
+  let id: UserIdentifier = (&*config::PEM).try_into().map_err(|err: io::Error| {
+    io::Error::new(
+      err.kind(),
+      format!("no usable identity, generate one using 'identity': {}", err),
+    )
+  })?;
+  println!("{}", id.bs58);
+  Ok(())
+}
+
+/// Connects to rhizome, opens a room as described by `command`
+/// and relays chat messages between the room and stdin/stdout until stdin is closed
+async fn chat(command: Command) -> io::Result<()> {
+  if let Ok(passphrase) = env::var("EMBERRY_PASSPHRASE") {
+    identity::unlock(&passphrase)?;
+  }
+
+  let (tx, mut events) = mpsc::unbounded_channel();
+  let cli = Arc::new(Cli {
+    events: tx,
+    listeners: Default::default(),
+  });
+  let net = Networking {
+    chats: Default::default(),
+    pending: Default::default(),
+    groups: Default::default(),
+    reconnects: Default::default(),
+  };
+  let rc: RhizomeConnection = RwLock::new(None);
+
+  select! {
+    res = ctrl_chnl::run(cli.clone(), &net, &rc) => res.map_err(into_io),
+    res = session(&cli, &command, &net, &rc, &mut events) => res,
+  }
+}
+
+async fn session(
+  cli: &Cli,
+  command: &Command,
+  net: &Networking,
+  rc: &RhizomeConnection,
+  events: &mut mpsc::UnboundedReceiver<(String, Value)>,
+) -> io::Result<()> {
+  let mut stdin = BufReader::new(tokio::io::stdin()).lines();
+  let mut room: Option<String> = None;
+  let mut requested = false;
+  let mut eof = false;
+  let mut closing = false;
+  // chat messages handed to the room that were not confirmed as sent yet
+  let mut unsent = 0usize;
+
+  loop {
+    if eof && unsent == 0 && !closing {
+      // say goodbye to the peer and wait for the room to be closed
+      match room.as_deref().and_then(room_id) {
+        Some(id) if net.close(&id, cli) => closing = true,
+        _ => return Ok(()),
+      }
+    }
+
+    select! {
+      Some((event, payload)) = events.recv() => match event.as_str() {
+        "rz-con" => {
+          log::info!("connected to rhizome");
+          // after a reconnect the control channel sends the pending request again on its own
+          if let (Command::Request(bs58), false) = (command, requested) {
+            send_request(cli, bs58.clone(), net, rc).await.map_err(into_io)?;
+            requested = true;
+          }
+        }
+        "wants-room" => {
+          let info: IdentifiedUserInfo = serde_json::from_value(payload)?;
+          match command {
+            Command::Accept(bs58) if *info.identifier.bs58 == *bs58 => {
+              send_answer(bs58.clone(), true, net, rc).await.map_err(into_io)?
+            }
+            _ => log::info!("ignoring room request of '{}'", info.identifier.bs58),
+          }
+        }
+        "no-route" => {
+          return Err(io::Error::new(ErrorKind::NotFound, format!("user is not online: {}", payload)))
+        }
+        "new-room" => {
+          let id = payload["room_id"].as_str().unwrap_or_default().to_string();
+          log::info!("room '{}' with '{}' is open", id, payload["peer_id"]);
+          eprintln!("connected, type to chat");
+          room = Some(id);
+        }
+        "room-closed" if room.as_deref() == payload["room_id"].as_str() => {
+          log::info!("room closed: {}", payload);
+          return match payload["reason"].as_str() {
+            Some("local") => Ok(()),
+            Some("remote") => {
+              eprintln!("peer closed the room");
+              Ok(())
+            }
+            _ => {
+              let msg = format!("room lost: {}", payload);
+              Err(io::Error::new(ErrorKind::ConnectionAborted, msg))
+            }
+          };
+        }
+        event => match &room {
+          Some(id) if event == format!("message_recieved_{}", id) => {
+            if let Some(content) = payload["message"]["Chat"].as_str() {
+              println!("{}", content);
+            }
+          }
+          Some(id) if event == format!("message_sent_{}", id) => unsent = unsent.saturating_sub(1),
+          _ => log::info!("{}: {}", event, payload),
+        },
+      },
+      line = stdin.next_line(), if !eof => match line? {
+        Some(line) => match &room {
+          Some(id) => {
+            let payload = json!({ "Chat": line }).to_string();
+            if cli.dispatch(&format!("send_message_{}", id), &payload) {
+              unsent += 1;
+            }
+          }
+          None => eprintln!("no room yet, dropping: '{}'", line),
+        },
+        None => eof = true,
+      },
+    }
+  }
+}
+
+/// Parses the bs58 room id of the "new-room" event
+fn room_id(bs58: &str) -> Option<RoomId> {
+  let bytes = bs58::decode(bs58).into_vec().ok()?;
+  Some(RoomId(bytes.try_into().ok()?))
+}
+
+fn into_io(err: tauri::Error) -> io::Error {
+  match err {
+    tauri::Error::Io(err) => err,
+    err => io::Error::new(ErrorKind::Other, err.to_string()),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn parse(args: &[&str]) -> Result<(Option<String>, Command), String> {
+    parse_args(args.iter().map(|arg| arg.to_string()))
+  }
+
+  /// Tests if every command is parsed together with its arguments
+  #[test]
+  fn parse_commands() {
+    assert_eq!(parse(&["id"]), Ok((None, Command::Id)));
+    assert_eq!(
+      parse(&["identity"]),
+      Ok((None, Command::Identity { force: false }))
+    );
+    assert_eq!(
+      parse(&["identity", "--force"]),
+      Ok((None, Command::Identity { force: true }))
+    );
+    assert_eq!(
+      parse(&["--server", "local", "request", "abc"]),
+      Ok((
+        Some("local".to_string()),
+        Command::Request("abc".to_string())
+      ))
+    );
+    assert_eq!(
+      parse(&["accept", "abc"]),
+      Ok((None, Command::Accept("abc".to_string())))
+    );
+  }
+
+  /// Tests if asking for help is a command of its own instead of an error
+  #[test]
+  fn parse_help() {
+    assert_eq!(parse(&["--help"]), Ok((None, Command::Help)));
+    assert_eq!(parse(&["-h"]), Ok((None, Command::Help)));
+  }
+
+  /// Tests if missing, unknown and surplus arguments are rejected
+  #[test]
+  fn parse_invalid() {
+    assert_eq!(parse(&[]), Err("missing command".to_string()));
+    assert_eq!(
+      parse(&["--server"]),
+      Err("--server needs a profile name".to_string())
+    );
+    assert_eq!(
+      parse(&["request"]),
+      Err("request needs a bs58 identifier".to_string())
+    );
+    assert_eq!(parse(&["chat"]), Err("unknown command 'chat'".to_string()));
+    assert_eq!(
+      parse(&["id", "abc"]),
+      Err("unexpected argument 'abc'".to_string())
+    );
+    assert_eq!(
+      parse(&["identity", "--forced"]),
+      Err("unexpected argument '--forced'".to_string())
+    );
+  }
+}
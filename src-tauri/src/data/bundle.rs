@@ -0,0 +1,163 @@
+//! Moving an identity together with its contacts to another install
+//!
+//! A bundle is a PEM file with a single [BUNDLE_TAG] section holding the identity file
+//! and a [Backup] of the database, sealed using a password like a protected identity key.
+
+use std::{
+  fs,
+  io::{self, ErrorKind},
+  path::Path,
+};
+
+use rustls::Certificate;
+use serde::{Deserialize, Serialize};
+
+use super::{
+  cert_gen,
+  config::{self, PEM, PEM_DATA},
+  identity::{self, SealingKey, SEALING},
+  sqlite::{
+    backup::{self, Backup},
+    try_exec_blocking,
+  },
+  PemfileReader, UserIdentifier,
+};
+
+/// Label of the PEM section holding a sealed bundle
+pub const BUNDLE_TAG: &str = "EMBERRY IDENTITY BUNDLE";
+
+#[derive(Serialize, Deserialize)]
+struct Bundle {
+  /** Content of the identity file, the key is not protected on its own as the bundle is */
+  identity: String,
+  backup: Backup,
+}
+
+/// Stores the identity with every known user in a bundle at `path` protected by `password`,
+/// the chat history is included if `history` is `true`
+///
+/// # Errors
+/// This function will return:</br>
+/// [ErrorKind::PermissionDenied] if there is no identity or it is locked</br>
+/// Any [io::Error] from reading the database or writing the bundle
+pub fn export(path: &Path, password: &str, history: bool) -> io::Result<()> {
+  let (cert, key) = PEM_DATA.read().unwrap().clone().ok_or_else(|| {
+    io::Error::new(
+      ErrorKind::PermissionDenied,
+      "the identity is missing or locked",
+    )
+  })?;
+
+  let bundle = Bundle {
+    identity: cert_gen::encode_identity(&cert, &key, None)?,
+    backup: try_exec_blocking(move |db| backup::export(db, history))?,
+  };
+  let json = serde_json::to_vec(&bundle)?;
+  let sealed = SealingKey::derive(password)?.seal_data(&json)?;
+
+  let pem = pem::Pem {
+    tag: BUNDLE_TAG.to_string(),
+    contents: sealed,
+  };
+  let config = pem::EncodeConfig {
+    line_ending: pem::LineEnding::LF,
+  };
+  fs::write(path, pem::encode_config(&pem, config))?;
+  log::info!("exported identity bundle to: '{}'", path.to_string_lossy());
+  Ok(())
+}
+
+/// Outcome of [import]
+#[derive(Serialize, Debug)]
+pub struct Imported {
+  /** bs58 encoded certificate of the imported identity */
+  pub identity: String,
+  /** Whether the identity is protected by the passphrase of the one it replaced */
+  pub protected: bool,
+}
+
+/// Replaces the identity by the one in the bundle at `path` protected by `password`
+/// and adds the users and chat history of the bundle to the database
+///
+/// An existing identity is only replaced by a different one if `replace` is `true`.
+/// The imported identity is protected by the passphrase of the current one if it is unlocked,
+/// it is stored without passphrase otherwise (see [identity::protect]).</br>
+/// The database is only changed once the identity is installed.
+///
+/// # Errors
+/// This function will return:</br>
+/// [ErrorKind::PermissionDenied] if `password` is wrong</br>
+/// [ErrorKind::InvalidData] if the bundle or the identity in it is malformed</br>
+/// [ErrorKind::AlreadyExists] if there is a different identity and `replace` is `false`</br>
+/// Any [io::Error] from reading the bundle or writing the identity and database
+pub fn import(path: &Path, password: &str, replace: bool) -> io::Result<Imported> {
+  let pem =
+    pem::parse(fs::read(path)?).map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+  if pem.tag != BUNDLE_TAG {
+    return Err(io::Error::new(
+      ErrorKind::InvalidData,
+      "file is no identity bundle",
+    ));
+  }
+  let (json, _) = identity::open_data(&pem.contents, password)?;
+  let bundle: Bundle = serde_json::from_slice(&json)?;
+
+  // the identity is checked using the same reader that loads it later on
+  let staged = PemfileReader {
+    filepath: PEM.filepath.with_extension("pem.import"),
+  };
+  if let Some(dir) = staged.filepath.parent() {
+    fs::create_dir_all(dir)?;
+  }
+  fs::write(&staged.filepath, &bundle.identity)?;
+  let (cert, key) = match staged
+    .parse()
+    .and_then(|(cert, key)| cert_gen::verify_pair(&cert, &key).map(|_| (cert, key)))
+  {
+    Ok(pair) => pair,
+    Err(err) => {
+      let _ = fs::remove_file(&staged.filepath);
+      return Err(io::Error::new(
+        ErrorKind::InvalidData,
+        format!("bundle holds an invalid identity: {}", err),
+      ));
+    }
+  };
+
+  let current: Option<Certificate> = PEM.read().ok().map(|(cert, _)| cert);
+  if current.map_or(false, |current| current != cert) && !replace {
+    let _ = fs::remove_file(&staged.filepath);
+    return Err(io::Error::new(
+      ErrorKind::AlreadyExists,
+      "there is a different identity already",
+    ));
+  }
+
+  // a protected identity is not replaced by an unprotected one behind the back of the user
+  let sealing = SEALING.read().unwrap().clone();
+  if let Some(sealing) = &sealing {
+    if let Err(err) = cert_gen::write_identity(&staged.filepath, &cert, &key, Some(sealing)) {
+      let _ = fs::remove_file(&staged.filepath);
+      return Err(err);
+    }
+  }
+
+  fs::rename(&staged.filepath, &PEM.filepath)?;
+  config::reload();
+  // a sealed identity is not loaded by reloading, but its key is known here
+  *PEM_DATA.write().unwrap() = Some((cert.clone(), key));
+  log::info!(
+    "imported identity bundle from: '{}'",
+    path.to_string_lossy()
+  );
+
+  let backup = bundle.backup;
+  try_exec_blocking(move |db| backup::import(db, &backup))?;
+
+  Ok(Imported {
+    identity: UserIdentifier::from(&smoke::User { cert_data: cert.0 })
+      .bs58
+      .into_owned(),
+    protected: sealing.is_some(),
+  })
+}
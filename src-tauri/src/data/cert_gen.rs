@@ -1,11 +1,27 @@
 use log::info;
+use pem::{encode_many_config, EncodeConfig, LineEnding, Pem};
 use rcgen::generate_simple_self_signed;
-#[allow(unused_imports)] // doc import
-use rustls::{Certificate, PrivateKey};
+use rustls::{sign::any_ecdsa_type, Certificate, PrivateKey, SignatureScheme};
+use serde::{Deserialize, Serialize};
 use std::fs::{DirBuilder, OpenOptions};
 use std::io::{ErrorKind, Write};
 use std::{io, path::PathBuf};
 
+use super::identity::{SealingKey, SEALED_KEY_TAG};
+
+/// Proof that the owner of an identity replaced it by the certificate `cert`
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct RotationNotice {
+  /** DER encoded certificate of the new identity */
+  pub cert: Vec<u8>,
+  /** ECDSA P-256 SHA-256 signature over [ROTATION_CONTEXT] followed by `cert` made with the key of the old identity */
+  pub signature: Vec<u8>,
+}
+
+/// Prefix of the signed payload of a [RotationNotice], so that no other signature
+/// made with the identity key can pass as one
+const ROTATION_CONTEXT: &[u8] = b"emberry-rotation";
+
 /// Generates a new pair of self signed [`X509 Certificate`](Certificate) and [`PKCS8 Key`](PrivateKey)
 /// and stores them in pemfile in the order Certificate, PrivateKey
 ///
@@ -14,8 +30,94 @@ use std::{io, path::PathBuf};
 /// # Errors
 /// Will return any errors from creating and writing "pemfile"
 pub fn generate_cert(pemfile: &PathBuf) -> io::Result<()> {
-  let subject_alt_names = vec!["embery_user".to_string()];
-  let cert = generate_simple_self_signed(subject_alt_names).unwrap();
+  let (cert, key) = generate();
+  write_identity(pemfile, &cert, &key, None)
+}
+
+/// Replaces the identity in pemfile by a newly generated one like [generate_cert]
+/// and signs the new certificate using `old_key`, the key of the replaced identity
+///
+/// The new key is protected using `sealing` if there is one, see [write_identity].</br>
+/// Returns the new certificate and key together with the [RotationNotice] for the peers
+///
+/// # Errors
+/// This function will return:</br>
+/// [ErrorKind::InvalidInput] if `old_key` is no ECDSA P-256 key</br>
+/// Any errors from creating and writing "pemfile"
+pub fn rotate_cert(
+  pemfile: &PathBuf,
+  old_key: &PrivateKey,
+  sealing: Option<&SealingKey>,
+) -> io::Result<(Certificate, PrivateKey, RotationNotice)> {
+  let (new_cert, new_key) = generate();
+  let notice = sign_rotation(old_key, &new_cert)?;
+
+  write_identity(pemfile, &new_cert, &new_key, sealing)?;
+  Ok((new_cert, new_key, notice))
+}
+
+/// Signs `new_cert` using `old_key` to prove that it replaces the identity of `old_key`
+///
+/// # Errors
+/// This function will return:</br>
+/// [ErrorKind::InvalidInput] if `old_key` is no ECDSA P-256 key</br>
+/// [ErrorKind::Other] if signing failed
+pub fn sign_rotation(old_key: &PrivateKey, new_cert: &Certificate) -> io::Result<RotationNotice> {
+  let invalid_key = || {
+    io::Error::new(
+      ErrorKind::InvalidInput,
+      "identity key is no ECDSA P-256 key",
+    )
+  };
+  let signer = any_ecdsa_type(old_key)
+    .map_err(|_| invalid_key())?
+    .choose_scheme(&[SignatureScheme::ECDSA_NISTP256_SHA256])
+    .ok_or_else(invalid_key)?;
+  let signature = signer
+    .sign(&rotation_payload(&new_cert.0))
+    .map_err(|err| io::Error::new(ErrorKind::Other, err))?;
+
+  Ok(RotationNotice {
+    cert: new_cert.0.clone(),
+    signature,
+  })
+}
+
+/// Checks that `notice` was signed by the owner of `old_cert`
+///
+/// Returns the certificate that replaces `old_cert`
+///
+/// # Errors
+/// This function will return:</br>
+/// [ErrorKind::InvalidData] if either certificate is malformed or the signature does not match
+pub fn verify_rotation(old_cert: &Certificate, notice: &RotationNotice) -> io::Result<Certificate> {
+  let invalid = |err: webpki::Error| io::Error::new(ErrorKind::InvalidData, err.to_string());
+  let old = webpki::EndEntityCert::try_from(old_cert.0.as_slice()).map_err(invalid)?;
+  webpki::EndEntityCert::try_from(notice.cert.as_slice()).map_err(invalid)?;
+  old
+    .verify_signature(
+      &webpki::ECDSA_P256_SHA256,
+      &rotation_payload(&notice.cert),
+      &notice.signature,
+    )
+    .map_err(invalid)?;
+
+  Ok(Certificate(notice.cert.clone()))
+}
+
+/// Stores `cert` and `key` in pemfile in the order Certificate, PrivateKey
+///
+/// The key is stored as passphrase protected [SEALED_KEY_TAG] section if `sealing` is given.
+///
+/// # Errors
+/// Will return any errors from sealing the key and creating and writing "pemfile"
+pub fn write_identity(
+  pemfile: &PathBuf,
+  cert: &Certificate,
+  key: &PrivateKey,
+  sealing: Option<&SealingKey>,
+) -> io::Result<()> {
+  let pem = encode_identity(cert, key, sealing)?;
 
   let dir = match pemfile.parent() {
     Some(dir) => dir,
@@ -29,11 +131,133 @@ pub fn generate_cert(pemfile: &PathBuf) -> io::Result<()> {
 
   DirBuilder::new().recursive(true).create(dir)?;
 
-  let mut pemfile = OpenOptions::new().create(true).write(true).open(pemfile)?;
+  let mut pemfile = OpenOptions::new()
+    .create(true)
+    .write(true)
+    .truncate(true)
+    .open(pemfile)?;
   info!("[created/overwritten] file: {pemfile:?}");
 
-  pemfile.write_all(cert.serialize_pem().unwrap().as_bytes())?;
-
-  pemfile.write_all(cert.serialize_private_key_pem().as_bytes())?;
+  pemfile.write_all(pem.as_bytes())?;
   Ok(())
 }
+
+/// Encodes `cert` and `key` like they are stored by [write_identity]
+///
+/// # Errors
+/// Will return any errors from sealing the key, see [SealingKey::seal]
+pub fn encode_identity(
+  cert: &Certificate,
+  key: &PrivateKey,
+  sealing: Option<&SealingKey>,
+) -> io::Result<String> {
+  let key = match sealing {
+    Some(sealing) => Pem {
+      tag: SEALED_KEY_TAG.to_string(),
+      contents: sealing.seal(key)?,
+    },
+    None => Pem {
+      tag: "PRIVATE KEY".to_string(),
+      contents: key.0.clone(),
+    },
+  };
+  let cert = Pem {
+    tag: "CERTIFICATE".to_string(),
+    contents: cert.0.clone(),
+  };
+
+  let config = EncodeConfig {
+    line_ending: LineEnding::LF,
+  };
+  Ok(encode_many_config(&[cert, key], config))
+}
+
+/// Checks that `key` is the private key of `cert` by signing using `key` and verifying using `cert`
+///
+/// # Errors
+/// This function will return:</br>
+/// [ErrorKind::InvalidInput] if `key` is no ECDSA P-256 key</br>
+/// [ErrorKind::InvalidData] if `cert` is malformed or does not belong to `key`
+pub fn verify_pair(cert: &Certificate, key: &PrivateKey) -> io::Result<()> {
+  let proof = sign_rotation(key, cert)?;
+  verify_rotation(cert, &proof).map(|_| ())
+}
+
+/// Returns the payload that is signed for a rotation to `cert`
+fn rotation_payload(cert: &[u8]) -> Vec<u8> {
+  [ROTATION_CONTEXT, cert].concat()
+}
+
+/// Generates a new pair of self signed certificate and key with "emberry_user" as subject name
+fn generate() -> (Certificate, PrivateKey) {
+  let cert = generate_simple_self_signed(vec!["emberry_user".to_string()]).unwrap();
+  (
+    Certificate(cert.serialize_der().unwrap()),
+    PrivateKey(cert.serialize_private_key_der()),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Tests if a rotation notice is only accepted for the identity that signed it
+  #[test]
+  fn verify_signed_rotation() {
+    let (old_cert, old_key) = generate();
+    let (new_cert, _) = generate();
+    let (other_cert, _) = generate();
+
+    let notice = sign_rotation(&old_key, &new_cert).unwrap();
+    assert_eq!(
+      verify_rotation(&old_cert, &notice).unwrap(),
+      new_cert,
+      "\nverify_rotation returned 'left' but 'right' was expected"
+    );
+    assert!(verify_rotation(&other_cert, &notice).is_err());
+
+    let forged = RotationNotice {
+      cert: other_cert.0,
+      ..notice
+    };
+    assert!(verify_rotation(&old_cert, &forged).is_err());
+
+    let bare = RotationNotice {
+      signature: any_ecdsa_type(&old_key)
+        .unwrap()
+        .choose_scheme(&[SignatureScheme::ECDSA_NISTP256_SHA256])
+        .unwrap()
+        .sign(&new_cert.0)
+        .unwrap(),
+      cert: new_cert.0.clone(),
+    };
+    assert!(verify_rotation(&old_cert, &bare).is_err());
+  }
+
+  /// Tests if a signed notice is refused if it does not carry a certificate
+  #[test]
+  fn verify_malformed_rotation() {
+    let (old_cert, old_key) = generate();
+    let garbage = Certificate(b"no certificate".to_vec());
+
+    let notice = sign_rotation(&old_key, &garbage).unwrap();
+    assert_eq!(
+      verify_rotation(&old_cert, &notice).unwrap_err().kind(),
+      ErrorKind::InvalidData
+    );
+  }
+
+  /// Tests if only a certificate and its own key are accepted as pair
+  #[test]
+  fn verify_own_pair() {
+    let (cert, key) = generate();
+    let (other_cert, other_key) = generate();
+
+    assert!(verify_pair(&cert, &key).is_ok());
+    assert!(verify_pair(&other_cert, &other_key).is_ok());
+    assert_eq!(
+      verify_pair(&cert, &other_key).unwrap_err().kind(),
+      ErrorKind::InvalidData
+    );
+  }
+}
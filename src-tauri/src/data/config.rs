@@ -1,10 +1,10 @@
-use std::sync::RwLock;
+use std::{io::ErrorKind, sync::RwLock};
 
 use crate::data::{IdentifiedUserInfo, UserIdentifier};
 
 use super::{
   path::DATA,
-  sqlite::{try_exec, user::try_get},
+  sqlite::{try_exec_blocking, user::try_get},
   PemfileReader, UserInfo, UserRelation,
 };
 use once_cell::sync::Lazy;
@@ -14,15 +14,33 @@ use tokio_rustls::rustls::{Certificate, PrivateKey};
 pub static PEM: Lazy<PemfileReader> = Lazy::new(pem_reader);
 /// IdentifiedUserInfo of the current user;
 /// None if [PEM] has no valid cert
+///
+/// Reading the username blocks on the database, so it has to be forced
+/// before the async runtime uses it first, see [try_exec_blocking]
 pub static IDI: Lazy<RwLock<Option<IdentifiedUserInfo<'static>>>> = Lazy::new(|| RwLock::new(maybe_info()));
 
 #[deprecated]
-/// cert and key of the local user from .pem file
-pub static PEM_DATA:  Lazy<Option<(Certificate, PrivateKey)>> = Lazy::new(maybe_pem_data);
+/// cert and key of the local user from .pem file,
+/// replaced when the identity is rotated;
+/// None while the key is protected by a passphrase and not unlocked (see [super::identity::unlock])
+pub static PEM_DATA: Lazy<RwLock<Option<(Certificate, PrivateKey)>>> =
+  Lazy::new(|| RwLock::new(maybe_pem_data()));
+
+/// Reads the identity file again after it was replaced, updating [PEM_DATA] and [IDI]
+///
+/// Must not be called from within the async runtime like [IDI]
+pub fn reload() {
+  *PEM_DATA.write().unwrap() = maybe_pem_data();
+  *IDI.write().unwrap() = maybe_info();
+}
 
 fn maybe_pem_data() -> Option<(Certificate, PrivateKey)> {
   match PEM.parse() {
     Ok(data) => Some(data),
+    Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+      log::info!("Identity is locked until it is unlocked using its passphrase");
+      None
+    }
     Err(err) => {
       log::warn!(
         "Failed to parse Certificate and PrivateKey from '{}', Err: '{}'",
@@ -36,11 +54,13 @@ fn maybe_pem_data() -> Option<(Certificate, PrivateKey)> {
 
 fn maybe_info<'a>() -> Option<IdentifiedUserInfo<'a>> {
   let id = maybe_identifier()?;
-  let info = match try_exec(try_get, &id) {
+  let ident = id.to_static();
+  let info = match try_exec_blocking(move |db| try_get(db, &ident)) {
     Ok(info) => info,
     Err(_err) => UserInfo {
       relation: UserRelation::Local,
       username: id.bs58.to_string(),
+      nickname: None,
     },
   };
 
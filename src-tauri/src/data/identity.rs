@@ -0,0 +1,305 @@
+//! Passphrase protection of the identity key
+//!
+//! A protected key is encrypted using AES-256-GCM with a key derived from the passphrase
+//! using PBKDF2-HMAC-SHA256 and stored in the identity file as [SEALED_KEY_TAG] section
+//! instead of the plain PKCS8 key. A locked identity has its certificate readable,
+//! its key only becomes available in [PEM_DATA] once it is unlocked using [unlock].
+
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::num::NonZeroU32;
+
+use once_cell::sync::Lazy;
+use ring::{
+  aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+  pbkdf2,
+  rand::{SecureRandom, SystemRandom},
+};
+use rustls::PrivateKey;
+use serde::Serialize;
+use std::sync::RwLock;
+
+use super::cert_gen;
+use super::config::{PEM, PEM_DATA};
+use super::pem_reader::StoredKey;
+
+/// Label of the PEM section holding a passphrase protected key
+pub const SEALED_KEY_TAG: &str = "EMBERRY SEALED KEY";
+
+const VERSION: u8 = 1;
+const PBKDF2_ITERATIONS: u32 = 100_000;
+const SALT_LEN: usize = 16;
+/// Version, iteration count and salt, authenticated as additional data
+const HEADER_LEN: usize = 1 + 4 + SALT_LEN;
+
+/// Key protecting the identity file, `None` if it is stored without passphrase or still locked
+///
+/// Kept while the identity is unlocked so a rotated identity can be stored protected again.
+pub static SEALING: Lazy<RwLock<Option<SealingKey>>> = Lazy::new(|| RwLock::new(None));
+
+/// Whether there is an identity and if its key can be used
+#[derive(Clone, Copy, Serialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IdentityState {
+  /// There is no usable identity file
+  Missing,
+  /// The key is protected by a passphrase that was not entered yet
+  Locked,
+  /// The key is loaded and can be used
+  Unlocked,
+}
+
+/// Key derived from a passphrase together with the parameters needed to derive it again
+#[derive(Clone)]
+pub struct SealingKey {
+  salt: [u8; SALT_LEN],
+  iterations: u32,
+  key: [u8; 32],
+}
+
+impl SealingKey {
+  /// Derives a new key from `passphrase` using a random salt
+  ///
+  /// # Errors
+  /// This function will return:</br>
+  /// [ErrorKind::Other] if no random salt could be generated
+  pub fn derive(passphrase: &str) -> io::Result<SealingKey> {
+    let mut salt = [0; SALT_LEN];
+    SystemRandom::new()
+      .fill(&mut salt)
+      .map_err(|_| io::Error::new(ErrorKind::Other, "no randomness for the salt"))?;
+    Ok(SealingKey::derive_with(passphrase, salt, PBKDF2_ITERATIONS))
+  }
+
+  fn derive_with(passphrase: &str, salt: [u8; SALT_LEN], iterations: u32) -> SealingKey {
+    let mut key = [0; 32];
+    pbkdf2::derive(
+      pbkdf2::PBKDF2_HMAC_SHA256,
+      NonZeroU32::new(iterations).unwrap(),
+      &salt,
+      passphrase.as_bytes(),
+      &mut key,
+    );
+    SealingKey {
+      salt,
+      iterations,
+      key,
+    }
+  }
+
+  fn header(&self) -> [u8; HEADER_LEN] {
+    let mut header = [0; HEADER_LEN];
+    header[0] = VERSION;
+    header[1..5].copy_from_slice(&self.iterations.to_be_bytes());
+    header[5..].copy_from_slice(&self.salt);
+    header
+  }
+
+  fn aead(&self) -> LessSafeKey {
+    LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &self.key).unwrap())
+  }
+
+  /// Encrypts `key` into the content of a [SEALED_KEY_TAG] section
+  ///
+  /// # Errors
+  /// See [SealingKey::seal_data]
+  pub fn seal(&self, key: &PrivateKey) -> io::Result<Vec<u8>> {
+    self.seal_data(&key.0)
+  }
+
+  /// Encrypts `data` so it can only be read using [open_data] and the passphrase of `self`
+  ///
+  /// # Errors
+  /// This function will return:</br>
+  /// [ErrorKind::Other] if no random nonce could be generated
+  pub fn seal_data(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut nonce = [0; NONCE_LEN];
+    SystemRandom::new()
+      .fill(&mut nonce)
+      .map_err(|_| io::Error::new(ErrorKind::Other, "no randomness for the nonce"))?;
+
+    let header = self.header();
+    let mut in_out = data.to_vec();
+    self
+      .aead()
+      .seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::from(&header),
+        &mut in_out,
+      )
+      .map_err(|_| io::Error::new(ErrorKind::Other, "unable to encrypt"))?;
+
+    Ok([&header[..], &nonce, &in_out].concat())
+  }
+}
+
+/// Leaves out the derived key
+impl fmt::Debug for SealingKey {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("SealingKey")
+      .field("iterations", &self.iterations)
+      .finish_non_exhaustive()
+  }
+}
+
+/// Decrypts the content of a [SEALED_KEY_TAG] section using `passphrase`
+///
+/// Returns the key and the [SealingKey] that protects it
+///
+/// # Errors
+/// See [open_data]
+pub fn open(sealed: &[u8], passphrase: &str) -> io::Result<(PrivateKey, SealingKey)> {
+  let (key, sealing) = open_data(sealed, passphrase)?;
+  Ok((PrivateKey(key), sealing))
+}
+
+/// Decrypts data sealed using [SealingKey::seal_data] with `passphrase`
+///
+/// Returns the data and the [SealingKey] that protects it
+///
+/// # Errors
+/// This function will return:</br>
+/// [ErrorKind::InvalidData] if `sealed` is malformed</br>
+/// [ErrorKind::PermissionDenied] if `passphrase` is wrong
+pub fn open_data(sealed: &[u8], passphrase: &str) -> io::Result<(Vec<u8>, SealingKey)> {
+  if sealed.len() < HEADER_LEN + NONCE_LEN || sealed[0] != VERSION || sealed[1..5] == [0; 4] {
+    return Err(io::Error::new(
+      ErrorKind::InvalidData,
+      "malformed or unsupported sealed data",
+    ));
+  }
+  let (header, rest) = sealed.split_at(HEADER_LEN);
+  let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+  let iterations = u32::from_be_bytes(header[1..5].try_into().unwrap());
+  let salt = header[5..].try_into().unwrap();
+
+  let sealing = SealingKey::derive_with(passphrase, salt, iterations);
+  let mut in_out = ciphertext.to_vec();
+  let data = sealing
+    .aead()
+    .open_in_place(
+      Nonce::try_assume_unique_for_key(nonce).unwrap(),
+      Aad::from(header),
+      &mut in_out,
+    )
+    .map_err(|_| io::Error::new(ErrorKind::PermissionDenied, "wrong passphrase"))?;
+
+  Ok((data.to_vec(), sealing))
+}
+
+/// Returns whether there is an identity and if it is unlocked
+pub fn state() -> IdentityState {
+  if PEM_DATA.read().unwrap().is_some() {
+    return IdentityState::Unlocked;
+  }
+  match PEM.read() {
+    Ok((_, StoredKey::Sealed(_))) => IdentityState::Locked,
+    _ => IdentityState::Missing,
+  }
+}
+
+/// Loads the key of the identity into [PEM_DATA] using `passphrase` if it is protected
+///
+/// # Errors
+/// This function will return:</br>
+/// Any [io::Error] from reading the identity file, see [PemfileReader::read](super::PemfileReader::read)</br>
+/// [ErrorKind::PermissionDenied] if `passphrase` is wrong
+pub fn unlock(passphrase: &str) -> io::Result<()> {
+  let (cert, key, sealing) = read_with(Some(passphrase))?;
+  *PEM_DATA.write().unwrap() = Some((cert, key));
+  *SEALING.write().unwrap() = sealing;
+  Ok(())
+}
+
+/// Protects the identity using the passphrase `new`, removes the protection if `new` is `None`
+///
+/// `current` is needed if the identity is protected already.
+///
+/// # Errors
+/// This function will return:</br>
+/// Any [io::Error] from reading or writing the identity file</br>
+/// [ErrorKind::PermissionDenied] if `current` is missing or wrong
+pub fn protect(current: Option<&str>, new: Option<&str>) -> io::Result<()> {
+  let (cert, key, _) = read_with(current)?;
+  let sealing = new.map(SealingKey::derive).transpose()?;
+  cert_gen::write_identity(&PEM.filepath, &cert, &key, sealing.as_ref())?;
+
+  *PEM_DATA.write().unwrap() = Some((cert, key));
+  *SEALING.write().unwrap() = sealing;
+  Ok(())
+}
+
+fn read_with(
+  passphrase: Option<&str>,
+) -> io::Result<(rustls::Certificate, PrivateKey, Option<SealingKey>)> {
+  match (PEM.read()?, passphrase) {
+    ((cert, StoredKey::Plain(key)), _) => Ok((cert, key, None)),
+    ((cert, StoredKey::Sealed(sealed)), Some(passphrase)) => {
+      let (key, sealing) = open(&sealed, passphrase)?;
+      Ok((cert, key, Some(sealing)))
+    }
+    ((_, StoredKey::Sealed(_)), None) => Err(io::Error::new(
+      ErrorKind::PermissionDenied,
+      "the identity is protected by a passphrase",
+    )),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fs::remove_file;
+
+  use super::*;
+  use crate::data::PemfileReader;
+
+  /// Tests if a sealed key can only be opened using the passphrase it was sealed with
+  #[test]
+  fn seal_open() {
+    let key = PrivateKey(b"pkcs8 key bytes".to_vec());
+    let sealing = SealingKey::derive("correct horse").unwrap();
+    let sealed = sealing.seal(&key).unwrap();
+
+    let (opened, resealing) = open(&sealed, "correct horse").unwrap();
+    assert_eq!(
+      opened, key,
+      "\nopen returned 'left' but 'right' was expected"
+    );
+    assert_eq!(resealing.key, sealing.key);
+
+    let err = open(&sealed, "battery staple").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+
+    let mut tampered = sealed.clone();
+    *tampered.last_mut().unwrap() ^= 1;
+    assert!(open(&tampered, "correct horse").is_err());
+    assert_eq!(
+      open(&sealed[..HEADER_LEN], "correct horse")
+        .unwrap_err()
+        .kind(),
+      ErrorKind::InvalidData
+    );
+  }
+
+  /// Tests if a protected identity file keeps the certificate readable and the key sealed
+  #[test]
+  fn write_sealed() {
+    let reader = PemfileReader {
+      filepath: std::env::temp_dir().join("emberry-sealed-identity.pem"),
+    };
+    let cert = rustls::Certificate(b"der certificate".to_vec());
+    let key = PrivateKey(b"pkcs8 key bytes".to_vec());
+    let sealing = SealingKey::derive("correct horse").unwrap();
+
+    cert_gen::write_identity(&reader.filepath, &cert, &key, Some(&sealing)).unwrap();
+    let read = reader.read();
+    let parsed = reader.parse();
+    remove_file(&reader.filepath).unwrap();
+
+    let sealed = match read.unwrap() {
+      (read_cert, StoredKey::Sealed(sealed)) if read_cert == cert => sealed,
+      _ => panic!("read did not return the certificate and a sealed key"),
+    };
+    assert_eq!(open(&sealed, "correct horse").unwrap().0, key);
+    assert_eq!(parsed.unwrap_err().kind(), ErrorKind::PermissionDenied);
+  }
+}
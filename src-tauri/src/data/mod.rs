@@ -1,11 +1,16 @@
-mod cert_gen;
+pub mod bundle;
+pub mod cert_gen;
 pub mod config;
+pub mod identity;
 mod path;
 mod pem_reader;
+pub mod servers;
+pub mod settings;
 pub mod sqlite;
 pub mod tauri;
 mod usr_ident;
 mod usr_info;
-pub use pem_reader::PemfileReader;
+pub use path::DOWNLOADS;
+pub use pem_reader::{PemfileReader, StoredKey};
 pub use usr_ident::*;
 pub use usr_info::*;
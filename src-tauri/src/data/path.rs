@@ -5,8 +5,9 @@ use std::{env::args, path::PathBuf};
 pub static DATA: Lazy<PathBuf> = Lazy::new(data_dir);
 #[allow(dead_code)]
 pub static CACHE: Lazy<PathBuf> = Lazy::new(cache_dir);
-#[allow(dead_code)]
 pub static CONFIG: Lazy<PathBuf> = Lazy::new(config_dir);
+/// Directory that files received from peers are stored in
+pub static DOWNLOADS: Lazy<PathBuf> = Lazy::new(|| DATA.join("downloads"));
 
 fn data_dir() -> PathBuf {
   let warehouse = if let Some(mut data_dir) = tauri::api::path::data_dir() {
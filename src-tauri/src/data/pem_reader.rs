@@ -1,6 +1,6 @@
 use std::{borrow::Cow, path::PathBuf};
 
-use crate::data::UserIdentifier;
+use crate::data::{identity::SEALED_KEY_TAG, UserIdentifier};
 
 use rustls::{Certificate, PrivateKey};
 use rustls_pemfile::Item::{PKCS8Key, X509Certificate};
@@ -10,6 +10,13 @@ pub struct PemfileReader {
   pub filepath: PathBuf,
 }
 
+/// Private key as stored in the identity file
+pub enum StoredKey {
+  Plain(PrivateKey),
+  /** Content of a passphrase protected [SEALED_KEY_TAG] section, see [super::identity::open] */
+  Sealed(Vec<u8>),
+}
+
 impl PemfileReader {
   /// Opens the filepath from [self] in readonly mode and reads one
   /// X509Certificate and one PKCS8Key from it.
@@ -18,12 +25,32 @@ impl PemfileReader {
   /// # Errors
   /// This function will return:</br>
   /// Any [std::io::Error] from opening/reading the file</br>
-  /// [ErrorKind::InvalidData] when the items are malformed or out of order
+  /// [ErrorKind::InvalidData] when the items are malformed or out of order</br>
+  /// [ErrorKind::PermissionDenied] when the key is protected by a passphrase
   pub fn parse(&self) -> Result<(Certificate, PrivateKey), std::io::Error> {
-    let certfile = std::fs::OpenOptions::new()
-      .read(true)
-      .open(&self.filepath)?;
-    let mut reader = std::io::BufReader::new(certfile);
+    match self.read()? {
+      (cert, StoredKey::Plain(key)) => Ok((cert, key)),
+      (_, StoredKey::Sealed(_)) => Err(std::io::Error::new(
+        ErrorKind::PermissionDenied,
+        format!(
+          "File: '{}' contains a key that is protected by a passphrase",
+          self.filepath.to_string_lossy()
+        ),
+      )),
+    }
+  }
+
+  /// Opens the filepath from [self] in readonly mode and reads one
+  /// X509Certificate and one PKCS8Key or [SEALED_KEY_TAG] section from it.
+  /// The order in which those items are expected is: X509Certificate, key
+  ///
+  /// # Errors
+  /// This function will return:</br>
+  /// Any [std::io::Error] from opening/reading the file</br>
+  /// [ErrorKind::InvalidData] when the items are malformed or out of order
+  pub fn read(&self) -> Result<(Certificate, StoredKey), std::io::Error> {
+    let contents = std::fs::read_to_string(&self.filepath)?;
+    let mut reader = contents.as_bytes();
 
     let cert = if let Some(X509Certificate(key)) = rustls_pemfile::read_one(&mut reader)? {
       rustls::Certificate(key)
@@ -37,8 +64,15 @@ impl PemfileReader {
       ));
     };
 
+    // rustls_pemfile skips sections it does not know, like the sealed key
     let key = if let Some(PKCS8Key(key)) = rustls_pemfile::read_one(&mut reader)? {
-      rustls::PrivateKey(key)
+      StoredKey::Plain(rustls::PrivateKey(key))
+    } else if let Some(sealed) = pem::parse_many(&contents)
+      .map_err(|err| std::io::Error::new(ErrorKind::InvalidData, err))?
+      .into_iter()
+      .find(|section| section.tag == SEALED_KEY_TAG)
+    {
+      StoredKey::Sealed(sealed.contents)
     } else {
       return Err(std::io::Error::new(
         ErrorKind::InvalidData,
@@ -0,0 +1,335 @@
+use std::{
+  fs,
+  io::{self, ErrorKind},
+  path::Path,
+  sync::RwLock,
+};
+
+use once_cell::sync::Lazy;
+use rustls::{Certificate, ServerName};
+use serde::{Deserialize, Serialize};
+
+use super::path::CONFIG;
+
+/// Name of the profile built from the compile time configuration (.env)
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// Rhizome servers known to the local user;
+/// loaded from "servers.json" in [CONFIG]
+pub static SERVERS: Lazy<RwLock<ServerProfiles>> = Lazy::new(|| RwLock::new(load()));
+
+/// Everything needed to connect to a single rhizome server
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct ServerProfile {
+  pub name: String,
+  /** Address of the TLS control channel (host:port) */
+  pub control_address: String,
+  /** Address of the UDP hole punching service (host:port) */
+  pub server_address: String,
+  /** Domain the certificate of rhizome is issued for */
+  pub domain: String,
+  /** PEM encoded certificate of rhizome */
+  pub cert: String,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct ServerProfiles {
+  /** Name of the profile used for the next connection to rhizome */
+  pub active: String,
+  /** All known profiles, starting with the [DEFAULT_PROFILE] */
+  pub profiles: Vec<ServerProfile>,
+}
+
+impl ServerProfile {
+  /// The profile compiled into this build
+  pub fn builtin() -> ServerProfile {
+    ServerProfile {
+      name: DEFAULT_PROFILE.to_string(),
+      control_address: dotenv!("CONTROL_ADDRESS").to_string(),
+      server_address: dotenv!("SERVER_ADDRESS").to_string(),
+      domain: dotenv!("SERVER_DOMAIN").to_string(),
+      cert: dotenv!("CERT").to_string(),
+    }
+  }
+
+  /// Parses the certificate of rhizome from [ServerProfile::cert]
+  ///
+  /// # Errors
+  /// This function will return:</br>
+  /// [ErrorKind::InvalidData] if [ServerProfile::cert] does not start with a X509Certificate
+  pub fn certificate(&self) -> io::Result<Certificate> {
+    let mut reader = io::BufReader::new(self.cert.as_bytes());
+
+    if let Some(rustls_pemfile::Item::X509Certificate(cert)) =
+      rustls_pemfile::read_one(&mut reader)?
+    {
+      Ok(Certificate(cert))
+    } else {
+      Err(io::Error::new(
+        ErrorKind::InvalidData,
+        format!("Server profile '{}' contains no X509Certificate", self.name),
+      ))
+    }
+  }
+
+  /// Parses [ServerProfile::domain] into the name used to verify the certificate of rhizome
+  ///
+  /// # Errors
+  /// This function will return:</br>
+  /// [ErrorKind::InvalidData] if [ServerProfile::domain] is not a valid DNS name
+  pub fn server_name(&self) -> io::Result<ServerName> {
+    self.domain.as_str().try_into().map_err(|_| {
+      io::Error::new(
+        ErrorKind::InvalidData,
+        format!("Server profile '{}' has an invalid domain", self.name),
+      )
+    })
+  }
+}
+
+impl Default for ServerProfiles {
+  fn default() -> Self {
+    ServerProfiles {
+      active: DEFAULT_PROFILE.to_string(),
+      profiles: vec![ServerProfile::builtin()],
+    }
+  }
+}
+
+impl ServerProfiles {
+  /// Returns the profile selected by [ServerProfiles::active]
+  /// or the builtin profile if there is no such profile
+  pub fn active(&self) -> ServerProfile {
+    self.get(&self.active).cloned().unwrap_or_else(|| {
+      log::warn!("Unknown server profile '{}', using builtin", self.active);
+      ServerProfile::builtin()
+    })
+  }
+
+  pub fn get(&self, name: &str) -> Option<&ServerProfile> {
+    self.profiles.iter().find(|profile| profile.name == name)
+  }
+
+  /// Adds `profile` or replaces the profile with the same name
+  ///
+  /// # Errors
+  /// This function will return:</br>
+  /// [ErrorKind::PermissionDenied] if `profile` would replace the builtin profile</br>
+  /// [ErrorKind::InvalidInput] if `profile` has no name</br>
+  /// [ErrorKind::InvalidData] if the certificate or domain of `profile` cannot be parsed
+  pub fn upsert(&mut self, profile: ServerProfile) -> io::Result<()> {
+    if profile.name == DEFAULT_PROFILE {
+      return Err(io::Error::new(
+        ErrorKind::PermissionDenied,
+        "The builtin server profile cannot be changed",
+      ));
+    }
+    if profile.name.trim().is_empty() {
+      return Err(io::Error::new(
+        ErrorKind::InvalidInput,
+        "Server profiles need a name",
+      ));
+    }
+    profile.certificate()?;
+    profile.server_name()?;
+
+    match self.profiles.iter_mut().find(|p| p.name == profile.name) {
+      Some(existing) => *existing = profile,
+      None => self.profiles.push(profile),
+    }
+    Ok(())
+  }
+
+  /// Removes the profile called `name`,
+  /// switching back to the builtin profile if it was the active one
+  ///
+  /// Returns `false` if there was no such profile
+  ///
+  /// # Errors
+  /// This function will return:</br>
+  /// [ErrorKind::PermissionDenied] if `name` refers to the builtin profile
+  pub fn remove(&mut self, name: &str) -> io::Result<bool> {
+    if name == DEFAULT_PROFILE {
+      return Err(io::Error::new(
+        ErrorKind::PermissionDenied,
+        "The builtin server profile cannot be removed",
+      ));
+    }
+
+    let len = self.profiles.len();
+    self.profiles.retain(|profile| profile.name != name);
+    if self.active == name {
+      self.active = DEFAULT_PROFILE.to_string();
+    }
+    Ok(len != self.profiles.len())
+  }
+
+  /// Makes the profile called `name` the one used for the next connection to rhizome
+  ///
+  /// # Errors
+  /// This function will return:</br>
+  /// [ErrorKind::NotFound] if there is no profile called `name`
+  pub fn select(&mut self, name: &str) -> io::Result<()> {
+    if self.get(name).is_none() {
+      return Err(io::Error::new(
+        ErrorKind::NotFound,
+        format!("Unknown server profile '{}'", name),
+      ));
+    }
+    self.active = name.to_string();
+    Ok(())
+  }
+
+  /// Reads the profiles stored at `path`
+  ///
+  /// The builtin profile is not stored but always added in front of the stored ones.
+  ///
+  /// # Errors
+  /// This function will return:</br>
+  /// Any [io::Error] from reading the file</br>
+  /// [ErrorKind::InvalidData] if the file is no valid profile list
+  pub fn read_from(path: &Path) -> io::Result<ServerProfiles> {
+    let json = fs::read_to_string(path)?;
+    let mut stored: ServerProfiles = serde_json::from_str(&json)?;
+
+    stored
+      .profiles
+      .retain(|profile| profile.name != DEFAULT_PROFILE);
+    stored.profiles.insert(0, ServerProfile::builtin());
+    Ok(stored)
+  }
+
+  /// Writes all profiles except the builtin one to `path`
+  ///
+  /// # Errors
+  /// This function will return:</br>
+  /// Any [io::Error] from creating the parent directory or writing the file
+  pub fn write_to(&self, path: &Path) -> io::Result<()> {
+    let stored = ServerProfiles {
+      active: self.active.clone(),
+      profiles: self
+        .profiles
+        .iter()
+        .filter(|profile| profile.name != DEFAULT_PROFILE)
+        .cloned()
+        .collect(),
+    };
+
+    if let Some(dir) = path.parent() {
+      fs::create_dir_all(dir)?;
+    }
+    fs::write(path, serde_json::to_string_pretty(&stored)?)
+  }
+}
+
+/// Stores `profiles` in "servers.json" in [CONFIG]
+///
+/// # Errors
+/// This function will return:</br>
+/// Any [io::Error] from [ServerProfiles::write_to]
+pub fn save(profiles: &ServerProfiles) -> io::Result<()> {
+  profiles.write_to(&CONFIG.join("servers.json"))
+}
+
+fn load() -> ServerProfiles {
+  let path = CONFIG.join("servers.json");
+  match ServerProfiles::read_from(&path) {
+    Ok(profiles) => profiles,
+    Err(err) if err.kind() == ErrorKind::NotFound => ServerProfiles::default(),
+    Err(err) => {
+      log::warn!(
+        "Unable to read server profiles from '{}', Err: '{}'",
+        path.to_string_lossy(),
+        err
+      );
+      ServerProfiles::default()
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn sample_profile(name: &str) -> ServerProfile {
+    ServerProfile {
+      name: name.to_string(),
+      control_address: "127.0.0.1:9999".to_string(),
+      server_address: "127.0.0.1:9998".to_string(),
+      ..ServerProfile::builtin()
+    }
+  }
+
+  /// Tests if the compile time configuration is a valid profile
+  #[test]
+  fn builtin_profile() {
+    let profile = ServerProfile::builtin();
+    profile
+      .certificate()
+      .expect("builtin certificate is invalid");
+    profile.server_name().expect("builtin domain is invalid");
+  }
+
+  /// Tests if profiles can be added, selected and removed
+  #[test]
+  fn upsert_select_remove() {
+    let mut profiles = ServerProfiles::default();
+    profiles.upsert(sample_profile("staging")).unwrap();
+    profiles.select("staging").unwrap();
+    assert_eq!(profiles.active(), sample_profile("staging"));
+
+    let mut changed = sample_profile("staging");
+    changed.control_address = "127.0.0.1:7777".to_string();
+    profiles.upsert(changed.clone()).unwrap();
+    assert_eq!(profiles.profiles.len(), 2);
+    assert_eq!(profiles.active(), changed);
+
+    assert!(matches!(profiles.remove("staging"), Ok(true)));
+    assert_eq!(profiles.active, DEFAULT_PROFILE);
+    assert_eq!(profiles.active(), ServerProfile::builtin());
+  }
+
+  /// Tests if the builtin profile cannot be changed and broken profiles are rejected
+  #[test]
+  fn reject_invalid() {
+    let mut profiles = ServerProfiles::default();
+    let builtin = profiles.upsert(sample_profile(DEFAULT_PROFILE));
+    assert_eq!(builtin.unwrap_err().kind(), ErrorKind::PermissionDenied);
+    let removed = profiles.remove(DEFAULT_PROFILE);
+    assert_eq!(removed.unwrap_err().kind(), ErrorKind::PermissionDenied);
+
+    let mut broken = sample_profile("broken");
+    broken.cert = "not a certificate".to_string();
+    assert_eq!(
+      profiles.upsert(broken).unwrap_err().kind(),
+      ErrorKind::InvalidData
+    );
+    assert_eq!(
+      profiles.select("broken").unwrap_err().kind(),
+      ErrorKind::NotFound
+    );
+    assert_eq!(profiles, ServerProfiles::default());
+  }
+
+  /// Tests if stored profiles are read back with the builtin profile in front
+  #[test]
+  fn write_read() {
+    let path = std::env::temp_dir().join(format!("emberry-servers-{}.json", std::process::id()));
+    let mut profiles = ServerProfiles::default();
+    profiles.upsert(sample_profile("staging")).unwrap();
+    profiles.select("staging").unwrap();
+
+    profiles.write_to(&path).unwrap();
+    let stored = fs::read_to_string(&path).unwrap();
+    let result = ServerProfiles::read_from(&path);
+    let _ = fs::remove_file(&path);
+
+    let stored: ServerProfiles = serde_json::from_str(&stored).unwrap();
+    assert_eq!(stored.profiles, vec![sample_profile("staging")]);
+    assert_eq!(
+      result.unwrap(),
+      profiles,
+      "\nread_from returned 'left' but 'right' was written"
+    );
+  }
+}
@@ -0,0 +1,117 @@
+use std::{
+  fs,
+  io::{self, ErrorKind},
+  path::Path,
+  sync::RwLock,
+};
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use super::path::CONFIG;
+
+/// Preferences of the local user;
+/// loaded from "settings.json" in [CONFIG]
+pub static SETTINGS: Lazy<RwLock<Settings>> = Lazy::new(|| RwLock::new(load()));
+
+/// Preferences of the local user,
+/// settings missing from the stored file keep their default value
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct Settings {
+  /** Tell peers when their messages were seen */
+  pub read_receipts: bool,
+}
+
+impl Default for Settings {
+  fn default() -> Self {
+    Settings {
+      read_receipts: true,
+    }
+  }
+}
+
+impl Settings {
+  /// Reads the settings stored at `path`
+  ///
+  /// # Errors
+  /// This function will return:</br>
+  /// Any [io::Error] from reading the file</br>
+  /// [ErrorKind::InvalidData] if the file is no valid settings object
+  pub fn read_from(path: &Path) -> io::Result<Settings> {
+    let json = fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&json)?)
+  }
+
+  /// Writes the settings to `path`
+  ///
+  /// # Errors
+  /// This function will return:</br>
+  /// Any [io::Error] from creating the parent directory or writing the file
+  pub fn write_to(&self, path: &Path) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+      fs::create_dir_all(dir)?;
+    }
+    fs::write(path, serde_json::to_string_pretty(self)?)
+  }
+}
+
+/// Stores `settings` in "settings.json" in [CONFIG]
+///
+/// # Errors
+/// This function will return:</br>
+/// Any [io::Error] from [Settings::write_to]
+pub fn save(settings: &Settings) -> io::Result<()> {
+  settings.write_to(&CONFIG.join("settings.json"))
+}
+
+fn load() -> Settings {
+  let path = CONFIG.join("settings.json");
+  match Settings::read_from(&path) {
+    Ok(settings) => settings,
+    Err(err) if err.kind() == ErrorKind::NotFound => Settings::default(),
+    Err(err) => {
+      log::warn!(
+        "Unable to read settings from '{}', Err: '{}'",
+        path.to_string_lossy(),
+        err
+      );
+      Settings::default()
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Tests if settings missing from an older file fall back to their default
+  #[test]
+  fn missing_defaults() {
+    let settings: Settings = serde_json::from_str("{}").unwrap();
+    assert_eq!(
+      settings,
+      Settings::default(),
+      "\nparsing '{{}}' returned 'left' but 'right' was expected"
+    );
+  }
+
+  /// Tests if stored settings are read back unchanged
+  #[test]
+  fn write_read() {
+    let path = std::env::temp_dir().join(format!("emberry-settings-{}.json", std::process::id()));
+    let settings = Settings {
+      read_receipts: false,
+    };
+
+    settings.write_to(&path).unwrap();
+    let result = Settings::read_from(&path);
+    let _ = fs::remove_file(&path);
+
+    assert_eq!(
+      result.unwrap(),
+      settings,
+      "\nread_from returned 'left' but 'right' was written"
+    );
+  }
+}
@@ -0,0 +1,223 @@
+use std::borrow::Cow;
+
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+
+use crate::data::{IdentifiedUserInfo, UserIdentifier, UserInfo, UserRelation};
+use crate::history::msg::MsgId;
+
+/// Content of the database that moves to another install together with the identity
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Debug, Default)]
+pub struct Backup {
+  /** Every known user including the local one */
+  pub users: Vec<IdentifiedUserInfo<'static>>,
+  pub messages: Vec<MessageRow>,
+  pub reactions: Vec<ReactionRow>,
+}
+
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub struct MessageRow {
+  pub id: MsgId,
+  /** bs58 encoded certificate of the user the message was exchanged with */
+  pub peer: String,
+  pub sender: u8,
+  pub content: String,
+  pub time: u64,
+}
+
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub struct ReactionRow {
+  /** bs58 encoded certificate of the user the reacted to message was exchanged with */
+  pub peer: String,
+  pub msg_id: MsgId,
+  pub sender: u8,
+  pub emoji: String,
+}
+
+/// Tries to read every user from `db`, together with the chat history if `history` is `true`
+///
+/// # Errors
+/// This function will return:</br>
+/// The first error returned by executing the underlying SQLite queries on `db`
+pub fn export(db: &mut Connection, history: bool) -> Result<Backup, rusqlite::Error> {
+  let mut backup = Backup::default();
+
+  let mut statement = db.prepare("SELECT tls_cert, username, relation, nickname FROM users")?;
+  let rows = statement.query_map([], |row| {
+    Ok(IdentifiedUserInfo {
+      identifier: UserIdentifier {
+        bs58: Cow::Owned(row.get(0)?),
+      },
+      info: UserInfo {
+        username: row.get(1)?,
+        relation: UserRelation::from(row.get::<usize, u8>(2)?),
+        nickname: row.get(3)?,
+      },
+    })
+  })?;
+  for row in rows {
+    backup.users.push(row?);
+  }
+
+  if !history {
+    return Ok(backup);
+  }
+
+  let mut statement =
+    db.prepare("SELECT id, peer, sender, content, time FROM messages ORDER BY id")?;
+  let rows = statement.query_map([], |row| {
+    Ok(MessageRow {
+      id: row.get(0)?,
+      peer: row.get(1)?,
+      sender: row.get(2)?,
+      content: row.get(3)?,
+      time: row.get(4)?,
+    })
+  })?;
+  for row in rows {
+    backup.messages.push(row?);
+  }
+
+  let mut statement =
+    db.prepare("SELECT peer, msg_id, sender, emoji FROM reactions ORDER BY rowid")?;
+  let rows = statement.query_map([], |row| {
+    Ok(ReactionRow {
+      peer: row.get(0)?,
+      msg_id: row.get(1)?,
+      sender: row.get(2)?,
+      emoji: row.get(3)?,
+    })
+  })?;
+  for row in rows {
+    backup.reactions.push(row?);
+  }
+
+  Ok(backup)
+}
+
+/// Tries to add `backup` to `db` in a single transaction
+///
+/// Users that are present already take the username, relation and nickname from `backup`,
+/// messages and reactions that are present already are kept as they are.
+///
+/// # Errors
+/// This function will return:</br>
+/// The first error returned by executing the underlying SQLite queries on `db`
+pub fn import(db: &mut Connection, backup: &Backup) -> Result<(), rusqlite::Error> {
+  log::trace!(
+    "importing {} users and {} messages",
+    backup.users.len(),
+    backup.messages.len()
+  );
+  let tx = db.transaction()?;
+
+  for user in backup.users.iter() {
+    tx.execute(
+      r#"INSERT INTO users (tls_cert, username, relation, nickname) VALUES (?1, ?2, ?3, ?4)
+ON CONFLICT (tls_cert) DO UPDATE
+SET username = excluded.username, relation = excluded.relation, nickname = excluded.nickname"#,
+      params![
+        user.identifier.bs58,
+        user.info.username,
+        user.info.relation as u8,
+        user.info.nickname,
+      ],
+    )?;
+  }
+  for msg in backup.messages.iter() {
+    tx.execute(
+      "INSERT OR IGNORE INTO messages (id, peer, sender, content, time) VALUES (?1, ?2, ?3, ?4, ?5)",
+      params![msg.id, msg.peer, msg.sender, msg.content, msg.time],
+    )?;
+  }
+  for reaction in backup.reactions.iter() {
+    tx.execute(
+      "INSERT OR IGNORE INTO reactions (peer, msg_id, sender, emoji) VALUES (?1, ?2, ?3, ?4)",
+      params![
+        reaction.peer,
+        reaction.msg_id,
+        reaction.sender,
+        reaction.emoji
+      ],
+    )?;
+  }
+
+  tx.commit()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::data::sqlite::{
+    message, reaction, schema,
+    user::{set_nickname, upsert},
+  };
+  use crate::history::msg::{Msg, Text};
+
+  fn init() {
+    let _ = env_logger::builder().is_test(true).try_init();
+  }
+
+  fn sample_db() -> Connection {
+    let mut db = Connection::open_in_memory().unwrap();
+    schema::validate(&mut db).unwrap();
+
+    let peer = IdentifiedUserInfo {
+      identifier: UserIdentifier {
+        bs58: Cow::Owned("bs58 certificate string".to_string()),
+      },
+      info: UserInfo {
+        username: "special username".to_string(),
+        relation: UserRelation::Friend,
+        nickname: None,
+      },
+    };
+    upsert(&mut db, (&peer, |_| ())).unwrap();
+    set_nickname(&mut db, (&peer.identifier, Some("nickname"))).unwrap();
+
+    let text = Text {
+      id: MsgId(42),
+      sender: 0,
+      content: "hello".to_string(),
+      time: 1_700_000_000_000,
+      remojis: Vec::new(),
+    };
+    message::insert(&mut db, (&peer.identifier, &Msg::Text(text))).unwrap();
+    reaction::add(&mut db, (&peer.identifier, &MsgId(42), 1, "👍")).unwrap();
+    db
+  }
+
+  /// Tests if an imported backup contains the same data as the database it was exported from
+  #[test]
+  fn export_import() {
+    init();
+    let mut source = sample_db();
+    let backup = export(&mut source, true).unwrap();
+    assert_eq!(backup.users.len(), 1);
+    assert_eq!(backup.messages.len(), 1);
+    assert_eq!(backup.reactions.len(), 1);
+
+    let mut target = Connection::open_in_memory().unwrap();
+    schema::validate(&mut target).unwrap();
+    import(&mut target, &backup).unwrap();
+    // importing twice must not duplicate anything
+    import(&mut target, &backup).unwrap();
+
+    assert_eq!(
+      export(&mut target, true).unwrap(),
+      backup,
+      "\nexport returned 'left' but 'right' was expected as it has been imported"
+    );
+  }
+
+  /// Tests if the chat history is left out unless it is asked for
+  #[test]
+  fn export_without_history() {
+    init();
+    let mut db = sample_db();
+    let backup = export(&mut db, false).unwrap();
+
+    assert_eq!(backup.users.len(), 1);
+    assert!(backup.messages.is_empty() && backup.reactions.is_empty());
+  }
+}
@@ -0,0 +1,128 @@
+use std::borrow::Cow;
+
+use rusqlite::Connection;
+
+use crate::data::UserIdentifier;
+
+/// Tries to add `ident` to the blocked users
+///
+/// Returns `false` if `ident` is blocked already
+///
+/// # Errors
+/// This function will return:</br>
+/// The first error returned by executing the underlying SQLite query on `db`
+pub fn add(db: &mut Connection, ident: &UserIdentifier) -> Result<bool, rusqlite::Error> {
+  log::trace!("blocking: '{}'", ident.bs58);
+
+  let added = db.execute(
+    "INSERT OR IGNORE INTO blocks (tls_cert) VALUES (?1)",
+    [&ident.bs58],
+  )?;
+
+  Ok(added > 0)
+}
+
+/// Tries to remove `ident` from the blocked users
+///
+/// Returns `false` if `ident` was not blocked
+///
+/// # Errors
+/// This function will return:</br>
+/// The first error returned by executing the underlying SQLite query on `db`
+pub fn remove(db: &mut Connection, ident: &UserIdentifier) -> Result<bool, rusqlite::Error> {
+  log::trace!("unblocking: '{}'", ident.bs58);
+
+  let removed = db.execute("DELETE FROM blocks WHERE tls_cert = (?1)", [&ident.bs58])?;
+
+  Ok(removed > 0)
+}
+
+/// Tries to find out if `ident` is blocked
+///
+/// # Errors
+/// This function will return:</br>
+/// The first error returned by executing the underlying SQLite query on `db`
+pub fn contains(db: &mut Connection, ident: &UserIdentifier) -> Result<bool, rusqlite::Error> {
+  db.query_row(
+    "SELECT EXISTS (SELECT 1 FROM blocks WHERE tls_cert = (?1))",
+    [&ident.bs58],
+    |row| row.get(0),
+  )
+}
+
+/// Tries to get every blocked user, ordered from the first to the last one blocked
+///
+/// # Errors
+/// This function will return:</br>
+/// The first error returned by executing the underlying SQLite query on `db`
+pub fn get_all(db: &mut Connection) -> Result<Vec<UserIdentifier<'static>>, rusqlite::Error> {
+  let mut statement = db.prepare("SELECT tls_cert FROM blocks ORDER BY rowid")?;
+  let rows = statement.query_map([], |row| {
+    Ok(UserIdentifier {
+      bs58: Cow::Owned(row.get(0)?),
+    })
+  })?;
+
+  let mut all = Vec::new();
+
+  for row in rows {
+    all.push(row?);
+  }
+
+  Ok(all)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::data::sqlite::schema;
+
+  fn init() {
+    let _ = env_logger::builder().is_test(true).try_init();
+  }
+
+  fn sample_user(name: &str) -> UserIdentifier<'static> {
+    UserIdentifier {
+      bs58: Cow::Owned(name.to_string()),
+    }
+  }
+
+  /// Tests if a user is only blocked between adding and removing the block
+  #[test]
+  fn add_remove() {
+    init();
+    let mut db = Connection::open_in_memory().unwrap();
+    schema::validate(&mut db).unwrap();
+
+    let user = sample_user("blocked certificate string");
+    assert!(!contains(&mut db, &user).unwrap());
+
+    assert!(add(&mut db, &user).unwrap());
+    assert!(!add(&mut db, &user).unwrap());
+    assert!(contains(&mut db, &user).unwrap());
+
+    assert!(remove(&mut db, &user).unwrap());
+    assert!(!remove(&mut db, &user).unwrap());
+    assert!(!contains(&mut db, &user).unwrap());
+  }
+
+  /// Tests if every blocked user is listed in the order they were blocked
+  #[test]
+  fn get_blocked() {
+    init();
+    let mut db = Connection::open_in_memory().unwrap();
+    schema::validate(&mut db).unwrap();
+
+    let users: Vec<_> = (0..3).map(|i| sample_user(&format!("user{}", i))).collect();
+    for user in users.iter() {
+      add(&mut db, user).unwrap();
+    }
+    remove(&mut db, &users[1]).unwrap();
+
+    assert_eq!(
+      get_all(&mut db).unwrap(),
+      vec![users[0].clone(), users[2].clone()],
+      "\nget_all returned 'left' but 'right' was expected as the second user was unblocked"
+    );
+  }
+}
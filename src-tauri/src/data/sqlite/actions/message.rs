@@ -0,0 +1,313 @@
+use rusqlite::{
+  params,
+  types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef},
+  Connection, ToSql,
+};
+
+use super::reaction;
+use crate::data::UserIdentifier;
+use crate::history::msg::{Msg, MsgId, Text};
+
+/// Tries to insert a [Msg] into the chat history with `peer`
+///
+/// Only [Msg::Text] is persisted at the moment, other variants are ignored.
+/// Inserting a message with an id that is already present in the history with `peer` is a no-op,
+/// ids are chosen by the writer of a message and only unique within a chat
+///
+/// # Errors
+/// This function will return:</br>
+/// The first error returned by executing the underlying SQLite query on `db`
+pub fn insert(db: &mut Connection, input: (&UserIdentifier, &Msg)) -> Result<(), rusqlite::Error> {
+  let (peer, msg) = input;
+  insert_into(db, peer, msg)
+}
+
+/// Tries to insert every [Msg] of `msgs` into the chat history with `peer` in one transaction
+///
+/// See [insert] for the messages that are persisted
+///
+/// # Errors
+/// This function will return:</br>
+/// The first error returned by executing the underlying SQLite queries on `db`
+pub fn insert_all(
+  db: &mut Connection,
+  input: (&UserIdentifier, &[Msg]),
+) -> Result<(), rusqlite::Error> {
+  let (peer, msgs) = input;
+  let tx = db.transaction()?;
+  for msg in msgs {
+    insert_into(&tx, peer, msg)?;
+  }
+  tx.commit()
+}
+
+fn insert_into(db: &Connection, peer: &UserIdentifier, msg: &Msg) -> Result<(), rusqlite::Error> {
+  let text = match msg {
+    Msg::Text(text) => text,
+    _ => {
+      log::debug!("not persisting non text message for: '{}'", peer.bs58);
+      return Ok(());
+    }
+  };
+  log::trace!("inserting message for: '{}'", peer.bs58);
+
+  db.execute(
+    "INSERT OR IGNORE INTO messages (id, peer, sender, content, time) VALUES (?1, ?2, ?3, ?4, ?5)",
+    params![text.id, peer.bs58, text.sender, text.content, text.time],
+  )?;
+
+  Ok(())
+}
+
+/// Tries to get a page of at most `limit` messages from the chat history with `peer`</br>
+/// `offset` counts backwards from the newest message, so `offset = 0` returns the most recent page
+///
+/// The messages in the result [Vec<Msg>] are ordered from oldest to newest
+/// and carry their aggregated reactions
+///
+/// # Errors
+/// This function will return:</br>
+/// The first error returned by executing the underlying SQLite query on `db`
+pub fn get_page(
+  db: &mut Connection,
+  input: (&UserIdentifier, i64, usize),
+) -> Result<Vec<Msg>, rusqlite::Error> {
+  let (peer, limit, offset) = input;
+  let mut statement = db.prepare(
+    "SELECT id, sender, content, time FROM messages WHERE peer = (?1) ORDER BY id DESC LIMIT (?2) OFFSET (?3)",
+  )?;
+  let rows = statement.query_map(params![peer.bs58, limit, offset], |row| {
+    Ok(Msg::Text(Text {
+      id: row.get(0)?,
+      sender: row.get(1)?,
+      content: row.get(2)?,
+      time: row.get(3)?,
+      remojis: Vec::new(),
+    }))
+  })?;
+
+  let mut page = Vec::new();
+
+  for row in rows {
+    page.push(row?);
+  }
+  drop(statement);
+  page.reverse();
+
+  for msg in page.iter_mut() {
+    if let Msg::Text(text) = msg {
+      text.remojis = reaction::get_remojis(db, (peer, &text.id))?;
+    }
+  }
+
+  Ok(page)
+}
+
+impl ToSql for MsgId {
+  fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+    Ok(ToSqlOutput::from(self.to_string()))
+  }
+}
+
+impl FromSql for MsgId {
+  fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+    value
+      .as_str()?
+      .parse()
+      .map_err(|err| FromSqlError::Other(Box::new(err)))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::borrow::Cow;
+
+  use super::*;
+  use crate::data::sqlite::schema;
+  use crate::history::msg::{LOCAL_SENDER, PEER_SENDER};
+  use rusqlite::Connection;
+
+  fn init() {
+    let _ = env_logger::builder().is_test(true).try_init();
+  }
+
+  fn sample_peer(name: &str) -> UserIdentifier<'static> {
+    UserIdentifier {
+      bs58: Cow::Owned(name.to_string()),
+    }
+  }
+
+  fn sample_msgs() -> Vec<Msg> {
+    let mut all = vec![];
+    for i in 0..10 {
+      let time = 1_000_000 + i;
+      all.push(Msg::Text(Text {
+        id: MsgId::new(time),
+        sender: if i % 2 == 0 {
+          LOCAL_SENDER
+        } else {
+          PEER_SENDER
+        },
+        content: format!("message number {}", i),
+        time,
+        remojis: Vec::new(),
+      }));
+    }
+    all
+  }
+
+  fn create_sample_msgs(
+    db: &mut Connection,
+    peer: &UserIdentifier,
+  ) -> Result<Vec<Msg>, rusqlite::Error> {
+    let all = sample_msgs();
+    for msg in &all {
+      insert(db, (peer, msg))?;
+    }
+    Ok(all)
+  }
+
+  /// Tests if get_page returns the newest messages in chronological order
+  #[test]
+  fn get_newest_page() {
+    init();
+    let mut db = Connection::open_in_memory().unwrap();
+    schema::validate(&mut db).unwrap();
+
+    let peer = sample_peer("peer");
+    let exprected = match create_sample_msgs(&mut db, &peer) {
+      Ok(all) => all,
+      Err(err) => panic!("error executing 'insert' command: '{}'", err),
+    };
+
+    match get_page(&mut db, (&peer, 4, 0)) {
+      Err(err) => panic!("error executing 'get_page' command: '{}'", err),
+      Ok(result) => {
+        assert_eq!(
+          &result[..],
+          &exprected[6..],
+          "\nget_page returned 'left' but 'right' was expected"
+        );
+      }
+    }
+  }
+
+  /// Tests if `offset` pages backwards through the history
+  #[test]
+  fn get_older_page() {
+    init();
+    let mut db = Connection::open_in_memory().unwrap();
+    schema::validate(&mut db).unwrap();
+
+    let peer = sample_peer("peer");
+    let exprected = match create_sample_msgs(&mut db, &peer) {
+      Ok(all) => all,
+      Err(err) => panic!("error executing 'insert' command: '{}'", err),
+    };
+
+    match get_page(&mut db, (&peer, 4, 8)) {
+      Err(err) => panic!("error executing 'get_page' command: '{}'", err),
+      Ok(result) => {
+        assert_eq!(
+          &result[..],
+          &exprected[..2],
+          "\nget_page returned 'left' but 'right' was expected"
+        );
+      }
+    }
+  }
+
+  /// Tests if inserting the same message twice does not duplicate it
+  #[test]
+  fn insert_duplicate() {
+    init();
+    let mut db = Connection::open_in_memory().unwrap();
+    schema::validate(&mut db).unwrap();
+
+    let peer = sample_peer("peer");
+    let msg = sample_msgs().remove(0);
+    for _ in 0..2 {
+      if let Err(err) = insert(&mut db, (&peer, &msg)) {
+        panic!("error executing 'insert' command: '{}'", err);
+      }
+    }
+
+    match get_page(&mut db, (&peer, -1, 0)) {
+      Err(err) => panic!("error executing 'get_page' command: '{}'", err),
+      Ok(result) => assert_eq!(
+        result,
+        vec![msg],
+        "\nget_page returned 'left' but 'right' was inserted twice"
+      ),
+    }
+  }
+
+  /// Tests if inserting a whole history returns it in order and skips present messages
+  #[test]
+  fn insert_all_msgs() {
+    init();
+    let mut db = Connection::open_in_memory().unwrap();
+    schema::validate(&mut db).unwrap();
+
+    let peer = sample_peer("peer");
+    let msgs = sample_msgs();
+    insert(&mut db, (&peer, &msgs[3])).unwrap();
+    if let Err(err) = insert_all(&mut db, (&peer, &msgs)) {
+      panic!("error executing 'insert_all' command: '{}'", err);
+    }
+
+    match get_page(&mut db, (&peer, -1, 0)) {
+      Err(err) => panic!("error executing 'get_page' command: '{}'", err),
+      Ok(result) => assert_eq!(
+        result, msgs,
+        "\nget_page returned 'left' but 'right' was inserted"
+      ),
+    }
+  }
+
+  /// Tests if a peer reusing an id of another chat does not touch that chat
+  #[test]
+  fn insert_same_id_other_peer() {
+    init();
+    let mut db = Connection::open_in_memory().unwrap();
+    schema::validate(&mut db).unwrap();
+
+    let (peer, other) = (sample_peer("peer"), sample_peer("other peer"));
+    let msg = sample_msgs().remove(0);
+    let mut copy = msg.clone();
+    if let Msg::Text(text) = &mut copy {
+      text.content = "same id, other content".to_string();
+    }
+    insert(&mut db, (&peer, &msg)).unwrap();
+    insert(&mut db, (&other, &copy)).unwrap();
+
+    assert_eq!(get_page(&mut db, (&peer, -1, 0)).unwrap(), vec![msg]);
+    assert_eq!(
+      get_page(&mut db, (&other, -1, 0)).unwrap(),
+      vec![copy],
+      "\nget_page returned 'left' but 'right' was inserted with the same id in another chat"
+    );
+  }
+
+  /// Tests if the history of one peer does not leak into the history of another
+  #[test]
+  fn get_page_other_peer() {
+    init();
+    let mut db = Connection::open_in_memory().unwrap();
+    schema::validate(&mut db).unwrap();
+
+    let peer = sample_peer("peer");
+    if let Err(err) = create_sample_msgs(&mut db, &peer) {
+      panic!("error executing 'insert' command: '{}'", err);
+    }
+
+    match get_page(&mut db, (&sample_peer("other peer"), -1, 0)) {
+      Err(err) => panic!("error executing 'get_page' command: '{}'", err),
+      Ok(result) => assert!(
+        result.is_empty(),
+        "\nget_page returned '{:?}' but there is no history with that peer",
+        result
+      ),
+    }
+  }
+}
@@ -5,43 +5,62 @@ use rusqlite::Connection;
 
 use super::DATABASE;
 
+pub mod backup;
+pub mod block;
+pub mod message;
+pub mod outbox;
+pub mod policy;
+pub mod reaction;
+pub mod receipt;
 pub mod user;
 pub mod user_batch;
 
-/// Uses the crate local mutex sqlite connection to run the supplied action
-/// The supplied action MUST never panic
+/// Runs `action` on the database thread of the crate local sqlite connection
+///
+/// `action` usually calls one of the action functions of this module
+/// with data it owns, like `move |db| user::get(db, &identifier)`.
 ///
 /// # Errors
 /// This function logs the first error returned by the supplied action
 /// and returns an [io::Error] with [ErrorKind::Other] and no further information
-/// then "SQLite error"
+/// then "SQLite error"</br>
+/// [ErrorKind::BrokenPipe] if the database thread stopped or the action panicked
+pub async fn try_exec<F, O>(action: F) -> Result<O, io::Error>
+where
+  F: FnOnce(&mut Connection) -> Result<O, rusqlite::Error> + Send + 'static,
+  O: Send + 'static,
+{
+  DATABASE.run(action).await?.map_err(sqlite_error)
+}
+
+/// Runs `action` on the database thread of the crate local sqlite connection
 ///
 /// # Panics
-/// If said mutex is poisoned
-#[inline(always)]
-pub fn try_exec<F, T, O>(action: F, input: T) -> Result<O, std::io::Error>
+/// If the database thread stopped or the action panicked
+pub async fn exec<F, O>(action: F) -> O
 where
-  F: FnOnce(&mut Connection, T) -> Result<O, rusqlite::Error>,
+  F: FnOnce(&mut Connection) -> O + Send + 'static,
+  O: Send + 'static,
 {
-  let db = &*DATABASE;
-  let mut db = db.lock().unwrap();
-  action(&mut db, input).map_err(|err| {
-    log::error!("SQLite access error: '{}'", err);
-    io::Error::new(ErrorKind::Other, "SQLite error")
-  })
+  DATABASE.run(action).await.expect("database thread stopped")
 }
 
-/// Uses the crate local mutex sqlite connection to run the supplied action
-/// The supplied action MUST never panic
+/// Blocking version of [try_exec] for code that does not run inside the async runtime
 ///
 /// # Panics
-/// If said mutex is poisoned
-#[inline(always)]
-pub fn exec<F, T, O>(action: F, input: T) -> O
+/// If called from within the async runtime, see [Database::run_blocking]
+///
+/// # Errors
+/// See [try_exec]
+pub fn try_exec_blocking<F, O>(action: F) -> Result<O, io::Error>
 where
-  F: FnOnce(&mut Connection, T) -> O,
+  F: FnOnce(&mut Connection) -> Result<O, rusqlite::Error> + Send + 'static,
+  O: Send + 'static,
 {
-  let db = &*DATABASE;
-  let mut db = db.lock().unwrap();
-  action(&mut db, input)
+  DATABASE.run_blocking(action)?.map_err(sqlite_error)
+}
+
+fn sqlite_error(err: rusqlite::Error) -> io::Error {
+  log::error!("SQLite access error: '{}'", err);
+  io::Error::new(ErrorKind::Other, "SQLite error")
 }
@@ -0,0 +1,163 @@
+use rusqlite::{params, Connection};
+use serde::Serialize;
+
+use crate::data::UserIdentifier;
+use crate::history::msg::MsgId;
+
+/// Chat message of the local user waiting for a room with its recipient
+#[derive(Clone, PartialEq, Eq, Serialize, Debug)]
+pub struct Queued {
+  pub id: MsgId,
+  pub content: String,
+  /** Time at which the message was written (UTC unix time in milliseconds) */
+  pub time: u64,
+}
+
+/// Tries to add `msg` to the messages waiting for a room with `peer`
+///
+/// Queueing a message with an id that is already present is a no-op
+///
+/// # Errors
+/// This function will return:</br>
+/// The first error returned by executing the underlying SQLite query on `db`
+pub fn push(db: &mut Connection, input: (&UserIdentifier, &Queued)) -> Result<(), rusqlite::Error> {
+  let (peer, msg) = input;
+  log::trace!("queueing message for: '{}'", peer.bs58);
+
+  db.execute(
+    "INSERT OR IGNORE INTO outbox (id, peer, content, time) VALUES (?1, ?2, ?3, ?4)",
+    params![msg.id, peer.bs58, msg.content, msg.time],
+  )?;
+
+  Ok(())
+}
+
+/// Tries to get all messages waiting for a room with `peer`, ordered from oldest to newest
+///
+/// # Errors
+/// This function will return:</br>
+/// The first error returned by executing the underlying SQLite query on `db`
+pub fn get_all(db: &mut Connection, peer: &UserIdentifier) -> Result<Vec<Queued>, rusqlite::Error> {
+  let mut statement =
+    db.prepare("SELECT id, content, time FROM outbox WHERE peer = (?1) ORDER BY id ASC")?;
+  let rows = statement.query_map([&peer.bs58], |row| {
+    Ok(Queued {
+      id: row.get(0)?,
+      content: row.get(1)?,
+      time: row.get(2)?,
+    })
+  })?;
+
+  let mut all = Vec::new();
+
+  for row in rows {
+    all.push(row?);
+  }
+
+  Ok(all)
+}
+
+/// Tries to remove the message `id` from the outbox once it was sent
+///
+/// Returns `false` if the message was not queued
+///
+/// # Errors
+/// This function will return:</br>
+/// The first error returned by executing the underlying SQLite query on `db`
+pub fn remove(db: &mut Connection, id: &MsgId) -> Result<bool, rusqlite::Error> {
+  let changed = db.execute("DELETE FROM outbox WHERE id = (?1)", [id])?;
+  Ok(changed > 0)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::borrow::Cow;
+
+  use super::*;
+  use crate::data::sqlite::schema;
+  use rusqlite::Connection;
+
+  fn init() {
+    let _ = env_logger::builder().is_test(true).try_init();
+  }
+
+  fn sample_peer(name: &str) -> UserIdentifier<'static> {
+    UserIdentifier {
+      bs58: Cow::Owned(name.to_string()),
+    }
+  }
+
+  fn sample_queue(db: &mut Connection, peer: &UserIdentifier) -> Vec<Queued> {
+    let mut all = vec![];
+    for i in 0..3 {
+      let time = 1_000_000 + i;
+      let msg = Queued {
+        id: MsgId::new(time),
+        content: format!("queued number {}", i),
+        time,
+      };
+      if let Err(err) = push(db, (peer, &msg)) {
+        panic!("error executing 'push' command: '{}'", err);
+      }
+      all.push(msg);
+    }
+    all
+  }
+
+  /// Tests if queued messages are returned oldest first and only for their peer
+  #[test]
+  fn get_queued() {
+    init();
+    let mut db = Connection::open_in_memory().unwrap();
+    schema::validate(&mut db).unwrap();
+
+    let peer = sample_peer("peer");
+    let expected = sample_queue(&mut db, &peer);
+
+    match get_all(&mut db, &peer) {
+      Err(err) => panic!("error executing 'get_all' command: '{}'", err),
+      Ok(result) => assert_eq!(
+        result, expected,
+        "\nget_all returned 'left' but 'right' was expected"
+      ),
+    }
+    match get_all(&mut db, &sample_peer("other peer")) {
+      Err(err) => panic!("error executing 'get_all' command: '{}'", err),
+      Ok(result) => assert!(
+        result.is_empty(),
+        "\nget_all returned '{:?}' but nothing was queued for that peer",
+        result
+      ),
+    }
+  }
+
+  /// Tests if sent messages leave the outbox exactly once
+  #[test]
+  fn remove_sent() {
+    init();
+    let mut db = Connection::open_in_memory().unwrap();
+    schema::validate(&mut db).unwrap();
+
+    let peer = sample_peer("peer");
+    let mut expected = sample_queue(&mut db, &peer);
+    let sent = expected.remove(0);
+
+    let removed = remove(&mut db, &sent.id);
+    assert!(
+      matches!(removed, Ok(true)),
+      "\nremove returned {:?}",
+      removed
+    );
+    let removed = remove(&mut db, &sent.id);
+    assert!(
+      matches!(removed, Ok(false)),
+      "\nsecond remove returned {:?}",
+      removed
+    );
+
+    match get_all(&mut db, &peer) {
+      Err(err) => panic!("error executing 'get_all' command: '{}'", err),
+      Ok(result) => assert_eq!(result, expected),
+    }
+  }
+}
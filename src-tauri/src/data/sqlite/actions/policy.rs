@@ -0,0 +1,257 @@
+use rusqlite::{
+  params,
+  types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef},
+  Connection, OptionalExtension, ToSql,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::data::{UserIdentifier, UserRelation};
+
+/// How room requests of a user are answered
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum RoomPolicy {
+  /// The room is opened without asking the local user
+  Accept,
+  /// The local user decides using the "wants-room" event
+  Ask,
+  /// The request is rejected without telling the local user
+  Reject,
+}
+
+/// Policy used for every relation without a policy of its own
+pub const DEFAULT_POLICY: RoomPolicy = RoomPolicy::Ask;
+
+/// Policy for the room requests of every user with `relation`
+#[derive(Clone, PartialEq, Eq, Serialize, Debug)]
+pub struct RelationPolicy {
+  pub relation: UserRelation,
+  pub policy: RoomPolicy,
+}
+
+/// Tries to get the policy for room requests of `ident`
+///
+/// The policy of the user is used if there is one, the policy of its relation otherwise.
+/// Users without an entry are treated as [UserRelation::Stranger].
+///
+/// # Errors
+/// This function will return:</br>
+/// The first error returned by executing the underlying SQLite queries on `db`
+pub fn resolve(db: &mut Connection, ident: &UserIdentifier) -> Result<RoomPolicy, rusqlite::Error> {
+  let entry: Option<(Option<RoomPolicy>, u8)> = db
+    .query_row(
+      "SELECT policy, relation FROM users WHERE tls_cert = (?1)",
+      [&ident.bs58],
+      |row| Ok((row.get(0)?, row.get(1)?)),
+    )
+    .optional()?;
+
+  match entry {
+    Some((Some(policy), _)) => Ok(policy),
+    Some((None, relation)) => get_for_relation(db, UserRelation::from(relation)),
+    None => get_for_relation(db, UserRelation::Stranger),
+  }
+}
+
+/// Tries to get the policy for room requests of users with `relation`
+///
+/// # Errors
+/// This function will return:</br>
+/// The first error returned by executing the underlying SQLite query on `db`
+pub fn get_for_relation(
+  db: &mut Connection,
+  relation: UserRelation,
+) -> Result<RoomPolicy, rusqlite::Error> {
+  let policy = db
+    .query_row(
+      "SELECT policy FROM relation_policies WHERE relation = (?1)",
+      [relation as u8],
+      |row| row.get(0),
+    )
+    .optional()?;
+
+  Ok(policy.unwrap_or(DEFAULT_POLICY))
+}
+
+/// Tries to get the policy of every relation that can be assigned to a contact
+///
+/// # Errors
+/// This function will return:</br>
+/// The first error returned by executing the underlying SQLite queries on `db`
+pub fn get_all_relations(db: &mut Connection) -> Result<Vec<RelationPolicy>, rusqlite::Error> {
+  [
+    UserRelation::Friend,
+    UserRelation::Known,
+    UserRelation::Stranger,
+  ]
+  .into_iter()
+  .map(|relation| {
+    Ok(RelationPolicy {
+      relation,
+      policy: get_for_relation(db, relation)?,
+    })
+  })
+  .collect()
+}
+
+/// Tries to set the policy for room requests of users with `relation`
+///
+/// # Errors
+/// This function will return:</br>
+/// The first error returned by executing the underlying SQLite query on `db`
+pub fn set_for_relation(
+  db: &mut Connection,
+  input: (UserRelation, RoomPolicy),
+) -> Result<(), rusqlite::Error> {
+  let (relation, policy) = input;
+  log::trace!("setting policy of '{:?}' to: '{:?}'", relation, policy);
+
+  db.execute(
+    r#"INSERT INTO relation_policies (relation, policy) VALUES (?1, ?2)
+ON CONFLICT (relation) DO UPDATE SET policy = excluded.policy"#,
+    params![relation as u8, policy],
+  )?;
+
+  Ok(())
+}
+
+/// Tries to get the policy of `ident` itself, `None` if it follows the policy of its relation
+///
+/// # Errors
+/// This function will return:</br>
+/// The first error returned by executing the underlying SQLite query on `db`</br>
+/// [QueryReturnedNoRows](rusqlite::Error::QueryReturnedNoRows) error if there is no entry for `ident`
+pub fn get_for_user(
+  db: &mut Connection,
+  ident: &UserIdentifier,
+) -> Result<Option<RoomPolicy>, rusqlite::Error> {
+  db.query_row(
+    "SELECT policy FROM users WHERE tls_cert = (?1)",
+    [&ident.bs58],
+    |row| row.get(0),
+  )
+}
+
+/// Tries to set the policy of `ident` itself, `None` makes it follow the policy of its relation
+///
+/// Returns `false` if there is no entry for `ident`
+///
+/// # Errors
+/// This function will return:</br>
+/// The first error returned by executing the underlying SQLite query on `db`
+pub fn set_for_user(
+  db: &mut Connection,
+  input: (&UserIdentifier, Option<RoomPolicy>),
+) -> Result<bool, rusqlite::Error> {
+  let (ident, policy) = input;
+  log::trace!("setting policy of '{}' to: '{:?}'", ident.bs58, policy);
+
+  let changed = db.execute(
+    "UPDATE users SET policy = (?2) WHERE tls_cert = (?1)",
+    params![ident.bs58, policy],
+  )?;
+
+  Ok(changed > 0)
+}
+
+impl ToSql for RoomPolicy {
+  fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+    Ok(ToSqlOutput::from(*self as u8))
+  }
+}
+
+impl FromSql for RoomPolicy {
+  fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+    match value.as_i64()? {
+      0 => Ok(RoomPolicy::Accept),
+      1 => Ok(RoomPolicy::Ask),
+      2 => Ok(RoomPolicy::Reject),
+      other => Err(FromSqlError::OutOfRange(other)),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::borrow::Cow;
+
+  use super::*;
+  use crate::data::sqlite::{schema, user::upsert};
+  use crate::data::{IdentifiedUserInfo, UserInfo};
+
+  fn init() {
+    let _ = env_logger::builder().is_test(true).try_init();
+  }
+
+  fn create_sample_user(db: &mut Connection, relation: UserRelation) -> UserIdentifier<'static> {
+    let ident_info = IdentifiedUserInfo {
+      identifier: UserIdentifier {
+        bs58: Cow::Owned(format!("{:?} certificate string", relation)),
+      },
+      info: UserInfo {
+        username: "special username".into(),
+        relation,
+        nickname: None,
+      },
+    };
+    upsert(db, (&ident_info, |_| ())).unwrap();
+    ident_info.identifier
+  }
+
+  /// Tests if users follow the policy of their relation unless they have one of their own
+  #[test]
+  fn resolve_policy() {
+    init();
+    let mut db = Connection::open_in_memory().unwrap();
+    schema::validate(&mut db).unwrap();
+
+    let friend = create_sample_user(&mut db, UserRelation::Friend);
+    let known = create_sample_user(&mut db, UserRelation::Known);
+    let unknown = UserIdentifier {
+      bs58: Cow::Owned("unknown certificate string".to_string()),
+    };
+    assert_eq!(resolve(&mut db, &friend).unwrap(), DEFAULT_POLICY);
+
+    set_for_relation(&mut db, (UserRelation::Friend, RoomPolicy::Accept)).unwrap();
+    set_for_relation(&mut db, (UserRelation::Stranger, RoomPolicy::Reject)).unwrap();
+    assert_eq!(
+      resolve(&mut db, &friend).unwrap(),
+      RoomPolicy::Accept,
+      "\nresolve returned 'left' but 'right' was expected as it is the policy of the relation"
+    );
+    assert_eq!(resolve(&mut db, &known).unwrap(), DEFAULT_POLICY);
+    assert_eq!(resolve(&mut db, &unknown).unwrap(), RoomPolicy::Reject);
+    assert_eq!(
+      get_all_relations(&mut db).unwrap(),
+      vec![
+        RelationPolicy {
+          relation: UserRelation::Friend,
+          policy: RoomPolicy::Accept
+        },
+        RelationPolicy {
+          relation: UserRelation::Known,
+          policy: DEFAULT_POLICY
+        },
+        RelationPolicy {
+          relation: UserRelation::Stranger,
+          policy: RoomPolicy::Reject
+        },
+      ]
+    );
+
+    assert!(set_for_user(&mut db, (&friend, Some(RoomPolicy::Reject))).unwrap());
+    assert_eq!(
+      resolve(&mut db, &friend).unwrap(),
+      RoomPolicy::Reject,
+      "\nresolve returned 'left' but 'right' was expected as it is the policy of the user"
+    );
+    assert_eq!(
+      get_for_user(&mut db, &friend).unwrap(),
+      Some(RoomPolicy::Reject)
+    );
+
+    assert!(set_for_user(&mut db, (&friend, None)).unwrap());
+    assert_eq!(resolve(&mut db, &friend).unwrap(), RoomPolicy::Accept);
+    assert!(!set_for_user(&mut db, (&unknown, Some(RoomPolicy::Accept))).unwrap());
+  }
+}
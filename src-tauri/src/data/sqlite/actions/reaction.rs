@@ -0,0 +1,265 @@
+use rusqlite::{params, Connection};
+
+use crate::data::UserIdentifier;
+use crate::history::msg::{MsgId, Remoji};
+
+/// Tries to add the reaction `emoji` of `sender` to the message `target`
+/// from the chat history with `peer`
+///
+/// Returns `false` if there is no such message in the history with `peer`
+/// or `sender` already reacted with `emoji`
+///
+/// # Errors
+/// This function will return:</br>
+/// The first error returned by executing the underlying SQLite query on `db`
+pub fn add(
+  db: &mut Connection,
+  input: (&UserIdentifier, &MsgId, u8, &str),
+) -> Result<bool, rusqlite::Error> {
+  let (peer, target, sender, emoji) = input;
+  log::trace!("adding reaction '{}' to: '{}'", emoji, target);
+
+  let changed = db.execute(
+    r#"INSERT OR IGNORE INTO reactions (peer, msg_id, sender, emoji)
+SELECT (?4), (?1), (?2), (?3) WHERE EXISTS (SELECT 1 FROM messages WHERE id = (?1) AND peer = (?4))"#,
+    params![target, sender, emoji, peer.bs58],
+  )?;
+
+  Ok(changed > 0)
+}
+
+/// Tries to remove the reaction `emoji` of `sender` from the message `target`
+/// from the chat history with `peer`
+///
+/// Returns `false` if there was no such reaction in the history with `peer`
+///
+/// # Errors
+/// This function will return:</br>
+/// The first error returned by executing the underlying SQLite query on `db`
+pub fn remove(
+  db: &mut Connection,
+  input: (&UserIdentifier, &MsgId, u8, &str),
+) -> Result<bool, rusqlite::Error> {
+  let (peer, target, sender, emoji) = input;
+  log::trace!("removing reaction '{}' from: '{}'", emoji, target);
+
+  let changed = db.execute(
+    "DELETE FROM reactions WHERE peer = (?4) AND msg_id = (?1) AND sender = (?2) AND emoji = (?3)",
+    params![target, sender, emoji, peer.bs58],
+  )?;
+
+  Ok(changed > 0)
+}
+
+/// Tries to get the aggregated reactions to the message `target` from the chat history with `peer`
+///
+/// Every emoji appears once in the result [Vec<Remoji>],
+/// ordered by the time it was first used on `target`
+///
+/// # Errors
+/// This function will return:</br>
+/// The first error returned by executing the underlying SQLite query on `db`
+pub fn get_remojis(
+  db: &mut Connection,
+  input: (&UserIdentifier, &MsgId),
+) -> Result<Vec<Remoji>, rusqlite::Error> {
+  let (peer, target) = input;
+  let mut statement = db.prepare(
+    "SELECT emoji, COUNT(*) FROM reactions WHERE peer = (?1) AND msg_id = (?2) GROUP BY emoji ORDER BY MIN(rowid)",
+  )?;
+  let rows = statement.query_map(params![peer.bs58, target], |row| {
+    Ok(Remoji {
+      emoji: row.get(0)?,
+      quantity: row.get(1)?,
+    })
+  })?;
+
+  let mut all = Vec::new();
+
+  for row in rows {
+    all.push(row?);
+  }
+
+  Ok(all)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::borrow::Cow;
+
+  use super::*;
+  use crate::data::sqlite::{message, schema};
+  use crate::history::msg::{Msg, Text, LOCAL_SENDER, PEER_SENDER};
+  use rusqlite::Connection;
+
+  fn init() {
+    let _ = env_logger::builder().is_test(true).try_init();
+  }
+
+  fn sample_peer() -> UserIdentifier<'static> {
+    UserIdentifier {
+      bs58: Cow::Owned("peer".to_string()),
+    }
+  }
+
+  fn create_sample_msg(db: &mut Connection) -> MsgId {
+    let time = 1_000_000;
+    let id = MsgId::new(time);
+    let msg = Msg::Text(Text {
+      id,
+      sender: PEER_SENDER,
+      content: "react to me".into(),
+      time,
+      remojis: Vec::new(),
+    });
+    if let Err(err) = message::insert(db, (&sample_peer(), &msg)) {
+      panic!("error executing 'insert' command: '{}'", err);
+    }
+    id
+  }
+
+  fn remoji(emoji: &str, quantity: u16) -> Remoji {
+    Remoji {
+      emoji: emoji.into(),
+      quantity,
+    }
+  }
+
+  /// Tests if reactions of both users are aggregated per emoji
+  #[test]
+  fn aggregate() {
+    init();
+    let mut db = Connection::open_in_memory().unwrap();
+    schema::validate(&mut db).unwrap();
+
+    let peer = sample_peer();
+    let target = create_sample_msg(&mut db);
+    let reactions = [
+      (LOCAL_SENDER, "😃"),
+      (PEER_SENDER, "👍🏽"),
+      (PEER_SENDER, "😃"),
+    ];
+    for (sender, emoji) in reactions {
+      match add(&mut db, (&peer, &target, sender, emoji)) {
+        Ok(added) => assert!(added, "\n'{}' by {} was not added", emoji, sender),
+        Err(err) => panic!("error executing 'add' command: '{}'", err),
+      }
+    }
+
+    match get_remojis(&mut db, (&peer, &target)) {
+      Err(err) => panic!("error executing 'get_remojis' command: '{}'", err),
+      Ok(result) => assert_eq!(
+        result,
+        vec![remoji("😃", 2), remoji("👍🏽", 1)],
+        "\nget_remojis returned 'left' but 'right' was expected"
+      ),
+    }
+  }
+
+  /// Tests if the same user reacting twice with the same emoji counts once
+  #[test]
+  fn add_twice() {
+    init();
+    let mut db = Connection::open_in_memory().unwrap();
+    schema::validate(&mut db).unwrap();
+
+    let peer = sample_peer();
+    let target = create_sample_msg(&mut db);
+    let first = add(&mut db, (&peer, &target, PEER_SENDER, "😃"));
+    let second = add(&mut db, (&peer, &target, PEER_SENDER, "😃"));
+    assert!(
+      matches!(first, Ok(true)),
+      "\nfirst add returned {:?}",
+      first
+    );
+    assert!(
+      matches!(second, Ok(false)),
+      "\nsecond add returned {:?}",
+      second
+    );
+
+    match get_remojis(&mut db, (&peer, &target)) {
+      Err(err) => panic!("error executing 'get_remojis' command: '{}'", err),
+      Ok(result) => assert_eq!(result, vec![remoji("😃", 1)]),
+    }
+  }
+
+  /// Tests if removing a reaction only removes the reaction of that user
+  #[test]
+  fn remove_own() {
+    init();
+    let mut db = Connection::open_in_memory().unwrap();
+    schema::validate(&mut db).unwrap();
+
+    let peer = sample_peer();
+    let target = create_sample_msg(&mut db);
+    for sender in [LOCAL_SENDER, PEER_SENDER] {
+      if let Err(err) = add(&mut db, (&peer, &target, sender, "😃")) {
+        panic!("error executing 'add' command: '{}'", err);
+      }
+    }
+
+    let removed = remove(&mut db, (&peer, &target, PEER_SENDER, "😃"));
+    assert!(
+      matches!(removed, Ok(true)),
+      "\nremove returned {:?}",
+      removed
+    );
+    let removed = remove(&mut db, (&peer, &target, PEER_SENDER, "😃"));
+    assert!(
+      matches!(removed, Ok(false)),
+      "\nremove returned {:?}",
+      removed
+    );
+
+    match get_remojis(&mut db, (&peer, &target)) {
+      Err(err) => panic!("error executing 'get_remojis' command: '{}'", err),
+      Ok(result) => assert_eq!(result, vec![remoji("😃", 1)]),
+    }
+  }
+
+  /// Tests if reactions to messages that are not in the history with that peer are rejected
+  #[test]
+  fn add_unknown_target() {
+    init();
+    let mut db = Connection::open_in_memory().unwrap();
+    schema::validate(&mut db).unwrap();
+
+    let target = create_sample_msg(&mut db);
+    let stranger = UserIdentifier {
+      bs58: Cow::Owned("stranger".to_string()),
+    };
+
+    let unknown = add(&mut db, (&sample_peer(), &MsgId::new(1), PEER_SENDER, "😃"));
+    assert!(matches!(unknown, Ok(false)), "\nadd returned {:?}", unknown);
+    let foreign = add(&mut db, (&stranger, &target, PEER_SENDER, "😃"));
+    assert!(matches!(foreign, Ok(false)), "\nadd returned {:?}", foreign);
+  }
+
+  /// Tests if reactions to messages that are not in the history with that peer are kept
+  #[test]
+  fn remove_foreign_target() {
+    init();
+    let mut db = Connection::open_in_memory().unwrap();
+    schema::validate(&mut db).unwrap();
+
+    let target = create_sample_msg(&mut db);
+    if let Err(err) = add(&mut db, (&sample_peer(), &target, LOCAL_SENDER, "😃")) {
+      panic!("error executing 'add' command: '{}'", err);
+    }
+    let stranger = UserIdentifier {
+      bs58: Cow::Owned("stranger".to_string()),
+    };
+
+    let foreign = remove(&mut db, (&stranger, &target, LOCAL_SENDER, "😃"));
+    assert!(
+      matches!(foreign, Ok(false)),
+      "\nremove returned {:?}",
+      foreign
+    );
+    match get_remojis(&mut db, (&sample_peer(), &target)) {
+      Err(err) => panic!("error executing 'get_remojis' command: '{}'", err),
+      Ok(result) => assert_eq!(result, vec![remoji("😃", 1)]),
+    }
+  }
+}
@@ -0,0 +1,230 @@
+use rusqlite::{
+  params,
+  types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef},
+  Connection, OptionalExtension, ToSql,
+};
+use serde::Serialize;
+
+use crate::data::UserIdentifier;
+use crate::history::msg::{MessageState, MsgId, LOCAL_SENDER};
+
+/// Last known [MessageState] of a message written by the local user
+#[derive(Clone, PartialEq, Eq, Serialize, Debug)]
+pub struct Receipt {
+  pub id: MsgId,
+  pub state: MessageState,
+}
+
+/// Tries to advance the state of the message `target` written by the local user
+/// to `peer` to `state`
+///
+/// Returns `false` if there is no such message in the history with `peer`
+/// or it already reached `state` or a later one
+///
+/// # Errors
+/// This function will return:</br>
+/// The first error returned by executing the underlying SQLite query on `db`
+pub fn advance(
+  db: &mut Connection,
+  input: (&UserIdentifier, &MsgId, MessageState),
+) -> Result<bool, rusqlite::Error> {
+  let (peer, target, state) = input;
+  log::trace!("advancing '{}' to: '{:?}'", target, state);
+
+  let changed = db.execute(
+    r#"INSERT INTO receipts (peer, msg_id, state)
+SELECT (?3), (?1), (?2) WHERE EXISTS (SELECT 1 FROM messages WHERE id = (?1) AND peer = (?3) AND sender = (?4))
+ON CONFLICT (peer, msg_id) DO UPDATE SET state = excluded.state WHERE excluded.state > receipts.state"#,
+    params![target, state, peer.bs58, LOCAL_SENDER],
+  )?;
+
+  Ok(changed > 0)
+}
+
+/// Tries to get the last known state of every message in `ids` of the chat with `peer`
+///
+/// Messages without a known state are left out of the result [Vec<Receipt>]
+///
+/// # Errors
+/// This function will return:</br>
+/// The first error returned by executing the underlying SQLite query on `db`
+pub fn get_all(
+  db: &mut Connection,
+  input: (&UserIdentifier, &[MsgId]),
+) -> Result<Vec<Receipt>, rusqlite::Error> {
+  let (peer, ids) = input;
+  let mut statement =
+    db.prepare("SELECT state FROM receipts WHERE peer = (?1) AND msg_id = (?2)")?;
+
+  let mut all = Vec::new();
+
+  for id in ids {
+    let state = statement
+      .query_row(params![peer.bs58, id], |row| row.get(0))
+      .optional()?;
+    if let Some(state) = state {
+      all.push(Receipt { id: *id, state });
+    }
+  }
+
+  Ok(all)
+}
+
+impl ToSql for MessageState {
+  fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+    Ok(ToSqlOutput::from(*self as u8))
+  }
+}
+
+impl FromSql for MessageState {
+  fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+    match value.as_i64()? {
+      0 => Ok(MessageState::Queued),
+      1 => Ok(MessageState::Sent),
+      2 => Ok(MessageState::Delivered),
+      3 => Ok(MessageState::Read),
+      other => Err(FromSqlError::OutOfRange(other)),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::borrow::Cow;
+
+  use super::*;
+  use crate::data::sqlite::{message, schema, user};
+  use crate::history::msg::{Msg, Text, PEER_SENDER};
+  use rusqlite::Connection;
+
+  fn init() {
+    let _ = env_logger::builder().is_test(true).try_init();
+  }
+
+  fn sample_peer() -> UserIdentifier<'static> {
+    UserIdentifier {
+      bs58: Cow::Owned("peer".to_string()),
+    }
+  }
+
+  fn create_sample_msg(db: &mut Connection, sender: u8) -> MsgId {
+    let time = 1_000_000;
+    let id = MsgId::new(time);
+    let msg = Msg::Text(Text {
+      id,
+      sender,
+      content: "did you get this?".into(),
+      time,
+      remojis: Vec::new(),
+    });
+    if let Err(err) = message::insert(db, (&sample_peer(), &msg)) {
+      panic!("error executing 'insert' command: '{}'", err);
+    }
+    id
+  }
+
+  /// Tests if states only advance and late receipts do not move them back
+  #[test]
+  fn advance_only() {
+    init();
+    let mut db = Connection::open_in_memory().unwrap();
+    schema::validate(&mut db).unwrap();
+
+    let peer = sample_peer();
+    let target = create_sample_msg(&mut db, LOCAL_SENDER);
+    let steps = [
+      (MessageState::Sent, true),
+      (MessageState::Read, true),
+      (MessageState::Delivered, false),
+      (MessageState::Read, false),
+    ];
+    for (state, expected) in steps {
+      match advance(&mut db, (&peer, &target, state)) {
+        Ok(changed) => assert_eq!(
+          changed, expected,
+          "\nadvance to {:?} returned 'left' but 'right' was expected",
+          state
+        ),
+        Err(err) => panic!("error executing 'advance' command: '{}'", err),
+      }
+    }
+
+    match get_all(&mut db, (&peer, &[target])) {
+      Err(err) => panic!("error executing 'get_all' command: '{}'", err),
+      Ok(result) => assert_eq!(
+        result,
+        vec![Receipt {
+          id: target,
+          state: MessageState::Read
+        }],
+        "\nget_all returned 'left' but 'right' was expected"
+      ),
+    }
+  }
+
+  /// Tests if receipts for messages of the peer or unknown messages are ignored
+  #[test]
+  fn ignore_foreign() {
+    init();
+    let mut db = Connection::open_in_memory().unwrap();
+    schema::validate(&mut db).unwrap();
+
+    let peer = sample_peer();
+    let other = UserIdentifier {
+      bs58: Cow::Owned("other peer".to_string()),
+    };
+    let own = create_sample_msg(&mut db, LOCAL_SENDER);
+    let foreign = create_sample_msg(&mut db, PEER_SENDER);
+    let unknown = MsgId::new(1_000_000);
+
+    for (who, target) in [(&peer, &foreign), (&peer, &unknown), (&other, &own)] {
+      let res = advance(&mut db, (who, target, MessageState::Delivered));
+      assert!(matches!(res, Ok(false)), "\nadvance returned {:?}", res);
+    }
+
+    match get_all(&mut db, (&peer, &[own, foreign, unknown])) {
+      Err(err) => panic!("error executing 'get_all' command: '{}'", err),
+      Ok(result) => assert!(
+        result.is_empty(),
+        "\nget_all returned '{:?}' but no state was known",
+        result
+      ),
+    }
+  }
+
+  /// Tests if the same message id in another chat neither shares nor loses its state
+  #[test]
+  fn scoped_by_chat() {
+    init();
+    let mut db = Connection::open_in_memory().unwrap();
+    schema::validate(&mut db).unwrap();
+
+    let peer = sample_peer();
+    let other = UserIdentifier {
+      bs58: Cow::Owned("other peer".to_string()),
+    };
+    let target = create_sample_msg(&mut db, LOCAL_SENDER);
+    let copy = Msg::Text(Text {
+      id: target,
+      sender: LOCAL_SENDER,
+      content: "same id, other chat".into(),
+      time: 1_000_000,
+      remojis: Vec::new(),
+    });
+    message::insert(&mut db, (&other, &copy)).unwrap();
+
+    assert!(advance(&mut db, (&peer, &target, MessageState::Read)).unwrap());
+    assert!(advance(&mut db, (&other, &target, MessageState::Sent)).unwrap());
+    user::delete(&mut db, &other).unwrap();
+
+    assert_eq!(
+      get_all(&mut db, (&peer, &[target])).unwrap(),
+      vec![Receipt {
+        id: target,
+        state: MessageState::Read
+      }],
+      "\nget_all returned 'left' but 'right' was expected as the chat with 'peer' was kept"
+    );
+    assert!(get_all(&mut db, (&other, &[target])).unwrap().is_empty());
+  }
+}
@@ -10,15 +10,17 @@ use crate::data::{IdentifiedUserInfo, UserIdentifier, UserInfo, UserRelation};
 /// The first error returned by executing the underlying SQLite query on `db`</br>
 /// [QueryReturnedNoRows] error if the entry is not present in the 'db'
 pub fn try_get(db: &mut Connection, data: &UserIdentifier) -> Result<UserInfo, rusqlite::Error> {
-  let mut statement = db.prepare("SELECT username, relation FROM users WHERE tls_cert = (?1)")?;
+  let mut statement =
+    db.prepare("SELECT username, relation, nickname FROM users WHERE tls_cert = (?1)")?;
   let mut rows = statement.query_map([&data.bs58], |row| {
     let username: String = row.get(0)?;
     let relation = UserRelation::from(row.get::<usize, u8>(1)?);
-    Ok((username, relation))
+    let nickname: Option<String> = row.get(2)?;
+    Ok((username, relation, nickname))
   })?;
 
   if let Some(row) = rows.next() {
-    let (name, relation) = row?;
+    let (name, relation, nickname) = row?;
     if rows.next().is_some() {
       warn!(
         "more then one database entry for certifificate: '{}'",
@@ -28,6 +30,7 @@ pub fn try_get(db: &mut Connection, data: &UserIdentifier) -> Result<UserInfo, r
     Ok(UserInfo {
       username: name,
       relation,
+      nickname,
     })
   } else {
     log::debug!("no database entry for '{}'", &data.bs58);
@@ -48,6 +51,7 @@ pub fn get(db: &mut Connection, data: &UserIdentifier) -> UserInfo {
       UserInfo {
         username: data.bs58.to_string(),
         relation: UserRelation::Stranger,
+        nickname: None,
       }
     }
   }
@@ -85,6 +89,142 @@ SET username = excluded.username, relation = excluded.relation"#,
   Ok(())
 }
 
+/// Tries to store the username of the user info entry without touching its relation or nickname,
+/// the whole entry is inserted if it is not present yet
+///
+/// Returns the stored user info
+///
+/// # Errors
+/// This function will return:</br>
+/// The first error returned by executing the underlying SQLite queries on `db`
+pub fn rename(
+  db: &mut Connection,
+  ident_info: &IdentifiedUserInfo,
+) -> Result<UserInfo, rusqlite::Error> {
+  log::trace!("renaming entry for: '{}'", ident_info.identifier.bs58);
+
+  db.execute(
+    r#"INSERT INTO users (tls_cert, username, relation) VALUES (?1, ?2, ?3)
+ON CONFLICT (tls_cert) DO UPDATE SET username = excluded.username"#,
+    params![
+      ident_info.identifier.bs58,
+      ident_info.info.username,
+      ident_info.info.relation as u8,
+    ],
+  )?;
+
+  try_get(db, &ident_info.identifier)
+}
+
+/// Tries to set the relation of the user info entry of `ident` to `relation`
+///
+/// Returns `false` if there is no entry for `ident`
+///
+/// # Errors
+/// This function will return:</br>
+/// The first error returned by executing the underlying SQLite query on `db`
+pub fn set_relation(
+  db: &mut Connection,
+  input: (&UserIdentifier, UserRelation),
+) -> Result<bool, rusqlite::Error> {
+  let (ident, relation) = input;
+  log::trace!("setting relation of '{}' to: '{:?}'", ident.bs58, relation);
+
+  let changed = db.execute(
+    "UPDATE users SET relation = (?2) WHERE tls_cert = (?1)",
+    params![ident.bs58, relation as u8],
+  )?;
+
+  Ok(changed > 0)
+}
+
+/// Tries to set the local nickname of the user info entry of `ident`,
+/// `None` removes the nickname
+///
+/// Returns `false` if there is no entry for `ident`
+///
+/// # Errors
+/// This function will return:</br>
+/// The first error returned by executing the underlying SQLite query on `db`
+pub fn set_nickname(
+  db: &mut Connection,
+  input: (&UserIdentifier, Option<&str>),
+) -> Result<bool, rusqlite::Error> {
+  let (ident, nickname) = input;
+  log::trace!("setting nickname of '{}' to: '{:?}'", ident.bs58, nickname);
+
+  let changed = db.execute(
+    "UPDATE users SET nickname = (?2) WHERE tls_cert = (?1)",
+    params![ident.bs58, nickname],
+  )?;
+
+  Ok(changed > 0)
+}
+
+/// Tries to delete the user info entry of `ident` together with the chat history,
+/// reactions, delivery states and queued messages of the chats with that user
+///
+/// Returns `false` if there is no entry for `ident`
+///
+/// # Errors
+/// This function will return:</br>
+/// The first error returned by executing the underlying SQLite queries on `db`
+pub fn delete(db: &mut Connection, ident: &UserIdentifier) -> Result<bool, rusqlite::Error> {
+  log::trace!("deleting entry for: '{}'", ident.bs58);
+
+  let tx = db.transaction()?;
+  tx.execute("DELETE FROM reactions WHERE peer = (?1)", [&ident.bs58])?;
+  tx.execute("DELETE FROM receipts WHERE peer = (?1)", [&ident.bs58])?;
+  tx.execute("DELETE FROM messages WHERE peer = (?1)", [&ident.bs58])?;
+  tx.execute("DELETE FROM outbox WHERE peer = (?1)", [&ident.bs58])?;
+  let deleted = tx.execute("DELETE FROM users WHERE tls_cert = (?1)", [&ident.bs58])?;
+  tx.commit()?;
+
+  Ok(deleted > 0)
+}
+
+/// Moves the user entry, chat history, delivery states and outbox of `old` to `new`
+/// after the user replaced its identity, keeping username and relation
+///
+/// Returns `false` if there is no entry for `old`
+///
+/// # Errors
+/// This function will return:</br>
+/// A constraint violation if there already is an entry for `new`, nothing is moved then</br>
+/// The first error returned by executing the underlying SQLite queries on `db`
+pub fn migrate(
+  db: &mut Connection,
+  input: (&UserIdentifier, &UserIdentifier),
+) -> Result<bool, rusqlite::Error> {
+  let (old, new) = input;
+  log::trace!("migrating entry of '{}' to '{}'", old.bs58, new.bs58);
+
+  let tx = db.transaction()?;
+  let migrated = tx.execute(
+    "UPDATE users SET tls_cert = (?2) WHERE tls_cert = (?1)",
+    [&old.bs58, &new.bs58],
+  )?;
+  tx.execute(
+    "UPDATE messages SET peer = (?2) WHERE peer = (?1)",
+    [&old.bs58, &new.bs58],
+  )?;
+  tx.execute(
+    "UPDATE reactions SET peer = (?2) WHERE peer = (?1)",
+    [&old.bs58, &new.bs58],
+  )?;
+  tx.execute(
+    "UPDATE receipts SET peer = (?2) WHERE peer = (?1)",
+    [&old.bs58, &new.bs58],
+  )?;
+  tx.execute(
+    "UPDATE outbox SET peer = (?2) WHERE peer = (?1)",
+    [&old.bs58, &new.bs58],
+  )?;
+  tx.commit()?;
+
+  Ok(migrated > 0)
+}
+
 #[cfg(test)]
 mod tests {
   use std::borrow::Cow;
@@ -107,6 +247,7 @@ mod tests {
     UserInfo {
       relation: UserRelation::Known,
       username: "special username".into(),
+      nickname: None,
     }
   }
 
@@ -114,6 +255,7 @@ mod tests {
     UserInfo {
       relation: UserRelation::Friend,
       username: "updated username".into(),
+      nickname: None,
     }
   }
 
@@ -144,7 +286,7 @@ mod tests {
   fn get_non_existing_user() {
     init();
     let mut db = Connection::open_in_memory().unwrap();
-    schema::validate(&mut db);
+    schema::validate(&mut db).unwrap();
 
     let ident = sample_user_ident();
 
@@ -153,6 +295,7 @@ mod tests {
     let exprected = UserInfo {
       relation: UserRelation::Stranger,
       username: ident.bs58.into_owned(),
+      nickname: None,
     };
     assert_eq!(
       result, exprected,
@@ -165,7 +308,7 @@ mod tests {
   fn insert_user() {
     init();
     let mut db = Connection::open_in_memory().unwrap();
-    schema::validate(&mut db);
+    schema::validate(&mut db).unwrap();
 
     if let Err(err) = create_sample_user(&mut db) {
       panic!("error executing 'upsert' command: '{}'", err);
@@ -177,7 +320,7 @@ mod tests {
   fn insert_update_user() {
     init();
     let mut db = Connection::open_in_memory().unwrap();
-    schema::validate(&mut db);
+    schema::validate(&mut db).unwrap();
 
     let result = create_sample_user(&mut db);
     if let Err(err) = result {
@@ -195,7 +338,7 @@ mod tests {
   fn get_inserted_user() {
     init();
     let mut db = Connection::open_in_memory().unwrap();
-    schema::validate(&mut db);
+    schema::validate(&mut db).unwrap();
 
     let result = create_sample_user(&mut db);
     if let Err(err) = result {
@@ -216,7 +359,7 @@ mod tests {
   fn get_updated_user() {
     init();
     let mut db = Connection::open_in_memory().unwrap();
-    schema::validate(&mut db);
+    schema::validate(&mut db).unwrap();
 
     let result = create_sample_user(&mut db);
     if let Err(err) = result {
@@ -235,4 +378,146 @@ mod tests {
       "\nget returned 'left' but 'right' was expected as it has previously been inserterd/updated"
     );
   }
+
+  /// Tests if a migrated user keeps its info under the new identifier
+  #[test]
+  fn migrate_user() {
+    init();
+    let mut db = Connection::open_in_memory().unwrap();
+    schema::validate(&mut db).unwrap();
+
+    let old = sample_user_ident();
+    let new = UserIdentifier {
+      bs58: Cow::Owned("rotated certificate string".to_string()),
+    };
+    let stale = IdentifiedUserInfo {
+      identifier: new.clone(),
+      info: UserInfo {
+        relation: UserRelation::Stranger,
+        username: new.bs58.to_string(),
+        nickname: None,
+      },
+    };
+    create_sample_user(&mut db).unwrap();
+    upsert(&mut db, (&stale, |_| ())).unwrap();
+
+    assert!(migrate(&mut db, (&old, &new)).is_err());
+    assert_eq!(
+      get(&mut db, &new),
+      stale.info,
+      "\nget returned 'left' but 'right' was expected as the existing entry must not be replaced"
+    );
+    assert_eq!(get(&mut db, &old), sample_user_info());
+
+    assert!(delete(&mut db, &new).unwrap());
+    assert!(migrate(&mut db, (&old, &new)).unwrap());
+    assert_eq!(
+      get(&mut db, &new),
+      sample_user_info(),
+      "\nget returned 'left' but 'right' was expected as it has been migrated"
+    );
+    assert!(try_get(&mut db, &old).is_err());
+    assert!(!migrate(&mut db, (&old, &new)).unwrap());
+  }
+
+  /// Tests if a nickname survives the user changing their username
+  #[test]
+  fn rename_keeps_nickname() {
+    init();
+    let mut db = Connection::open_in_memory().unwrap();
+    schema::validate(&mut db).unwrap();
+
+    let ident = sample_user_ident();
+    create_sample_user(&mut db).unwrap();
+    assert!(set_nickname(&mut db, (&ident, Some("nickname"))).unwrap());
+    assert!(set_relation(&mut db, (&ident, UserRelation::Friend)).unwrap());
+
+    let renamed = IdentifiedUserInfo {
+      identifier: ident.clone(),
+      info: sample_user_info_updated(),
+    };
+    let result = rename(&mut db, &renamed).unwrap();
+
+    let exprected = UserInfo {
+      relation: UserRelation::Friend,
+      username: "updated username".into(),
+      nickname: Some("nickname".into()),
+    };
+    assert_eq!(
+      result, exprected,
+      "\nrename returned 'left' but 'right' was expected as only the username changed"
+    );
+    assert_eq!(result.display_name(), "nickname");
+
+    assert!(set_nickname(&mut db, (&ident, None)).unwrap());
+    assert_eq!(get(&mut db, &ident).display_name(), "updated username");
+  }
+
+  /// Tests if changing an unknown user reports that there is no entry
+  #[test]
+  fn change_non_existing_user() {
+    init();
+    let mut db = Connection::open_in_memory().unwrap();
+    schema::validate(&mut db).unwrap();
+
+    let ident = sample_user_ident();
+    assert!(!set_relation(&mut db, (&ident, UserRelation::Friend)).unwrap());
+    assert!(!set_nickname(&mut db, (&ident, Some("nickname"))).unwrap());
+    assert!(!delete(&mut db, &ident).unwrap());
+  }
+
+  /// Tests if deleting a user removes their chat history but keeps other chats
+  #[test]
+  fn delete_user() {
+    use crate::data::sqlite::{message, outbox, reaction};
+    use crate::history::msg::{Msg, MsgId, Text};
+
+    init();
+    let mut db = Connection::open_in_memory().unwrap();
+    schema::validate(&mut db).unwrap();
+
+    let ident = sample_user_ident();
+    let other = UserIdentifier {
+      bs58: Cow::Owned("other certificate string".to_string()),
+    };
+    create_sample_user(&mut db).unwrap();
+    for (id, peer) in [(1, &ident), (2, &other)] {
+      let text = Msg::Text(Text {
+        id: MsgId(id),
+        sender: 0,
+        content: "hello".to_string(),
+        time: 1_700_000_000_000,
+        remojis: Vec::new(),
+      });
+      message::insert(&mut db, (peer, &text)).unwrap();
+      reaction::add(&mut db, (peer, &MsgId(id), 1, "👍")).unwrap();
+    }
+    let queued = outbox::Queued {
+      id: MsgId(3),
+      content: "later".to_string(),
+      time: 1_700_000_000_000,
+    };
+    outbox::push(&mut db, (&ident, &queued)).unwrap();
+
+    assert!(delete(&mut db, &ident).unwrap());
+    assert!(try_get(&mut db, &ident).is_err());
+    assert!(message::get_page(&mut db, (&ident, 10, 0))
+      .unwrap()
+      .is_empty());
+    assert!(outbox::get_all(&mut db, &ident).unwrap().is_empty());
+    assert!(reaction::get_remojis(&mut db, (&ident, &MsgId(1)))
+      .unwrap()
+      .is_empty());
+    assert_eq!(
+      message::get_page(&mut db, (&other, 10, 0)).unwrap().len(),
+      1,
+      "\nget_page returned 'left' but 'right' was expected as only the deleted user loses history"
+    );
+    assert_eq!(
+      reaction::get_remojis(&mut db, (&other, &MsgId(2)))
+        .unwrap()
+        .len(),
+      1
+    );
+  }
 }
@@ -18,17 +18,22 @@ pub fn get_limit_offset<'a>(
 ) -> Result<Vec<IdentifiedUserInfo<'a>>, rusqlite::Error> {
   let (limit, offset) = range;
   let mut statement = db.prepare(
-    "SELECT tls_cert, username, relation FROM users WHERE relation < 255 LIMIT (?1) OFFSET (?2)",
+    "SELECT tls_cert, username, relation, nickname FROM users WHERE relation < 255 LIMIT (?1) OFFSET (?2)",
   )?;
   let rows = statement.query_map(params![limit, offset], |row| {
     let cert: String = row.get(0)?;
     let username: String = row.get(1)?;
     let relation = UserRelation::from(row.get::<usize, u8>(2)?);
+    let nickname: Option<String> = row.get(3)?;
     let ident_info = IdentifiedUserInfo {
       identifier: UserIdentifier {
         bs58: Cow::Owned(cert),
       },
-      info: UserInfo { username, relation },
+      info: UserInfo {
+        username,
+        relation,
+        nickname,
+      },
     };
     Ok(ident_info)
   })?;
@@ -64,6 +69,7 @@ mod tests {
         info: UserInfo {
           username: format!("generic_username{}", i),
           relation: UserRelation::Known,
+          nickname: None,
         },
       });
     }
@@ -91,7 +97,7 @@ mod tests {
   fn get_exhausted_limit() {
     init();
     let mut db = Connection::open_in_memory().unwrap();
-    schema::validate(&mut db);
+    schema::validate(&mut db).unwrap();
 
     let result = create_sample_users(&mut db);
     if let Err(err) = result {
@@ -120,7 +126,7 @@ mod tests {
   fn get_exhausted_limit_offset() {
     init();
     let mut db = Connection::open_in_memory().unwrap();
-    schema::validate(&mut db);
+    schema::validate(&mut db).unwrap();
 
     let result = create_sample_users(&mut db);
     if let Err(err) = result {
@@ -149,7 +155,7 @@ mod tests {
   fn get_overshoot_limit() {
     init();
     let mut db = Connection::open_in_memory().unwrap();
-    schema::validate(&mut db);
+    schema::validate(&mut db).unwrap();
 
     let result = create_sample_users(&mut db);
     if let Err(err) = result {
@@ -179,7 +185,7 @@ mod tests {
   fn get_overshoot_limit_offset() {
     init();
     let mut db = Connection::open_in_memory().unwrap();
-    schema::validate(&mut db);
+    schema::validate(&mut db).unwrap();
 
     let result = create_sample_users(&mut db);
     if let Err(err) = result {
@@ -209,7 +215,7 @@ mod tests {
   fn get_all_offset() {
     init();
     let mut db = Connection::open_in_memory().unwrap();
-    schema::validate(&mut db);
+    schema::validate(&mut db).unwrap();
 
     let result = create_sample_users(&mut db);
     if let Err(err) = result {
@@ -238,7 +244,7 @@ mod tests {
   fn get_all() {
     init();
     let mut db = Connection::open_in_memory().unwrap();
-    schema::validate(&mut db);
+    schema::validate(&mut db).unwrap();
 
     let result = create_sample_users(&mut db);
     if let Err(err) = result {
@@ -267,7 +273,7 @@ mod tests {
   fn get_all_exclude_local() {
     init();
     let mut db = Connection::open_in_memory().unwrap();
-    schema::validate(&mut db);
+    schema::validate(&mut db).unwrap();
 
     // local user
     let user = IdentifiedUserInfo {
@@ -277,6 +283,7 @@ mod tests {
       info: UserInfo {
         username: "local_user_username".into(),
         relation: UserRelation::Local,
+        nickname: None,
       },
     };
 
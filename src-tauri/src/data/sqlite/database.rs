@@ -0,0 +1,157 @@
+use std::io::{self, ErrorKind};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc as std_mpsc;
+use std::thread;
+
+use rusqlite::Connection;
+use tokio::sync::{mpsc, oneshot};
+
+/// Maximum number of jobs waiting for the database thread,
+/// submitting more jobs waits until there is space again
+pub const QUEUE_LEN: usize = 64;
+
+type Job = Box<dyn FnOnce(&mut Connection) + Send>;
+
+/// Handle to the thread that owns the sqlite connection
+///
+/// Jobs are run one after another in the order they were submitted,
+/// async callers wait for their turn without blocking a runtime worker.
+pub struct Database {
+  jobs: mpsc::Sender<Job>,
+}
+
+impl Database {
+  /// Moves `db` to a new thread that runs the jobs submitted using the returned [Database]
+  ///
+  /// The thread stops once every [Database] handle is dropped
+  pub fn spawn(mut db: Connection) -> Database {
+    let (jobs, mut rx) = mpsc::channel::<Job>(QUEUE_LEN);
+    thread::Builder::new()
+      .name("warehouse".to_string())
+      .spawn(move || {
+        while let Some(job) = rx.blocking_recv() {
+          // a panicking job only loses its own result, the connection stays usable
+          if panic::catch_unwind(AssertUnwindSafe(|| job(&mut db))).is_err() {
+            log::error!("SQLite job panicked");
+          }
+        }
+        log::trace!("database thread stopped");
+      })
+      .expect("Could not spawn the database thread");
+
+    Database { jobs }
+  }
+
+  /// Runs `job` on the database thread and waits for its result
+  ///
+  /// # Errors
+  /// This function will return:</br>
+  /// [ErrorKind::BrokenPipe] if the database thread stopped or `job` panicked
+  pub async fn run<F, O>(&self, job: F) -> io::Result<O>
+  where
+    F: FnOnce(&mut Connection) -> O + Send + 'static,
+    O: Send + 'static,
+  {
+    let (tx, rx) = oneshot::channel();
+    let job: Job = Box::new(move |db| {
+      let _ = tx.send(job(db));
+    });
+
+    self.jobs.send(job).await.map_err(|_| stopped())?;
+    rx.await.map_err(|_| stopped())
+  }
+
+  /// Blocking version of [Database::run] for code that does not run inside the async runtime,
+  /// async code can call it using [tokio::task::spawn_blocking]
+  ///
+  /// # Panics
+  /// If called from within the async runtime, see [mpsc::Sender::blocking_send]
+  ///
+  /// # Errors
+  /// See [Database::run]
+  pub fn run_blocking<F, O>(&self, job: F) -> io::Result<O>
+  where
+    F: FnOnce(&mut Connection) -> O + Send + 'static,
+    O: Send + 'static,
+  {
+    let (tx, rx) = std_mpsc::sync_channel(1);
+    let job: Job = Box::new(move |db| {
+      let _ = tx.send(job(db));
+    });
+
+    self.jobs.blocking_send(job).map_err(|_| stopped())?;
+    rx.recv().map_err(|_| stopped())
+  }
+}
+
+fn stopped() -> io::Error {
+  io::Error::new(ErrorKind::BrokenPipe, "database thread stopped")
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+
+  use super::*;
+
+  fn init() {
+    let _ = env_logger::builder().is_test(true).try_init();
+  }
+
+  fn sample_database() -> Database {
+    let db = Connection::open_in_memory().unwrap();
+    db.execute(r#"CREATE TABLE "numbers" ("n" INTEGER NOT NULL);"#, [])
+      .unwrap();
+    Database::spawn(db)
+  }
+
+  /// Tests if more jobs than fit into the queue are all run
+  #[tokio::test]
+  async fn run_beyond_queue() {
+    init();
+    let database = Arc::new(sample_database());
+    let total = QUEUE_LEN as u32 * 2;
+
+    let mut handles = Vec::new();
+    for n in 0..total {
+      let database = database.clone();
+      handles.push(tokio::spawn(async move {
+        database
+          .run(move |db| db.execute("INSERT INTO numbers (n) VALUES (?1)", [n]))
+          .await
+      }));
+    }
+    for handle in handles {
+      assert_eq!(handle.await.unwrap().unwrap().unwrap(), 1);
+    }
+
+    let count: u32 = database
+      .run(|db| db.query_row("SELECT COUNT(*) FROM numbers", [], |row| row.get(0)))
+      .await
+      .unwrap()
+      .unwrap();
+    assert_eq!(
+      count, total,
+      "\nrun returned 'left' but 'right' was expected as that many rows were inserted"
+    );
+  }
+
+  /// Tests if a panicking job leaves the database usable
+  #[tokio::test]
+  async fn survive_panic() {
+    init();
+    let database = Arc::new(sample_database());
+
+    let err = database.run(|_| -> u32 { panic!("job panicked") }).await;
+    assert_eq!(err.unwrap_err().kind(), ErrorKind::BrokenPipe);
+
+    let count: u32 = tokio::task::spawn_blocking(move || {
+      database.run_blocking(|db| db.query_row("SELECT COUNT(*) FROM numbers", [], |row| row.get(0)))
+    })
+    .await
+    .unwrap()
+    .unwrap()
+    .unwrap();
+    assert_eq!(count, 0);
+  }
+}
@@ -1,18 +1,36 @@
 mod actions;
+mod database;
 mod schema;
 
 use log::{warn, info};
 use once_cell::sync::Lazy;
 use rusqlite::Connection;
-use std::sync::Mutex;
 
 use crate::data::path::DATA;
 
 pub use actions::*;
+pub use database::Database;
 
-pub static DATABASE: Lazy<Mutex<Connection>> = Lazy::new(generate);
+pub static DATABASE: Lazy<Database> = Lazy::new(|| Database::spawn(generate()));
 
-fn generate() -> Mutex<Connection> {
+fn generate() -> Connection {
+  // tests must never touch the database file of the user
+  if !cfg!(test) {
+    if let Some(db) = open_file() {
+      return db;
+    }
+  }
+
+  warn!("Using in memory database");
+  // using expect here is safe as sqlite does not return an error
+  // when creating in memory database
+  let mut db = Connection::open_in_memory().expect("In memory database creation failed");
+  schema::validate(&mut db).expect("In memory database migration failed");
+  db
+}
+
+/// Opens and migrates the database file, `None` if it is unusable
+fn open_file() -> Option<Connection> {
   let mut path = DATA.clone();
   path.push("warehouse.db3");
   let db = Connection::open(&path);
@@ -20,17 +38,23 @@ fn generate() -> Mutex<Connection> {
   match db {
     Err(err) => {
       warn!("Unable to open database file: {}", err);
-      warn!("Using in memory database");
-      // using unwrap here is safe as sqlite does not return an error
-      // when creating in memory database
-      let mut db = Connection::open_in_memory().expect("In memory database creation failed");
-      schema::validate(&mut db);
-      Mutex::new(db)
     }
     Ok(mut db) => {
       info!("[created/write_open] file: {path:?}");
-      schema::validate(&mut db);
-      Mutex::new(db)
+      // appending commits to a log is cheaper than rewriting pages of the database file
+      if let Err(err) = db.pragma_update(None, "journal_mode", "WAL") {
+        warn!("Unable to use write ahead logging: {}", err);
+      }
+      match schema::validate(&mut db) {
+        Ok(version) if version > schema::LATEST => {
+          warn!("Database file has the newer schema version {version}, it is used as it is");
+          return Some(db);
+        }
+        Ok(_) => return Some(db),
+        Err(err) => warn!("Unable to migrate database file: {}", err),
+      }
     }
   }
+
+  None
 }
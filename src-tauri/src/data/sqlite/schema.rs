@@ -1,11 +1,78 @@
-use rusqlite::Connection;
+//! Versioned schema of the warehouse
+//!
+//! The version of a database is stored in `PRAGMA user_version`,
+//! version `n` means the first `n` steps of [MIGRATIONS] have been applied.
+//! Steps are only ever appended, a released step must not change anymore.
 
-pub fn validate(db: &mut Connection) {
-  validate_user_table(db);
+use rusqlite::{Connection, Transaction};
+
+/// Single step from one schema version to the next one
+type Migration = fn(&Transaction) -> Result<(), rusqlite::Error>;
+
+/// Every migration step in the order they have to be applied
+///
+/// The steps creating the tables use `IF NOT EXISTS` as databases from before
+/// versioning was introduced have these tables already while being at version 0.
+const MIGRATIONS: &[Migration] = &[
+  create_user_table,
+  create_message_table,
+  create_reaction_table,
+  create_outbox_table,
+  create_receipt_table,
+  add_user_nickname,
+  create_block_table,
+  create_policy_tables,
+  scope_message_ids,
+  scope_receipts,
+];
+
+/// Schema version of a database with every migration applied
+pub const LATEST: u32 = MIGRATIONS.len() as u32;
+
+/// Tries to migrate `db` to the [LATEST] schema version
+///
+/// Each step is applied in its own transaction, a failing step is rolled back
+/// leaving `db` at the version of the last successful step.
+/// A database with a newer version than [LATEST] is left as it is.</br>
+/// Returns the version of `db` afterwards
+///
+/// # Errors
+/// This function will return:</br>
+/// The first error returned by reading the version of `db` or applying a migration step
+pub fn validate(db: &mut Connection) -> Result<u32, rusqlite::Error> {
+  migrate(db, MIGRATIONS)
+}
+
+fn migrate(db: &mut Connection, migrations: &[Migration]) -> Result<u32, rusqlite::Error> {
+  let current = version(db)?;
+  let latest = migrations.len() as u32;
+  if current > latest {
+    log::warn!(
+      "database schema version {} is newer than the latest known version {}",
+      current,
+      latest
+    );
+    return Ok(current);
+  }
+
+  for (step, migration) in migrations.iter().enumerate().skip(current as usize) {
+    let target = step as u32 + 1;
+    log::info!("migrating database schema to version {}", target);
+    let tx = db.transaction()?;
+    migration(&tx)?;
+    tx.pragma_update(None, "user_version", target)?;
+    tx.commit()?;
+  }
+
+  Ok(latest)
+}
+
+fn version(db: &Connection) -> Result<u32, rusqlite::Error> {
+  db.pragma_query_value(None, "user_version", |row| row.get(0))
 }
 
-fn validate_user_table(db: &mut Connection) {
-  db.execute(
+fn create_user_table(tx: &Transaction) -> Result<(), rusqlite::Error> {
+  tx.execute(
     r#"CREATE TABLE IF NOT EXISTS "users" (
 "tls_cert" TEXT NOT NULL UNIQUE,
 "username" TEXT NOT NULL,
@@ -13,18 +80,322 @@ fn validate_user_table(db: &mut Connection) {
 PRIMARY KEY("tls_cert")
 );"#,
     [],
+  )?;
+  Ok(())
+}
+
+fn create_message_table(tx: &Transaction) -> Result<(), rusqlite::Error> {
+  tx.execute(
+    r#"CREATE TABLE IF NOT EXISTS "messages" (
+"id" TEXT NOT NULL UNIQUE,
+"peer" TEXT NOT NULL,
+"sender" INTEGER NOT NULL,
+"content" TEXT NOT NULL,
+"time" INTEGER NOT NULL,
+PRIMARY KEY("id")
+);"#,
+    [],
+  )?;
+  tx.execute(
+    r#"CREATE INDEX IF NOT EXISTS "messages_peer" ON "messages" ("peer", "id");"#,
+    [],
+  )?;
+  Ok(())
+}
+
+fn create_reaction_table(tx: &Transaction) -> Result<(), rusqlite::Error> {
+  tx.execute(
+    r#"CREATE TABLE IF NOT EXISTS "reactions" (
+"msg_id" TEXT NOT NULL,
+"sender" INTEGER NOT NULL,
+"emoji" TEXT NOT NULL,
+PRIMARY KEY("msg_id", "sender", "emoji")
+);"#,
+    [],
+  )?;
+  Ok(())
+}
+
+fn create_outbox_table(tx: &Transaction) -> Result<(), rusqlite::Error> {
+  tx.execute(
+    r#"CREATE TABLE IF NOT EXISTS "outbox" (
+"id" TEXT NOT NULL UNIQUE,
+"peer" TEXT NOT NULL,
+"content" TEXT NOT NULL,
+"time" INTEGER NOT NULL,
+PRIMARY KEY("id")
+);"#,
+    [],
+  )?;
+  tx.execute(
+    r#"CREATE INDEX IF NOT EXISTS "outbox_peer" ON "outbox" ("peer", "id");"#,
+    [],
+  )?;
+  Ok(())
+}
+
+fn create_receipt_table(tx: &Transaction) -> Result<(), rusqlite::Error> {
+  tx.execute(
+    r#"CREATE TABLE IF NOT EXISTS "receipts" (
+"msg_id" TEXT NOT NULL UNIQUE,
+"state" INTEGER NOT NULL,
+PRIMARY KEY("msg_id")
+);"#,
+    [],
+  )?;
+  Ok(())
+}
+
+fn add_user_nickname(tx: &Transaction) -> Result<(), rusqlite::Error> {
+  tx.execute(r#"ALTER TABLE "users" ADD COLUMN "nickname" TEXT;"#, [])?;
+  Ok(())
+}
+
+fn create_block_table(tx: &Transaction) -> Result<(), rusqlite::Error> {
+  tx.execute(
+    r#"CREATE TABLE "blocks" (
+"tls_cert" TEXT NOT NULL UNIQUE,
+PRIMARY KEY("tls_cert")
+);"#,
+    [],
+  )?;
+  Ok(())
+}
+
+fn create_policy_tables(tx: &Transaction) -> Result<(), rusqlite::Error> {
+  tx.execute(r#"ALTER TABLE "users" ADD COLUMN "policy" INTEGER;"#, [])?;
+  tx.execute(
+    r#"CREATE TABLE "relation_policies" (
+"relation" INTEGER NOT NULL UNIQUE,
+"policy" INTEGER NOT NULL,
+PRIMARY KEY("relation")
+);"#,
+    [],
+  )?;
+  Ok(())
+}
+
+/// Message ids are chosen by the peer that wrote the message,
+/// so they are only unique within the chat with that peer
+fn scope_message_ids(tx: &Transaction) -> Result<(), rusqlite::Error> {
+  tx.execute_batch(
+    r#"CREATE TABLE "scoped_messages" (
+"peer" TEXT NOT NULL,
+"id" TEXT NOT NULL,
+"sender" INTEGER NOT NULL,
+"content" TEXT NOT NULL,
+"time" INTEGER NOT NULL,
+PRIMARY KEY("peer", "id")
+);
+INSERT INTO "scoped_messages" ("peer", "id", "sender", "content", "time")
+SELECT "peer", "id", "sender", "content", "time" FROM "messages";
+
+CREATE TABLE "scoped_reactions" (
+"peer" TEXT NOT NULL,
+"msg_id" TEXT NOT NULL,
+"sender" INTEGER NOT NULL,
+"emoji" TEXT NOT NULL,
+PRIMARY KEY("peer", "msg_id", "sender", "emoji")
+);
+INSERT INTO "scoped_reactions" ("peer", "msg_id", "sender", "emoji")
+SELECT "messages"."peer", "reactions"."msg_id", "reactions"."sender", "reactions"."emoji"
+FROM "reactions" JOIN "messages" ON "messages"."id" = "reactions"."msg_id"
+ORDER BY "reactions"."rowid";
+
+DROP TABLE "reactions";
+DROP TABLE "messages";
+ALTER TABLE "scoped_messages" RENAME TO "messages";
+ALTER TABLE "scoped_reactions" RENAME TO "reactions";"#,
+  )
+}
+
+/// Receipts belong to messages of the local user, whose ids are only unique within a chat as well
+fn scope_receipts(tx: &Transaction) -> Result<(), rusqlite::Error> {
+  tx.execute_batch(
+    r#"CREATE TABLE "scoped_receipts" (
+"peer" TEXT NOT NULL,
+"msg_id" TEXT NOT NULL,
+"state" INTEGER NOT NULL,
+PRIMARY KEY("peer", "msg_id")
+);
+INSERT OR IGNORE INTO "scoped_receipts" ("peer", "msg_id", "state")
+SELECT "messages"."peer", "receipts"."msg_id", "receipts"."state"
+FROM "receipts" JOIN "messages" ON "messages"."id" = "receipts"."msg_id" AND "messages"."sender" = 0;
+
+DROP TABLE "receipts";
+ALTER TABLE "scoped_receipts" RENAME TO "receipts";"#,
   )
-  .unwrap();
 }
 
 #[cfg(test)]
 mod tests {
-  use rusqlite::Connection;
+  use super::*;
+
+  fn init() {
+    let _ = env_logger::builder().is_test(true).try_init();
+  }
+
+  fn tables(db: &Connection) -> Vec<String> {
+    let mut statement = db
+      .prepare("SELECT name FROM sqlite_master WHERE type = 'table' ORDER BY name")
+      .unwrap();
+    let rows = statement.query_map([], |row| row.get(0)).unwrap();
+    rows.map(|row| row.unwrap()).collect()
+  }
+
+  fn definitions(db: &Connection) -> Vec<String> {
+    let mut statement = db
+      .prepare("SELECT sql FROM sqlite_master WHERE sql NOT NULL ORDER BY name")
+      .unwrap();
+    let rows = statement.query_map([], |row| row.get(0)).unwrap();
+    rows.map(|row| row.unwrap()).collect()
+  }
 
   #[test]
   fn validate() {
+    init();
+    let mut db = Connection::open_in_memory().unwrap();
+    super::validate(&mut db).unwrap(); //run once to test creation
+    super::validate(&mut db).unwrap(); //run twice to test validation of existing schema
+    assert_eq!(version(&db).unwrap(), LATEST);
+  }
+
+  /// Tests if a database at any historical version ends up with the latest schema
+  #[test]
+  fn migrate_every_version() {
+    init();
+    let mut expected = Connection::open_in_memory().unwrap();
+    super::validate(&mut expected).unwrap();
+
+    for historical in 0..=MIGRATIONS.len() {
+      let mut db = Connection::open_in_memory().unwrap();
+      assert_eq!(
+        migrate(&mut db, &MIGRATIONS[..historical]).unwrap(),
+        historical as u32
+      );
+
+      assert_eq!(
+        super::validate(&mut db).unwrap(),
+        LATEST,
+        "\nvalidate returned 'left' but 'right' was expected migrating from version {}",
+        historical
+      );
+      assert_eq!(version(&db).unwrap(), LATEST);
+      assert_eq!(
+        definitions(&db),
+        definitions(&expected),
+        "\ndefinitions returned 'left' but 'right' was expected migrating from version {}",
+        historical
+      );
+    }
+  }
+
+  /// Tests if messages and reactions stored with global ids end up in the chat they belong to
+  #[test]
+  fn scope_existing_messages() {
+    init();
     let mut db = Connection::open_in_memory().unwrap();
-    super::validate(&mut db); //run once to test creation
-    super::validate(&mut db); //run twice to test validation of existing schema
+    // the version right before scope_message_ids
+    migrate(&mut db, &MIGRATIONS[..8]).unwrap();
+    db.execute_batch(
+      r#"INSERT INTO messages (id, peer, sender, content, time) VALUES ('1', 'peer', 1, 'hello', 0);
+INSERT INTO reactions (msg_id, sender, emoji) VALUES ('1', 0, 'x');"#,
+    )
+    .unwrap();
+
+    assert_eq!(super::validate(&mut db).unwrap(), LATEST);
+    let peer: String = db
+      .query_row("SELECT peer FROM reactions WHERE msg_id = '1'", [], |row| {
+        row.get(0)
+      })
+      .unwrap();
+    assert_eq!(
+      peer, "peer",
+      "\nthe reaction got the peer 'left' but 'right' was expected as it is the peer of the message"
+    );
+    db.execute(
+      "INSERT INTO messages (id, peer, sender, content, time) VALUES ('1', 'other', 1, 'hi', 0)",
+      [],
+    )
+    .unwrap();
+  }
+
+  /// Tests if receipts stored with global ids end up in the chat of the local message they belong to
+  #[test]
+  fn scope_existing_receipts() {
+    init();
+    let mut db = Connection::open_in_memory().unwrap();
+    migrate(&mut db, &MIGRATIONS[..MIGRATIONS.len() - 1]).unwrap();
+    db.execute_batch(
+      r#"INSERT INTO messages (peer, id, sender, content, time) VALUES ('peer', '1', 0, 'hello', 0);
+INSERT INTO messages (peer, id, sender, content, time) VALUES ('other', '1', 1, 'hi', 0);
+INSERT INTO receipts (msg_id, state) VALUES ('1', 2);"#,
+    )
+    .unwrap();
+
+    assert_eq!(super::validate(&mut db).unwrap(), LATEST);
+    let receipts: Vec<(String, u8)> = db
+      .prepare("SELECT peer, state FROM receipts WHERE msg_id = '1'")
+      .unwrap()
+      .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+      .unwrap()
+      .map(|row| row.unwrap())
+      .collect();
+    assert_eq!(
+      receipts,
+      vec![("peer".to_string(), 2)],
+      "\nthe receipts were 'left' but 'right' was expected as only 'peer' got the local message"
+    );
+  }
+
+  /// Tests if a database created before versioning keeps its tables and data
+  #[test]
+  fn migrate_unversioned() {
+    init();
+    let mut db = Connection::open_in_memory().unwrap();
+    db.execute(
+      r#"CREATE TABLE "users" ("tls_cert" TEXT NOT NULL UNIQUE, "username" TEXT NOT NULL, "relation" INTEGER NOT NULL, PRIMARY KEY("tls_cert"));"#,
+      [],
+    )
+    .unwrap();
+    db.execute(
+      "INSERT INTO users (tls_cert, username, relation) VALUES ('cert', 'name', 0)",
+      [],
+    )
+    .unwrap();
+
+    assert_eq!(super::validate(&mut db).unwrap(), LATEST);
+    let count: u32 = db
+      .query_row("SELECT COUNT(*) FROM users", [], |row| row.get(0))
+      .unwrap();
+    assert_eq!(count, 1);
+  }
+
+  /// Tests if a failing step is rolled back leaving the last successful version
+  #[test]
+  fn failing_step_rolls_back() {
+    init();
+    fn failing(tx: &Transaction) -> Result<(), rusqlite::Error> {
+      tx.execute(r#"CREATE TABLE "partial" ("id" INTEGER);"#, [])?;
+      tx.execute("INSERT INTO missing VALUES (1)", [])?;
+      Ok(())
+    }
+    let migrations: &[Migration] = &[create_user_table, failing, create_message_table];
+
+    let mut db = Connection::open_in_memory().unwrap();
+    assert!(migrate(&mut db, migrations).is_err());
+    assert_eq!(version(&db).unwrap(), 1);
+    assert_eq!(tables(&db), vec!["users".to_string()]);
+  }
+
+  /// Tests if a database from a newer release is left untouched
+  #[test]
+  fn newer_version() {
+    init();
+    let mut db = Connection::open_in_memory().unwrap();
+    db.pragma_update(None, "user_version", LATEST + 1).unwrap();
+
+    assert_eq!(super::validate(&mut db).unwrap(), LATEST + 1);
+    assert!(tables(&db).is_empty());
   }
 }
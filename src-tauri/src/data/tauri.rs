@@ -1,27 +1,119 @@
 use std::borrow::Cow;
+use std::io::{self, ErrorKind};
+use std::path::PathBuf;
 
+use rusqlite::{Connection, OptionalExtension};
 use tauri::Window;
 
+use super::bundle::{self, Imported};
+use super::identity::{self, IdentityState};
+use super::servers::{self, ServerProfile, ServerProfiles, SERVERS};
+use super::settings::{self, Settings, SETTINGS};
+use super::sqlite::message::{self, get_page};
+use super::sqlite::policy::{self, RelationPolicy, RoomPolicy};
+use super::sqlite::receipt::{self, Receipt};
 use super::sqlite::user_batch::get_limit_offset;
-use super::{cert_gen, config, IdentifiedUserInfo, UserIdentifier, UserInfo};
+use super::{cert_gen, config, IdentifiedUserInfo, UserIdentifier, UserInfo, UserRelation};
+use crate::history::encode::{decode_hist, encode_hist};
+use crate::history::msg::{Msg, MsgId};
 
-use super::sqlite::{exec, try_exec, user::*};
+use super::sqlite::{
+  exec, try_exec,
+  user::{self, *},
+};
 
-#[tauri::command]
-pub fn get_usr_info(bs58cert: String) -> UserInfo {
+#[tauri::command(async)]
+pub async fn get_usr_info(bs58cert: String) -> UserInfo {
   let user = UserIdentifier {
-    bs58: Cow::Borrowed(&bs58cert),
+    bs58: Cow::Owned(bs58cert),
   };
 
-  exec(get, &user)
+  exec(move |db| get(db, &user)).await
 }
 
-#[tauri::command]
-pub fn get_usrs<'a>(
+#[tauri::command(async)]
+pub async fn get_usrs(
   limit: i64,
   offset: usize,
-) -> Result<Vec<IdentifiedUserInfo<'a>>, tauri::Error> {
-  try_exec(get_limit_offset, (limit, offset)).map_err(tauri::Error::Io)
+) -> Result<Vec<IdentifiedUserInfo<'static>>, tauri::Error> {
+  try_exec(move |db| get_limit_offset(db, (limit, offset)))
+    .await
+    .map_err(tauri::Error::Io)
+}
+
+/// Returns a page of the chat history with the user identified by `bs58cert`</br>
+/// `offset` counts backwards from the newest message, see [get_page]
+#[tauri::command(async)]
+pub async fn get_history(
+  bs58cert: String,
+  limit: i64,
+  offset: usize,
+) -> Result<Vec<Msg>, tauri::Error> {
+  let peer = UserIdentifier {
+    bs58: Cow::Owned(bs58cert),
+  };
+
+  try_exec(move |db| get_page(db, (&peer, limit, offset)))
+    .await
+    .map_err(tauri::Error::Io)
+}
+
+/// Returns the last known delivery state of every message in `ids` written by the local user
+/// to the user identified by `bs58cert`
+#[tauri::command(async)]
+pub async fn get_message_states(
+  bs58cert: String,
+  ids: Vec<MsgId>,
+) -> Result<Vec<Receipt>, tauri::Error> {
+  let peer = UserIdentifier {
+    bs58: Cow::Owned(bs58cert),
+  };
+
+  try_exec(move |db| receipt::get_all(db, (&peer, &ids)))
+    .await
+    .map_err(tauri::Error::Io)
+}
+
+/// Writes the whole chat history with the user identified by `bs58cert` to `path`
+/// as a blob created by [encode_hist]
+///
+/// # Errors
+/// This function will return:</br>
+/// Any [io::Error] from reading the history or writing the file
+#[tauri::command(async)]
+pub async fn export_history(bs58cert: String, path: PathBuf) -> Result<(), tauri::Error> {
+  let peer = UserIdentifier {
+    bs58: Cow::Owned(bs58cert),
+  };
+
+  // a negative limit makes sqlite return every message
+  let msgs = try_exec(move |db| get_page(db, (&peer, -1, 0))).await?;
+  let blob = encode_hist(&msgs)?;
+  tokio::fs::write(path, blob).await?;
+  Ok(())
+}
+
+/// Adds the messages of a blob written by [export_history] at `path`
+/// to the chat history with the user identified by `bs58cert`
+///
+/// Messages that are already present are skipped, reactions are not restored.</br>
+/// Returns the number of messages in the blob
+///
+/// # Errors
+/// This function will return:</br>
+/// Any [io::Error] from reading the file</br>
+/// Any error returned by [decode_hist]
+#[tauri::command(async)]
+pub async fn import_history(bs58cert: String, path: PathBuf) -> Result<usize, tauri::Error> {
+  let peer = UserIdentifier {
+    bs58: Cow::Owned(bs58cert),
+  };
+
+  let blob = tokio::fs::read(path).await?;
+  let msgs = decode_hist(&blob)?;
+  let count = msgs.len();
+  try_exec(move |db| message::insert_all(db, (&peer, &msgs))).await?;
+  Ok(count)
 }
 
 #[tauri::command]
@@ -30,28 +122,350 @@ pub fn get_local<'a>() -> Option<IdentifiedUserInfo<'a>> {
   lock.clone()
 }
 
-#[tauri::command]
-pub fn update_username(window: Window, name: String) {
-  let frontend_event = |info: &IdentifiedUserInfo| {
-    let event = format!("usr_name_{}", info.identifier.bs58);
-    if let Err(err) = window.emit(&event, &info.info.username) {
-      log::error!("Failed to emit event: '{}'", err);
+#[tauri::command(async)]
+pub async fn update_username(window: Window, name: String) {
+  // the lock is released before waiting for the database
+  let id_info = {
+    let mut lock = config::IDI.write().unwrap();
+    match lock.as_mut() {
+      Some(id_info) if name != id_info.info.username => {
+        id_info.info.username = name;
+        id_info.clone()
+      }
+      _ => return,
     }
   };
-  let mut lock = config::IDI.write().unwrap();
-  let option = lock.as_mut();
-  if let Some(mut id_info) = option {
-    if name != id_info.info.username {
-      id_info.info.username = name;
-      match try_exec(upsert, (id_info, frontend_event)) {
-        Ok(()) => (),
-        Err(err) => log::warn!("Could not update local username: '{}'", err),
-      }
+
+  let event = format!("usr_name_{}", id_info.identifier.bs58);
+  if let Err(err) = window.emit(&event, &id_info.info.username) {
+    log::error!("Failed to emit event: '{}'", err);
+  }
+  if let Err(err) = try_exec(move |db| upsert(db, (&id_info, |_| ()))).await {
+    log::warn!("Could not update local username: '{}'", err);
+  }
+}
+
+/// Sets the relation of the user identified by `bs58cert` to `relation`,
+/// the changed entry is reported using the "user-updated" event
+///
+/// # Errors
+/// This function will return:</br>
+/// [ErrorKind::InvalidInput] if `relation` cannot be assigned or `bs58cert` is the local user</br>
+/// [ErrorKind::NotFound] if there is no entry for the user
+#[tauri::command(async)]
+pub async fn set_relation(
+  window: Window,
+  bs58cert: String,
+  relation: UserRelation,
+) -> Result<(), tauri::Error> {
+  if let UserRelation::Local | UserRelation::Undefined = relation {
+    return Err(tauri::Error::Io(io::Error::new(
+      ErrorKind::InvalidInput,
+      "relation cannot be assigned to a contact",
+    )));
+  }
+
+  change_contact(&window, bs58cert, move |db, ident| {
+    user::set_relation(db, (ident, relation))
+  })
+  .await
+}
+
+/// Gives the user identified by `bs58cert` a local nickname that is shown instead of their username,
+/// an empty `nickname` removes it again
+///
+/// The nickname stays in place when the user changes their username.
+/// The changed entry is reported using the "user-updated" event.
+///
+/// # Errors
+/// This function will return:</br>
+/// [ErrorKind::InvalidInput] if `bs58cert` is the local user</br>
+/// [ErrorKind::NotFound] if there is no entry for the user
+#[tauri::command(async)]
+pub async fn set_nickname(
+  window: Window,
+  bs58cert: String,
+  nickname: Option<String>,
+) -> Result<(), tauri::Error> {
+  let nickname = nickname
+    .map(|nickname| nickname.trim().to_string())
+    .filter(|nickname| !nickname.is_empty());
+
+  change_contact(&window, bs58cert, move |db, ident| {
+    user::set_nickname(db, (ident, nickname.as_deref()))
+  })
+  .await
+}
+
+/// Returns the room request policy of every relation that can be assigned to a contact
+#[tauri::command(async)]
+pub async fn get_relation_policies() -> Result<Vec<RelationPolicy>, tauri::Error> {
+  try_exec(policy::get_all_relations)
+    .await
+    .map_err(tauri::Error::Io)
+}
+
+/// Sets how room requests of users with `relation` are answered,
+/// users with a policy of their own are not affected
+///
+/// # Errors
+/// This function will return:</br>
+/// [ErrorKind::InvalidInput] if `relation` cannot be assigned to a contact
+#[tauri::command(async)]
+pub async fn set_relation_policy(
+  relation: UserRelation,
+  policy: RoomPolicy,
+) -> Result<(), tauri::Error> {
+  if let UserRelation::Local | UserRelation::Undefined = relation {
+    return Err(tauri::Error::Io(io::Error::new(
+      ErrorKind::InvalidInput,
+      "relation cannot be assigned to a contact",
+    )));
+  }
+
+  try_exec(move |db| policy::set_for_relation(db, (relation, policy)))
+    .await
+    .map_err(tauri::Error::Io)
+}
+
+/// Returns the room request policy of the user identified by `bs58cert`,
+/// `None` if they follow the policy of their relation
+///
+/// # Errors
+/// This function will return:</br>
+/// [ErrorKind::InvalidInput] if `bs58cert` is the local user</br>
+/// [ErrorKind::NotFound] if there is no entry for the user
+#[tauri::command(async)]
+pub async fn get_contact_policy(bs58cert: String) -> Result<Option<RoomPolicy>, tauri::Error> {
+  let ident = contact(bs58cert)?;
+  try_exec(move |db| policy::get_for_user(db, &ident).optional())
+    .await?
+    .ok_or_else(|| tauri::Error::Io(no_such_user()))
+}
+
+/// Sets how room requests of the user identified by `bs58cert` are answered,
+/// `None` makes them follow the policy of their relation again
+///
+/// # Errors
+/// This function will return:</br>
+/// [ErrorKind::InvalidInput] if `bs58cert` is the local user</br>
+/// [ErrorKind::NotFound] if there is no entry for the user
+#[tauri::command(async)]
+pub async fn set_contact_policy(
+  bs58cert: String,
+  policy: Option<RoomPolicy>,
+) -> Result<(), tauri::Error> {
+  let ident = contact(bs58cert)?;
+  if !try_exec(move |db| policy::set_for_user(db, (&ident, policy))).await? {
+    return Err(tauri::Error::Io(no_such_user()));
+  }
+  Ok(())
+}
+
+/// Deletes the user identified by `bs58cert` together with the chat history with them,
+/// the deletion is reported using the "user-deleted" event
+///
+/// # Errors
+/// This function will return:</br>
+/// [ErrorKind::InvalidInput] if `bs58cert` is the local user</br>
+/// [ErrorKind::NotFound] if there is no entry for the user
+#[tauri::command(async)]
+pub async fn delete_user(window: Window, bs58cert: String) -> Result<(), tauri::Error> {
+  let ident = contact(bs58cert)?;
+  let target = ident.clone();
+  if !try_exec(move |db| user::delete(db, &target)).await? {
+    return Err(tauri::Error::Io(no_such_user()));
+  }
+
+  if let Err(err) = window.emit("user-deleted", &ident.bs58) {
+    log::error!("Failed to emit event: '{}'", err);
+  }
+  Ok(())
+}
+
+/// Applies `change` to the entry of the user identified by `bs58cert`
+/// and reports the changed entry using the "user-updated" event
+async fn change_contact<F>(window: &Window, bs58cert: String, change: F) -> Result<(), tauri::Error>
+where
+  F: FnOnce(&mut Connection, &UserIdentifier) -> Result<bool, rusqlite::Error> + Send + 'static,
+{
+  let ident = contact(bs58cert)?;
+  let target = ident.clone();
+  let info = try_exec(move |db| {
+    if change(db, &target)? {
+      try_get(db, &target).map(Some)
+    } else {
+      Ok(None)
     }
+  })
+  .await?
+  .ok_or_else(no_such_user)?;
+
+  let ident_info = IdentifiedUserInfo {
+    identifier: ident,
+    info,
+  };
+  // open chats show the name using the same event as username changes of the peer
+  let event = format!("usr_name_{}", ident_info.identifier.bs58);
+  if let Err(err) = window.emit(&event, ident_info.info.display_name()) {
+    log::error!("Failed to emit event: '{}'", err);
   }
+  if let Err(err) = window.emit("user-updated", &ident_info) {
+    log::error!("Failed to emit event: '{}'", err);
+  }
+  Ok(())
+}
+
+/// Returns the identifier for `bs58cert` if it does not belong to the local user
+fn contact(bs58cert: String) -> Result<UserIdentifier<'static>, io::Error> {
+  let local = config::IDI.read().unwrap();
+  if local
+    .as_ref()
+    .map_or(false, |local| *local.identifier.bs58 == bs58cert)
+  {
+    return Err(io::Error::new(
+      ErrorKind::InvalidInput,
+      "the local user is no contact",
+    ));
+  }
+
+  Ok(UserIdentifier {
+    bs58: Cow::Owned(bs58cert),
+  })
+}
+
+fn no_such_user() -> io::Error {
+  io::Error::new(ErrorKind::NotFound, "there is no entry for the user")
 }
 
 #[tauri::command]
 pub fn generate_user_certificate() {
-  cert_gen::generate_cert(&config::PEM.filepath).unwrap()
+  cert_gen::generate_cert(&config::PEM.filepath).unwrap();
+  // the new identity is stored without passphrase
+  config::reload();
+  *identity::SEALING.write().unwrap() = None;
+}
+
+/// Returns whether there is an identity and if it has to be unlocked before connecting
+#[tauri::command]
+pub fn get_identity_state() -> IdentityState {
+  identity::state()
+}
+
+/// Unlocks the identity protected by `passphrase`
+///
+/// # Errors
+/// See [identity::unlock]
+#[tauri::command]
+pub fn unlock_identity(passphrase: String) -> Result<(), tauri::Error> {
+  identity::unlock(&passphrase).map_err(tauri::Error::Io)
+}
+
+/// Protects the identity that has no passphrase yet using `passphrase`
+///
+/// # Errors
+/// See [identity::protect]
+#[tauri::command]
+pub fn set_passphrase(passphrase: String) -> Result<(), tauri::Error> {
+  identity::protect(None, Some(&passphrase)).map_err(tauri::Error::Io)
+}
+
+/// Replaces the passphrase `current` of the identity by `passphrase`
+///
+/// # Errors
+/// See [identity::protect]
+#[tauri::command]
+pub fn change_passphrase(current: String, passphrase: String) -> Result<(), tauri::Error> {
+  identity::protect(Some(&current), Some(&passphrase)).map_err(tauri::Error::Io)
+}
+
+/// Stores the identity protected by `current` without passphrase
+///
+/// # Errors
+/// See [identity::protect]
+#[tauri::command]
+pub fn remove_passphrase(current: String) -> Result<(), tauri::Error> {
+  identity::protect(Some(&current), None).map_err(tauri::Error::Io)
+}
+
+/// Stores the identity with every known user in a password protected bundle at `path`,
+/// the chat history is included if `history` is `true`
+///
+/// # Errors
+/// See [bundle::export]
+#[tauri::command]
+pub fn export_identity(path: PathBuf, password: String, history: bool) -> Result<(), tauri::Error> {
+  bundle::export(&path, &password, history).map_err(tauri::Error::Io)
+}
+
+/// Replaces the identity by the one in the bundle at `path` and adds its users and history,
+/// a different existing identity is only replaced if `replace` is `true`
+///
+/// Returns the bs58 encoded certificate of the imported identity and whether it is protected,
+/// an identity stored without passphrase can be protected using [set_passphrase]
+///
+/// # Errors
+/// See [bundle::import]
+#[tauri::command]
+pub fn import_identity(
+  path: PathBuf,
+  password: String,
+  replace: bool,
+) -> Result<Imported, tauri::Error> {
+  bundle::import(&path, &password, replace).map_err(tauri::Error::Io)
+}
+
+/// Returns all known rhizome server profiles and the name of the active one
+#[tauri::command]
+pub fn get_servers() -> ServerProfiles {
+  SERVERS.read().unwrap().clone()
+}
+
+/// Adds or replaces the server profile with the name of `profile`
+#[tauri::command]
+pub fn save_server(profile: ServerProfile) -> Result<(), tauri::Error> {
+  update_servers(|servers| servers.upsert(profile))
+}
+
+/// Removes the server profile called `name`
+#[tauri::command]
+pub fn remove_server(name: String) -> Result<bool, tauri::Error> {
+  update_servers(|servers| servers.remove(&name))
+}
+
+/// Selects the server profile used by the next call to `connect`
+#[tauri::command]
+pub fn select_server(name: String) -> Result<(), tauri::Error> {
+  update_servers(|servers| servers.select(&name))
+}
+
+/// Applies `change` to a copy of [SERVERS] and only keeps it if it was stored successfully
+fn update_servers<T, F>(change: F) -> Result<T, tauri::Error>
+where
+  F: FnOnce(&mut ServerProfiles) -> std::io::Result<T>,
+{
+  let mut lock = SERVERS.write().unwrap();
+  let mut next = lock.clone();
+  let result = change(&mut next)?;
+  servers::save(&next)?;
+  *lock = next;
+  Ok(result)
+}
+
+/// Returns the preferences of the local user
+#[tauri::command]
+pub fn get_settings() -> Settings {
+  SETTINGS.read().unwrap().clone()
+}
+
+/// Decides whether peers are told when their messages were seen
+#[tauri::command]
+pub fn set_read_receipts(enabled: bool) -> Result<(), tauri::Error> {
+  let mut lock = SETTINGS.write().unwrap();
+  let next = Settings {
+    read_receipts: enabled,
+  };
+  settings::save(&next)?;
+  *lock = next;
+  Ok(())
 }
@@ -26,6 +26,14 @@ impl<'a> UserIdentifier<'a> {
       bs58: Cow::Borrowed(&self.bs58),
     }
   }
+
+  /// Creates a new UserIdentifier that uses a [Cow::Owned] copy of
+  /// self, for moving it to another thread
+  pub fn to_static(&self) -> UserIdentifier<'static> {
+    UserIdentifier {
+      bs58: Cow::Owned(self.bs58.to_string()),
+    }
+  }
 }
 
 impl<'a> From<&User> for UserIdentifier<'a> {
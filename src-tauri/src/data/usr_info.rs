@@ -4,6 +4,16 @@ use serde::{Deserialize, Serialize};
 pub struct UserInfo {
   pub username: String,
   pub relation: UserRelation,
+  /** Name given to the user locally, shown instead of the username they chose */
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub nickname: Option<String>,
+}
+
+impl UserInfo {
+  /// Returns the nickname if there is one, the username otherwise
+  pub fn display_name(&self) -> &str {
+    self.nickname.as_deref().unwrap_or(&self.username)
+  }
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug)]
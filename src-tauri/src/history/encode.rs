@@ -1,3 +1,175 @@
-pub fn encode_hist() {
-  // Encode messages from JSON to Bytes
+use std::io::{self, ErrorKind};
+
+use super::msg::Msg;
+
+/// Version of the binary history format written by [encode_hist]
+pub const HIST_VERSION: u8 = 1;
+
+/// Size of the header in front of the postcard payload:
+/// 1 byte version followed by a 4 byte little endian payload length
+const HEADER_SIZE: usize = 5;
+
+/// Encodes messages into a compact versioned blob
+///
+/// # Format
+/// ```text
+/// [ version: u8 ][ payload length: u32 LE ][ postcard payload ]
+/// ```
+///
+/// # Errors
+/// This function will return:</br>
+/// [ErrorKind::InvalidData] if the messages cannot be serialized
+/// or the payload exceeds [u32::MAX] bytes
+pub fn encode_hist(msgs: &[Msg]) -> io::Result<Vec<u8>> {
+  let payload = postcard::to_stdvec(msgs).map_err(|err| {
+    log::error!("Error serializing history: '{}'", err);
+    io::Error::new(ErrorKind::InvalidData, "cannot serialize history")
+  })?;
+  let len = u32::try_from(payload.len())
+    .map_err(|_| io::Error::new(ErrorKind::InvalidData, "history too large"))?;
+
+  let mut blob = Vec::with_capacity(HEADER_SIZE + payload.len());
+  blob.push(HIST_VERSION);
+  blob.extend_from_slice(&len.to_le_bytes());
+  blob.extend_from_slice(&payload);
+
+  Ok(blob)
+}
+
+/// Decodes a blob created by [encode_hist] back into messages
+///
+/// # Errors
+/// This function will return:</br>
+/// [ErrorKind::Unsupported] if the blob was written with an unknown version</br>
+/// [ErrorKind::UnexpectedEof] if the blob is truncated</br>
+/// [ErrorKind::InvalidData] if the payload is malformed or followed by trailing bytes
+pub fn decode_hist(blob: &[u8]) -> io::Result<Vec<Msg>> {
+  if blob.len() < HEADER_SIZE {
+    return Err(io::Error::new(
+      ErrorKind::UnexpectedEof,
+      "history header truncated",
+    ));
+  }
+
+  let version = blob[0];
+  if version != HIST_VERSION {
+    return Err(io::Error::new(
+      ErrorKind::Unsupported,
+      format!("unknown history version: {}", version),
+    ));
+  }
+
+  let mut len = [0u8; 4];
+  len.copy_from_slice(&blob[1..HEADER_SIZE]);
+  let len = u32::from_le_bytes(len) as usize;
+
+  let payload = &blob[HEADER_SIZE..];
+  if payload.len() < len {
+    return Err(io::Error::new(
+      ErrorKind::UnexpectedEof,
+      "history payload truncated",
+    ));
+  }
+  if payload.len() > len {
+    return Err(io::Error::new(
+      ErrorKind::InvalidData,
+      "trailing bytes after history payload",
+    ));
+  }
+
+  postcard::from_bytes(payload).map_err(|err| {
+    log::error!("Error deserializing history: '{}'", err);
+    io::Error::new(ErrorKind::InvalidData, "malformed history payload")
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::ErrorKind;
+
+  use super::*;
+  use crate::history::msg::{MsgId, Text, LOCAL_SENDER, PEER_SENDER};
+
+  fn sample_msgs() -> Vec<Msg> {
+    let mut all = vec![];
+    for i in 0..10 {
+      let time = 1_000_000 + i;
+      all.push(Msg::Text(Text {
+        id: MsgId::new(time),
+        sender: if i % 2 == 0 {
+          LOCAL_SENDER
+        } else {
+          PEER_SENDER
+        },
+        content: format!("message number {}", i),
+        time,
+        remojis: Vec::new(),
+      }));
+    }
+    all
+  }
+
+  /// Tests if decoding an encoded history returns the original messages
+  #[test]
+  fn round_trip() {
+    let msgs = sample_msgs();
+    let blob = encode_hist(&msgs).expect("error encoding history");
+    let result = decode_hist(&blob).expect("error decoding history");
+
+    assert_eq!(
+      result, msgs,
+      "\ndecode_hist returned 'left' but 'right' was encoded"
+    );
+  }
+
+  /// Tests if an empty history survives the round trip
+  #[test]
+  fn round_trip_empty() {
+    let blob = encode_hist(&[]).expect("error encoding history");
+    let result = decode_hist(&blob).expect("error decoding history");
+
+    assert!(result.is_empty(), "\ndecode_hist returned '{:?}'", result);
+  }
+
+  /// Tests if every truncation of a valid blob is rejected
+  #[test]
+  fn reject_truncated() {
+    let blob = encode_hist(&sample_msgs()).expect("error encoding history");
+
+    for len in 0..blob.len() {
+      match decode_hist(&blob[..len]) {
+        Err(err) => assert_eq!(
+          err.kind(),
+          ErrorKind::UnexpectedEof,
+          "\ndecode_hist of {} bytes failed with the wrong kind",
+          len
+        ),
+        Ok(msgs) => panic!("decode_hist of {} bytes returned '{:?}'", len, msgs),
+      }
+    }
+  }
+
+  /// Tests if a blob with an unknown version is rejected
+  #[test]
+  fn reject_unknown_version() {
+    let mut blob = encode_hist(&sample_msgs()).expect("error encoding history");
+    blob[0] = HIST_VERSION + 1;
+
+    match decode_hist(&blob) {
+      Err(err) => assert_eq!(err.kind(), ErrorKind::Unsupported),
+      Ok(msgs) => panic!("decode_hist returned '{:?}' for an unknown version", msgs),
+    }
+  }
+
+  /// Tests if trailing bytes after the payload are rejected
+  #[test]
+  fn reject_trailing() {
+    let mut blob = encode_hist(&sample_msgs()).expect("error encoding history");
+    blob.push(0);
+
+    match decode_hist(&blob) {
+      Err(err) => assert_eq!(err.kind(), ErrorKind::InvalidData),
+      Ok(msgs) => panic!("decode_hist returned '{:?}' despite trailing bytes", msgs),
+    }
+  }
 }
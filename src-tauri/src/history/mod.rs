@@ -1,2 +1,2 @@
-mod encode;
-mod msg;
+pub mod encode;
+pub mod msg;
@@ -1,6 +1,17 @@
-use serde::{Deserialize, Serialize};
+use std::{
+  fmt,
+  str::FromStr,
+  time::{SystemTime, UNIX_EPOCH},
+};
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+/// [Text::sender] of messages written by the local user
+pub const LOCAL_SENDER: u8 = 0;
+/// [Text::sender] of messages written by the peer of a chat
+pub const PEER_SENDER: u8 = 1;
+
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Debug)]
 pub enum Msg {
   /// Normal text message
   Text(Text),
@@ -9,7 +20,7 @@ pub enum Msg {
   // Image, Embeds, Invites, etc...
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Debug)]
 /// # Text
 /// The default chat message.
 /// These contain text and a sender.
@@ -19,18 +30,20 @@ pub enum Msg {
 /// @sender : text message content
 /// ```
 pub struct Text {
+  /** Unique ID of this message */
+  pub id: MsgId,
   /** ID of the user who send this message */
-  sender: u8,
+  pub sender: u8,
   /** Content of the message */
-  content: String,
-  /** Time at which the message was send */
-  // time: u64, // This should be a DateTime<UTC>
+  pub content: String,
+  /** Time at which the message was send (UTC unix time in milliseconds) */
+  pub time: u64,
 
   /** Reaction emojis */
-  remojis: Vec<Remoji>,
+  pub remojis: Vec<Remoji>,
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Debug)]
 /// # Inline Reactions
 /// A reaction to another message in the same chat.
 /// These reactions are inserted at the bottom of the chat.
@@ -42,20 +55,22 @@ pub struct Text {
 /// @sender : text message content
 /// ```
 pub struct InlineReaction {
+  /** Unique ID of this message */
+  pub id: MsgId,
   /** ID of the user who send this message */
-  sender: u8,
+  pub sender: u8,
   /** Content of the message */
-  content: String,
-  /** Time at which the message was send */
-  // time: u64, // This should be a DateTime<UTC>
+  pub content: String,
+  /** Time at which the message was send (UTC unix time in milliseconds) */
+  pub time: u64,
 
   /** Reaction emojis */
-  remojis: Vec<Remoji>,
-  /** The message this reaction is targeting */
-  target: Box<Msg>,
+  pub remojis: Vec<Remoji>,
+  /** ID of the message this reaction is targeting */
+  pub target: MsgId,
 }
 
-#[derive(Clone, Copy, Serialize, Deserialize, Debug)]
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Debug)]
 /// # Reaction Emojis
 /// All messages can be reacted too using emojis.
 ///
@@ -65,6 +80,167 @@ pub struct InlineReaction {
 ///            😃 4  😵 6
 /// ```
 pub struct Remoji {
-  charCode: u8,
-  quantity: u16,
+  /** Emoji sequence (may consist of multiple code points, e.g. 👍🏽) */
+  pub emoji: String,
+  /** Amount of users that reacted with this emoji */
+  pub quantity: u16,
+}
+
+/// # Message State
+/// Where a message written by the local user is on its way to the peer.
+/// States only ever advance, in the order they are declared in.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageState {
+  /// Waiting in the outbox for a room with the peer
+  Queued,
+  /// Written to the P2P tunnel
+  Sent,
+  /// The peer confirmed that it stored the message
+  Delivered,
+  /// The peer confirmed that its user has seen the message
+  Read,
+}
+
+/// Longest emoji sequence (in bytes) accepted as [Remoji::emoji]
+pub const MAX_REMOJI_LEN: usize = 64;
+
+impl Remoji {
+  /// Checks if `emoji` is acceptable as a reaction:
+  /// not empty, at most [MAX_REMOJI_LEN] bytes and free of whitespace and control characters
+  pub fn is_valid(emoji: &str) -> bool {
+    !emoji.is_empty()
+      && emoji.len() <= MAX_REMOJI_LEN
+      && !emoji.chars().any(|c| c.is_whitespace() || c.is_control())
+  }
+}
+
+/// # Message ID
+/// Unique and sortable identifier of a message.
+///
+/// The upper 48 bits hold the UTC unix time in milliseconds at which the message was created,
+/// the lower 80 bits are random. Ordering IDs therefore orders messages by creation time.
+///
+/// Human readable formats (JSON, SQLite) represent the ID as 32 lowercase hex digits,
+/// binary formats (postcard) as plain [u128].
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct MsgId(pub u128);
+
+const RANDOM_BITS: u32 = 80;
+
+impl MsgId {
+  /// Creates a new random [MsgId] for a message created at `time` (unix time in milliseconds)
+  pub fn new(time: u64) -> MsgId {
+    let random = rand::random::<u128>() & ((1 << RANDOM_BITS) - 1);
+    MsgId(((time as u128) << RANDOM_BITS) | random)
+  }
+
+  /// UTC unix time in milliseconds at which the message was created
+  pub fn time(&self) -> u64 {
+    (self.0 >> RANDOM_BITS) as u64
+  }
+}
+
+impl fmt::Display for MsgId {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{:032x}", self.0)
+  }
+}
+
+impl FromStr for MsgId {
+  type Err = std::num::ParseIntError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    u128::from_str_radix(s, 16).map(MsgId)
+  }
+}
+
+impl Serialize for MsgId {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    if serializer.is_human_readable() {
+      serializer.collect_str(self)
+    } else {
+      serializer.serialize_u128(self.0)
+    }
+  }
+}
+
+impl<'de> Deserialize<'de> for MsgId {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    if deserializer.is_human_readable() {
+      let s = String::deserialize(deserializer)?;
+      s.parse().map_err(de::Error::custom)
+    } else {
+      u128::deserialize(deserializer).map(MsgId)
+    }
+  }
+}
+
+/// Current UTC unix time in milliseconds
+pub fn unix_millis() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|dur| dur.as_millis() as u64)
+    .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Tests if multi code point emojis are accepted and text is rejected
+  #[test]
+  fn remoji_validation() {
+    assert!(Remoji::is_valid("😃"));
+    assert!(Remoji::is_valid("👍🏽"));
+    assert!(Remoji::is_valid("👩‍👩‍👧"));
+    assert!(Remoji::is_valid("🏳️‍🌈"));
+    assert!(!Remoji::is_valid(""));
+    assert!(!Remoji::is_valid("two words"));
+    assert!(!Remoji::is_valid("\u{7}"));
+    assert!(!Remoji::is_valid(&"😃".repeat(MAX_REMOJI_LEN)));
+  }
+
+  /// Tests if the creation time can be recovered from a [MsgId]
+  #[test]
+  fn id_time() {
+    let time = unix_millis();
+    let id = MsgId::new(time);
+
+    assert_eq!(id.time(), time);
+  }
+
+  /// Tests if ids of later messages sort after ids of earlier messages
+  #[test]
+  fn id_order() {
+    let time = unix_millis();
+    let earlier = MsgId::new(time);
+    let later = MsgId::new(time + 1);
+
+    assert!(
+      earlier < later,
+      "\n'{}' should sort before '{}'",
+      earlier,
+      later
+    );
+    assert!(
+      earlier.to_string() < later.to_string(),
+      "\n'{}' should sort before '{}' as string",
+      earlier,
+      later
+    );
+  }
+
+  /// Tests if a [MsgId] survives the round trip through both human readable and binary formats
+  #[test]
+  fn id_serde() {
+    let id = MsgId::new(unix_millis());
+
+    let json = serde_json::to_string(&id).unwrap();
+    assert_eq!(json, format!("\"{}\"", id));
+    assert_eq!(serde_json::from_str::<MsgId>(&json).unwrap(), id);
+
+    let bytes = postcard::to_stdvec(&id).unwrap();
+    assert_eq!(postcard::from_bytes::<MsgId>(&bytes).unwrap(), id);
+  }
 }
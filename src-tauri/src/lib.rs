@@ -0,0 +1,13 @@
+//! Core of the emberry client, shared by the Tauri app and the command line client
+
+#[macro_use]
+extern crate dotenv_codegen;
+
+pub mod data;
+pub mod history;
+pub mod network;
+
+use std::sync::atomic::AtomicBool;
+
+/// `true` while the app window is focused, used to decide when to send desktop notifications
+pub static FOCUS: AtomicBool = AtomicBool::new(false);
@@ -3,49 +3,92 @@
   windows_subsystem = "windows"
 )]
 
-#[macro_use]
-extern crate dotenv_codegen;
-
-mod data;
 mod embed;
-mod history;
-mod network;
-
-use std::sync::atomic::AtomicBool;
 
-use data::tauri::*;
 use embed::embed;
+use emberry_rs::data::config;
+use emberry_rs::data::tauri::*;
+use emberry_rs::network::blocks::{block_user, get_blocks, unblock_user};
+use emberry_rs::network::ctrl_chnl::{
+  connect, disconnect_rhizome, requests::*, responses::*, State,
+};
+use emberry_rs::network::group::*;
+use emberry_rs::network::outbox::{get_outbox, queue_message};
+use emberry_rs::network::rotation::rotate_identity;
+use emberry_rs::network::{answer_file, chat_exists, close_room, send_file, Networking};
+use emberry_rs::FOCUS;
 use log::trace;
-use network::ctrl_chnl::{connect, requests::*, responses::*, State};
-use network::{chat_exists, Networking};
+use once_cell::sync::Lazy;
 use std::sync::atomic::Ordering;
 use tokio::sync::RwLock;
 
-pub static FOCUS: AtomicBool = AtomicBool::new(false);
-
 fn main() {
   env_logger::init();
 
   trace!(concat!("emberry-rs v", env!("CARGO_PKG_VERSION")));
+  // loads the local user from the database before any async command can block on it
+  Lazy::force(&config::IDI);
   tauri::Builder::default()
     // Application State
     .manage(Networking {
       chats: Default::default(),
       pending: Default::default(),
+      groups: Default::default(),
+      reconnects: Default::default(),
     })
     .manage(RwLock::<Option<State>>::new(None))
     // Tauri Commands
     .invoke_handler(tauri::generate_handler![
       chat_exists,
+      close_room,
       connect,
+      disconnect_rhizome,
       request_room,
       accept_room,
       get_usr_info,
       get_usrs,
+      get_history,
+      get_message_states,
+      export_history,
+      import_history,
       update_username,
+      set_relation,
+      set_nickname,
+      delete_user,
+      get_blocks,
+      block_user,
+      unblock_user,
+      get_relation_policies,
+      set_relation_policy,
+      get_contact_policy,
+      set_contact_policy,
       get_local,
       embed,
       generate_user_certificate,
+      get_identity_state,
+      unlock_identity,
+      set_passphrase,
+      change_passphrase,
+      remove_passphrase,
+      export_identity,
+      import_identity,
+      rotate_identity,
+      get_servers,
+      save_server,
+      remove_server,
+      select_server,
+      create_group,
+      get_groups,
+      join_group,
+      invite_to_group,
+      leave_group,
+      send_group_message,
+      send_file,
+      answer_file,
+      queue_message,
+      get_outbox,
+      get_settings,
+      set_read_receipts,
     ])
     // TEMP / TODO : This will be obsolete once the `window.is_focused()` function is released from Tauri.
     .on_window_event(|event| {
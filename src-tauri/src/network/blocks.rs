@@ -0,0 +1,114 @@
+//! Users that may neither ask for a room nor open a P2P tunnel
+//!
+//! Room requests of blocked users are rejected without telling the local user
+//! and tunnels to them are refused before any hole punching happens.
+
+use std::borrow::Cow;
+use std::io::{self, ErrorKind};
+
+use smoke::User;
+use tauri::Window;
+
+use crate::data::sqlite::{block, try_exec};
+use crate::data::{config, UserIdentifier};
+
+use super::{emit_closed, Frontend, Networking};
+
+/// Returns whether `usr` is blocked, a database error counts as blocked
+pub async fn is_blocked(usr: &User) -> bool {
+  let ident = UserIdentifier::from(usr);
+  match try_exec(move |db| block::contains(db, &ident)).await {
+    Ok(blocked) => blocked,
+    Err(err) => {
+      log::warn!(
+        "Could not look up whether a user is blocked, refusing them: '{}'",
+        err
+      );
+      true
+    }
+  }
+}
+
+/// Returns the bs58 encoded certificates of all blocked users, first blocked first
+#[tauri::command(async)]
+pub async fn get_blocks() -> tauri::Result<Vec<String>> {
+  let blocks = try_exec(block::get_all).await?;
+  Ok(
+    blocks
+      .into_iter()
+      .map(|ident| ident.bs58.into_owned())
+      .collect(),
+  )
+}
+
+/// Blocks the user identified by `bs58cert`, closing every room with them
+/// and dropping their pending room request, the block is reported using the "user-blocked" event
+///
+/// # Errors
+/// This function will return:</br>
+/// [ErrorKind::InvalidData] if `bs58cert` is malformed</br>
+/// [ErrorKind::InvalidInput] if `bs58cert` is the local user
+#[tauri::command(async)]
+pub async fn block_user(
+  window: Window,
+  bs58cert: String,
+  net: tauri::State<'_, Networking>,
+) -> tauri::Result<()> {
+  let ident = UserIdentifier {
+    bs58: Cow::Owned(bs58cert),
+  };
+  let usr: User = (&ident).try_into()?;
+  if let Some(local) = config::IDI.read().unwrap().as_ref() {
+    if local.identifier == ident {
+      return Err(tauri::Error::Io(io::Error::new(
+        ErrorKind::InvalidInput,
+        "Cannot block yourself",
+      )));
+    }
+  }
+
+  let target = ident.clone();
+  try_exec(move |db| block::add(db, &target)).await?;
+  disconnect(&window, &net, &usr);
+
+  window.emit("user-blocked", &ident.bs58)?;
+  Ok(())
+}
+
+/// Unblocks the user identified by `bs58cert`, reported using the "user-unblocked" event
+///
+/// Returns `false` if the user was not blocked
+#[tauri::command(async)]
+pub async fn unblock_user(window: Window, bs58cert: String) -> tauri::Result<bool> {
+  let ident = UserIdentifier {
+    bs58: Cow::Owned(bs58cert),
+  };
+
+  let target = ident.clone();
+  let removed = try_exec(move |db| block::remove(db, &target)).await?;
+  if removed {
+    window.emit("user-unblocked", &ident.bs58)?;
+  }
+  Ok(removed)
+}
+
+/// Ends everything that connects the local user to `usr`
+fn disconnect(frontend: &dyn Frontend, net: &Networking, usr: &User) {
+  net.pending.lock().unwrap().remove(usr);
+
+  let rooms: Vec<_> = {
+    let chats = net.chats.lock().unwrap();
+    chats
+      .iter()
+      .filter(|(_, con)| con.peer == *usr)
+      .map(|(id, _)| id.clone())
+      .collect()
+  };
+  for id in rooms {
+    net.close(&id, frontend);
+  }
+
+  if let Some((id, reason)) = net.reconnects.give_up(usr) {
+    emit_closed(frontend, "room-closed", &id, reason);
+  }
+}
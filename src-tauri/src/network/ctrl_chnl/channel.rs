@@ -1,18 +1,21 @@
-use super::room_creation::try_holepunch;
+use super::{requests::send_request, room_creation::try_holepunch};
 use std::{
   io::{self, ErrorKind},
-  sync::atomic::Ordering,
+  sync::Arc,
 };
 
 use crate::{
   data::{
+    servers::ServerProfile,
     sqlite::{
-      exec, try_exec,
+      exec,
+      policy::{self, RoomPolicy},
+      try_exec,
       user::{try_get, upsert},
     },
     IdentifiedUserInfo, UserIdentifier, UserInfo,
   },
-  network::ctrl_chnl::state,
+  network::{blocks, ctrl_chnl::state, emit_closed},
 };
 
 pub use super::messages::EmberryMessage;
@@ -20,29 +23,48 @@ pub use super::state::RwOption;
 pub use super::state::{RhizomeConnection, State};
 use log::trace;
 use rustls::Certificate;
+use serde::Serialize;
 use serde_json::json;
 use smoke::messages::EmbMessage;
 use smoke::messages::RhizMessage::{self, *};
-use tauri::{api::notification::Notification, AppHandle, Window};
+use smoke::User;
 use tokio::{io::BufReader, net::TcpStream};
 use tokio::{select, sync::mpsc::Receiver};
 use tokio_rustls::client::TlsStream;
 
-use super::Networking;
+use super::{Frontend, Networking};
+
+#[derive(Serialize)]
+struct RoomRequestPayload<'a> {
+  #[serde(flatten)]
+  user: &'a IdentifiedUserInfo<'a>,
+  /** The user shares a group with the local user and most likely wants to link the group mesh */
+  group_member: bool,
+}
 
 pub struct ControlChannel<'a> {
-  pub window: &'a Window,
-  pub app: &'a AppHandle,
+  pub frontend: &'a Arc<dyn Frontend>,
   pub rx: Receiver<EmberryMessage>,
   pub tls: BufReader<TlsStream<TcpStream>>,
-  pub net: tauri::State<'a, Networking>,
-  pub rc: &'a tauri::State<'a, RhizomeConnection>,
+  pub net: &'a Networking,
+  pub rc: &'a RhizomeConnection,
   pub identity: Certificate,
+  pub server: &'a ServerProfile,
 }
 
 impl<'a> ControlChannel<'a> {
+  /// Handles the connection to rhizome until it is closed
+  ///
+  /// # Errors
+  /// Returns `Ok(())` only if the connection was closed locally using [EmberryMessage::Close],
+  /// any loss of the connection (including a rhizome shutdown) is returned as an error
   pub async fn spin(mut self) -> tauri::Result<()> {
     let mut buf = vec![];
+    let net = self.net;
+    let mut missing_links = net.groups.missing_links().await;
+    let mut lost_rooms = net.reconnects.requests().await;
+    // requests for rooms lost while there was no control channel never reached rhizome
+    net.reconnects.request_all();
     loop {
       select! {
         Some(msg) = self.rx.recv() => {
@@ -50,9 +72,91 @@ impl<'a> ControlChannel<'a> {
           match msg {
               EmberryMessage::Direct(msg) => msg.send_with(&mut self.tls).await?,
               EmberryMessage::Close() => return Ok(()),
+              EmberryMessage::Reconnect() => return Err(tauri::Error::Io(io::Error::new(
+                ErrorKind::ConnectionReset,
+                "Reconnecting to use a new identity",
+              ))),
           }
         }
-        msg = RhizMessage::recv_with(&mut self.tls, &mut buf) => self.handle_rhiz_msg(msg).await?
+        msg = RhizMessage::recv_with(&mut self.tls, &mut buf) => self.handle_rhiz_msg(msg).await?,
+        Some(usr) = missing_links.recv() => self.link(usr).await,
+        Some(usr) = lost_rooms.recv() => self.relink(usr).await,
+      }
+    }
+  }
+
+  /// Requests a P2P tunnel to the group member `usr` unless there is one already
+  ///
+  /// Only the member with the lower public key sends the request,
+  /// the other one answers it like any other room request, which is marked as coming from a group member.
+  async fn link(&self, usr: User) {
+    if self.net.link(&usr).is_some() || self.identity.0 >= usr.cert_data {
+      return;
+    }
+
+    let bs58 = UserIdentifier::from(&usr).bs58.into_owned();
+    if let Err(err) = send_request(&**self.frontend, bs58, self.net, self.rc).await {
+      log::warn!("Unable to request a tunnel to a group member: '{}'", err);
+    }
+  }
+
+  /// Returns `true` if `usr` asks for a room to continue a room that lost its tunnel
+  ///
+  /// A room that is still alive on this end is taken over once the new tunnel is established.
+  fn lost_room(&self, usr: &User) -> bool {
+    self.net.reconnects.is_lost(usr)
+  }
+
+  /// Requests a new room with `usr` after the tunnel to them was lost
+  ///
+  /// Both ends of a lost tunnel send this request, colliding requests are resolved like any other.
+  async fn relink(&self, usr: User) {
+    if !self.net.reconnects.is_lost(&usr) || self.net.pending.lock().unwrap().contains_key(&usr) {
+      return;
+    }
+
+    let bs58 = UserIdentifier::from(&usr).bs58.into_owned();
+    if let Err(err) = send_request(&**self.frontend, bs58, self.net, self.rc).await {
+      log::warn!("Unable to request a new tunnel for a lost room: '{}'", err);
+    }
+  }
+
+  /// Returns the user info entry of `usr`,
+  /// a new entry is stored and reported using the "new-user" event if there is none yet
+  async fn lookup(&self, usr: &User) -> Result<IdentifiedUserInfo<'static>, io::Error> {
+    let identifier = UserIdentifier::from(usr);
+    let ident = identifier.clone();
+    match exec(move |db| try_get(db, &ident)).await {
+      Ok(info) => Ok(IdentifiedUserInfo { identifier, info }),
+      Err(rusqlite::Error::QueryReturnedNoRows) => {
+        let ident_info = IdentifiedUserInfo {
+          info: UserInfo {
+            username: identifier.bs58.to_string(),
+            relation: crate::data::UserRelation::Stranger,
+            nickname: None,
+          },
+          identifier,
+        };
+        let new_user = ident_info.clone();
+        try_exec(move |db| upsert(db, (&new_user, |_| ()))).await?;
+        self.frontend.emit("new-user", &ident_info.info.username);
+        Ok(ident_info)
+      }
+      Err(err) => {
+        log::error!("SQLite access error : '{}'", err);
+        Err(io::Error::new(ErrorKind::Other, "SQLite error"))
+      }
+    }
+  }
+
+  /// Returns how room requests of `ident` are answered, a database error means asking the local user
+  async fn policy(&self, ident: &UserIdentifier<'static>) -> RoomPolicy {
+    let ident = ident.clone();
+    match exec(move |db| policy::resolve(db, &ident)).await {
+      Ok(policy) => policy,
+      Err(err) => {
+        log::warn!("Could not look up the room policy of a user: '{}'", err);
+        RoomPolicy::Ask
       }
     }
   }
@@ -60,106 +164,104 @@ impl<'a> ControlChannel<'a> {
   async fn handle_rhiz_msg(&mut self, msg: Result<RhizMessage, io::Error>) -> tauri::Result<()> {
     trace!("ctrl recv: {:?}", msg);
     match msg? {
-      Shutdown() => return Ok(()),
+      Shutdown() => {
+        return Err(tauri::Error::Io(io::Error::new(
+          ErrorKind::ConnectionAborted,
+          "Rhizome is shutting down",
+        )))
+      }
       HasRoute(usr) => {
         let pending = self.net.pending.lock().unwrap().contains_key(&usr);
-        self
-          .window
-          .emit(
-            "has-route",
-            json!({ "pending": pending, "usr": UserIdentifier::from(&usr).bs58, }),
-          )
-          .expect("Failed to emit HasRoute")
+        self.frontend.emit(
+          "has-route",
+          json!({ "pending": pending, "usr": UserIdentifier::from(&usr).bs58, }),
+        )
       }
       NoRoute(usr) => {
         // might want to remove the ".remove(&usr)" when trying to auto reconnect...
         let pending = self.net.pending.lock().unwrap().remove(&usr);
-        self
-          .window
-          .emit(
-            "no-route",
-            json!({ "pending": pending.is_some(), "usr": UserIdentifier::from(&usr).bs58, }),
-          )
-          .expect("Failed to emit NoRoute")
+        self.frontend.emit(
+          "no-route",
+          json!({ "pending": pending.is_some(), "usr": UserIdentifier::from(&usr).bs58, }),
+        );
+        // the peer went offline, its lost room cannot be continued
+        if let Some((id, reason)) = self.net.reconnects.give_up(&usr) {
+          emit_closed(&**self.frontend, "room-closed", &id, reason);
+        }
       }
       WantsRoom(usr) => {
         // only option here is None or RRState::RemoteUnaware
-        let none;
-        {
+        let collides = {
           let mut guard = self.net.pending.lock().unwrap();
-          none = guard.get(&usr).is_none();
-          if none {
-            let ident = UserIdentifier::from(&usr);
-            let info = exec(try_get, &ident);
-            let ident_info = match info {
-              Ok(info) => IdentifiedUserInfo {
-                identifier: ident,
-                info,
-              },
-              Err(rusqlite::Error::QueryReturnedNoRows) => {
-                let ident_info = IdentifiedUserInfo {
-                  info: UserInfo {
-                    username: ident.bs58.to_string(),
-                    relation: crate::data::UserRelation::Stranger,
-                  },
-                  identifier: ident.as_ref(),
-                };
-                let new_user_event = |ident_info: &IdentifiedUserInfo| {
-                  self
-                    .window
-                    .emit("new-user", &ident_info.info.username)
-                    .expect("Failed to emit new-user event")
-                };
-                try_exec(upsert, (&ident_info, new_user_event))?;
-
-                ident_info
-              }
-              Err(err) => {
-                log::error!("SQLite access error : '{}'", err);
-                return Err(tauri::Error::Io(io::Error::new(
-                  ErrorKind::Other,
-                  "SQLite error",
-                )));
-              }
-            };
-
-            self
-              .window
-              .emit("wants-room", &ident_info)
-              .expect("Failed to emit WantsRoom event");
-
-            /* Create a new notification for the message */
-            if !crate::FOCUS.load(Ordering::SeqCst) {
-              Notification::new(self.app.config().tauri.bundle.identifier.clone())
-                .title(format!(
-                  "{} wants to connect to you",
-                  ident_info.info.username
-                ))
-                .show()
-                .expect("Failed to send desktop notification");
-            }
-          } else {
+          let collides = guard.get(&usr).is_some();
+          if collides {
             // Here we get a WantsRoom while we already want a room with them (they were unaware when they made their request)
             // In this situation the user with the higher value as pub key rejects the request
             // the client with the lower value pub key auto accepts
             // this is done to remove the dublicate request
             guard.insert(usr.clone(), super::RRState::Agreement);
           }
-        }
-
-        if !none {
-          // this is the same case where guard.insert(Agreement) happens just outside scope because we want to drop guard before await
+          collides
+        };
+        if collides {
+          // the local user asked for this room, so neither blocks nor policies apply
           let priority = self.identity.0 < usr.cert_data;
           let msg = EmbMessage::Accept(priority);
           state::send(self.rc, msg).await?;
+          return Ok(());
+        }
+
+        // blocked users are turned down without bothering the local user
+        if blocks::is_blocked(&usr).await {
+          log::debug!("Rejecting room request of a blocked user");
+          state::send(self.rc, EmbMessage::Accept(false)).await?;
+          return Ok(());
+        }
+        // the policy is resolved without an entry, so strangers that are turned down leave none
+        let policy = self.policy(&UserIdentifier::from(&usr)).await;
+        if policy == RoomPolicy::Reject {
+          log::debug!("Rejecting room request as the policy forbids it");
+          state::send(self.rc, EmbMessage::Accept(false)).await?;
+          return Ok(());
+        }
+
+        let ident_info = self.lookup(&usr).await?;
+        // peers that continue a room whose tunnel was lost get a tunnel without asking the user
+        if policy == RoomPolicy::Accept || self.lost_room(&usr) {
+          log::debug!("Accepting room request without asking");
+          self
+            .net
+            .pending
+            .lock()
+            .unwrap()
+            .insert(usr.clone(), super::RRState::Agreement);
+          state::send(self.rc, EmbMessage::Accept(true)).await?;
+        } else {
+          let group_member = self.net.groups.is_member(&usr);
+          self.frontend.emit(
+            "wants-room",
+            RoomRequestPayload {
+              user: &ident_info,
+              group_member,
+            },
+          );
+
+          /* Create a new notification for the message */
+          let name = ident_info.info.display_name();
+          let title = if group_member {
+            format!("{} of your groups wants to connect to you", name)
+          } else {
+            format!("{} wants to connect to you", name)
+          };
+          self.frontend.notify(&title, None);
         }
       }
       AcceptedRoom(id, usr) => {
         let priority = self.identity.0 < usr.cert_data;
         if let Err(err) = try_holepunch(
-          self.window.clone(),
-          self.app,
-          self.net.clone(),
+          self.frontend,
+          self.net,
+          &self.server.server_address,
           id,
           &usr,
           priority,
@@ -167,9 +269,8 @@ impl<'a> ControlChannel<'a> {
         .await
         {
           self
-            .window
-            .emit("error", format!("Connecting to {usr:?} failed! ERROR: '{}'", err))
-            .expect("Failed to emit error event");
+            .frontend
+            .emit("error", format!("Connecting to {usr:?} failed! ERROR: '{}'", err));
         }
       }
       ServerError(err) => {
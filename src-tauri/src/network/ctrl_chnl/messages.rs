@@ -4,6 +4,8 @@ use smoke::messages::EmbMessage;
 #[derive(Serialize, Deserialize, Debug)]
 pub enum EmberryMessage {
   Close(),
+  /// Drops the connection to rhizome so it is reestablished using the current identity
+  Reconnect(),
   Direct(EmbMessage),
 }
 
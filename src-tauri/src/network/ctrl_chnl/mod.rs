@@ -1,4 +1,3 @@
-mod certs;
 mod channel;
 mod messages;
 pub mod requests;
@@ -8,51 +7,300 @@ mod state;
 
 use std::{
   io::{self, ErrorKind},
-  sync::Arc,
-  time::Instant,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+  },
+  time::{Duration, Instant},
 };
 
-use crate::data::config;
+use crate::data::{
+  config,
+  servers::{ServerProfile, SERVERS},
+};
 
 use self::channel::ControlChannel;
 pub use self::state::RwOption;
-use log::error;
+use log::{error, warn};
 pub use messages::EmberryMessage;
-use rustls::{ClientConfig, RootCertStore, ServerName};
+use once_cell::sync::Lazy;
+use rustls::{Certificate, ClientConfig, RootCertStore};
+use smoke::{messages::EmbMessage, User};
 pub use state::RhizomeConnection;
 pub use state::State;
 use tokio::{
   io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
   net::TcpStream,
-  sync::mpsc,
+  select,
+  sync::{mpsc, watch},
 };
-use tokio_rustls::TlsConnector;
+use tokio_rustls::{client::TlsStream, TlsConnector};
+
+use super::{Frontend, Networking, RRState};
+
+/// First delay before trying to reconnect to rhizome
+const BACKOFF_BASE: Duration = Duration::from_secs(1);
+/// Upper bound for the delay between two reconnection attempts
+const BACKOFF_MAX: Duration = Duration::from_secs(60);
 
-use super::{Networking, RRState};
+/// Set while a [run] call supervises the connection to rhizome,
+/// including the time it is waiting to reconnect
+static SUPERVISING: AtomicBool = AtomicBool::new(false);
 
+/// Set to `true` by [disconnect] to make a [run] call that waits to reconnect give up
+static STOP: Lazy<watch::Sender<bool>> = Lazy::new(|| watch::channel(false).0);
+
+#[derive(Clone, serde::Serialize)]
+struct ReconnectPayload {
+  attempt: u32,
+  /** Delay before this attempt in milliseconds */
+  delay: u64,
+}
+
+/// Tauri command wrapper around [run] using `window` as [Frontend]
+///
+/// # Errors
+/// See [run]
 #[tauri::command(async)]
 pub async fn connect(
   window: tauri::Window,
-  app_handle: tauri::AppHandle,
   net: tauri::State<'_, Networking>,
   rc: tauri::State<'_, RhizomeConnection>,
 ) -> tauri::Result<()> {
-  let start = Instant::now();
-  if rc.read().await.is_some() {
+  run(Arc::new(window), &net, &rc).await
+}
+
+/// Tauri command wrapper around [disconnect]
+#[tauri::command(async)]
+pub async fn disconnect_rhizome(rc: tauri::State<'_, RhizomeConnection>) -> tauri::Result<bool> {
+  Ok(disconnect(&rc).await)
+}
+
+/// Closes the connection to rhizome, making the [run] call that supervises it return `Ok(())`
+///
+/// A [run] call waiting to reconnect stops waiting.</br>
+/// Returns `false` if there is no [run] call
+pub async fn disconnect(rc: &RhizomeConnection) -> bool {
+  if !SUPERVISING.load(Ordering::SeqCst) {
+    return false;
+  }
+
+  STOP.send_replace(true);
+  if let Some(state) = rc.read().await.as_ref() {
+    // fails if the control channel ended already, then it is reconnecting and sees STOP
+    let _ = state.channel.send(EmberryMessage::Close()).await;
+  }
+  true
+}
+
+/// Connects to the rhizome of the active server profile
+/// and keeps the connection alive until it is closed by the client
+///
+/// If the connection is lost it is reestablished using exponential backoff with jitter.
+/// Every attempt is announced using the "rz-reconnecting" event,
+/// a successful one using the "rz-reconnected" event.
+///
+/// # Errors
+/// Errors from establishing the initial connection are returned immediately,
+/// later ones only if they can not be resolved by reconnecting (e.g. missing identity)
+pub async fn run(
+  frontend: Arc<dyn Frontend>,
+  net: &Networking,
+  rc: &RhizomeConnection,
+) -> tauri::Result<()> {
+  let server = SERVERS.read().unwrap().active();
+  run_on(frontend, net, rc, server).await
+}
+
+/// [run] using the rhizome of `server` instead of the one of the active server profile
+///
+/// # Errors
+/// See [run]
+pub(crate) async fn run_on(
+  frontend: Arc<dyn Frontend>,
+  net: &Networking,
+  rc: &RhizomeConnection,
+  server: ServerProfile,
+) -> tauri::Result<()> {
+  if rc.read().await.is_some()
+    || SUPERVISING
+      .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+      .is_err()
+  {
     return Err(tauri::Error::Io(io::Error::new(
       io::ErrorKind::Unsupported,
       "Already connected to the server",
     )));
   }
 
-  let server_cert = certs::craft();
-  let client_cert = config::PEM.parse();
-  let (client_cert, _) = match client_cert {
-    Ok(data) => data,
-    Err(err) => {
+  STOP.send_replace(false);
+  let res = supervise(&frontend, net, rc, server).await;
+  SUPERVISING.store(false, Ordering::SeqCst);
+  res
+}
+
+async fn supervise(
+  frontend: &Arc<dyn Frontend>,
+  net: &Networking,
+  rc: &RhizomeConnection,
+  server: ServerProfile,
+) -> tauri::Result<()> {
+  let start = Instant::now();
+  log::info!(
+    "Connecting to rhizome using server profile '{}'",
+    server.name
+  );
+  let (mut tls, mut identity) = establish(&server).await?;
+
+  loop {
+    // disconnect was called while the connection was being established
+    if *STOP.borrow() {
+      return Ok(());
+    }
+    let (tx, rx) = mpsc::channel::<EmberryMessage>(25);
+
+    let conn = State { channel: tx };
+    rc.write().await.replace(conn);
+    // only announce the connection once it can be used to send messages
+    frontend.emit("rz-con", start.elapsed().as_millis() as u64);
+
+    let chnl = ControlChannel {
+      frontend,
+      rx,
+      tls,
+      net,
+      rc,
+      identity,
+      server: &server,
+    };
+
+    let res = chnl.spin().await;
+
+    *rc.write().await = None;
+
+    frontend.emit("rz-dc", start.elapsed().as_millis() as u64);
+
+    match res {
+      Ok(()) => return Ok(()),
+      Err(err) => warn!("Lost connection to rhizome: '{}'", err),
+    }
+
+    match reconnect(&**frontend, &server).await? {
+      Some((next_tls, next_identity)) => {
+        tls = next_tls;
+        identity = next_identity;
+      }
+      None => return Ok(()),
+    }
+    resume_pending(net, rc).await;
+  }
+}
+
+/// Tries to reestablish the connection to rhizome until it succeeds
+///
+/// Returns `None` if [disconnect] was called in the meantime
+///
+/// # Errors
+/// Returns the first error that can not be resolved by trying again
+async fn reconnect(
+  frontend: &dyn Frontend,
+  server: &ServerProfile,
+) -> tauri::Result<Option<(BufReader<TlsStream<TcpStream>>, Certificate)>> {
+  let mut stop = STOP.subscribe();
+  let mut attempt = 0;
+  loop {
+    if *stop.borrow_and_update() {
+      log::info!("Stopped reconnecting to rhizome");
+      return Ok(None);
+    }
+
+    attempt += 1;
+    let delay = backoff(attempt);
+    frontend.emit(
+      "rz-reconnecting",
+      ReconnectPayload {
+        attempt,
+        delay: delay.as_millis() as u64,
+      },
+    );
+
+    let res = select! {
+      // the sender lives in a static, so this only returns once the value changed
+      _ = stop.changed() => continue,
+      res = async {
+        tokio::time::sleep(delay).await;
+        establish(server).await
+      } => res,
+    };
+
+    match res {
+      Ok(conn) => {
+        frontend.emit("rz-reconnected", attempt);
+        return Ok(Some(conn));
+      }
+      Err(tauri::Error::Io(err)) if err.kind() == io::ErrorKind::Unsupported => {
+        error!("Giving up reconnecting to rhizome: '{}'", err);
+        return Err(tauri::Error::Io(err));
+      }
+      Err(err) => warn!("Reconnect attempt {} failed: '{}'", attempt, err),
+    }
+  }
+}
+
+/// Rhizome forgets about all room requests when the connection drops,
+/// therefore every request that was still pending is sent again.
+///
+/// Accepted requests are downgraded to pending requests as both sides will send a new request
+/// which is then resolved like two colliding requests, without asking the user again.
+async fn resume_pending(net: &Networking, rc: &RhizomeConnection) {
+  let usrs: Vec<User> = {
+    let mut guard = net.pending.lock().unwrap();
+    for state in guard.values_mut() {
+      *state = RRState::Pending;
+    }
+    guard.keys().cloned().collect()
+  };
+
+  for usr in usrs {
+    if let Err(err) = state::send(rc, EmbMessage::Room(usr.clone())).await {
+      warn!("Unable to resume room request: '{}'", err);
+      net.pending.lock().unwrap().remove(&usr);
+    }
+  }
+}
+
+/// Delay before the reconnection `attempt` (starting at 1)
+///
+/// Doubles with every attempt starting at [BACKOFF_BASE] up to [BACKOFF_MAX].
+/// The result is randomized to lie within the upper half of that value
+/// to keep clients from reconnecting all at once after a rhizome restart.
+fn backoff(attempt: u32) -> Duration {
+  let exp = BACKOFF_BASE
+    .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+    .min(BACKOFF_MAX);
+  let half = exp / 2;
+  half + half.mul_f64(rand::random::<f64>())
+}
+
+/// Opens the TLS connection to the rhizome of `server`, checks its greeting and identifies the local user
+async fn establish(
+  server: &ServerProfile,
+) -> tauri::Result<(BufReader<TlsStream<TcpStream>>, Certificate)> {
+  // a broken profile will not fix itself, so it is reported like a missing identity
+  let unusable = |err: io::Error| tauri::Error::Io(io::Error::new(ErrorKind::Unsupported, err));
+  let server_cert = server.certificate().map_err(unusable)?;
+  // the key is needed for the tunnels, so a locked identity is as good as none
+  let client_cert = config::PEM_DATA
+    .read()
+    .unwrap()
+    .as_ref()
+    .map(|(cert, _)| cert.clone());
+  let client_cert = match client_cert {
+    Some(cert) => cert,
+    None => {
       return Err(tauri::Error::Io(io::Error::new(
         io::ErrorKind::Unsupported,
-        format!("Identity needed to connect with rhizome. Unable to parse Identity file: {err}"),
+        "Identity needed to connect with rhizome. It is missing, invalid or locked",
       )))
     }
   };
@@ -67,9 +315,9 @@ pub async fn connect(
     .with_root_certificates(root_store)
     .with_no_client_auth();
 
-  let server_name = get_server_name();
+  let server_name = server.server_name().map_err(unusable)?;
   let conn = TlsConnector::from(Arc::new(config));
-  let sock = TcpStream::connect(dotenv!("CONTROL_ADDRESS")).await?;
+  let sock = TcpStream::connect(&server.control_address).await?;
   let mut tls = BufReader::new(conn.connect(server_name, sock).await?);
 
   let mut plaintext = String::new();
@@ -81,9 +329,6 @@ pub async fn connect(
       "Server did greet with rhizome signature",
     )));
   }
-  window
-    .emit("rz-con", start.elapsed().as_millis() as u64)
-    .expect("Failed to emit event");
 
   let cobs_cert = match postcard::to_vec_cobs::<Vec<u8>, 1024>(&client_cert.0) {
     Ok(cobs) => cobs,
@@ -97,44 +342,30 @@ pub async fn connect(
   };
   tls.write_all(&cobs_cert).await?;
 
-  let (tx, rx) = mpsc::channel::<EmberryMessage>(25);
-
-  let conn = State { channel: tx };
-  rc.write().await.replace(conn);
-
-  let chnl = ControlChannel {
-    window: &window,
-    app: &app_handle,
-    rx,
-    tls,
-    net,
-    rc: &rc,
-    identity: client_cert,
-  };
-
-  let res = chnl.spin().await;
-
-  *rc.write().await = None;
-
-  window
-    .emit("rz-dc", start.elapsed().as_millis() as u64)
-    .expect("Failed to emit event");
-
-  res
-}
-
-#[inline]
-fn get_server_name() -> ServerName {
-  // unwrap is ok here as this is tested with tests on build time
-  dotenv!("SERVER_DOMAIN").try_into().unwrap()
+  Ok((tls, client_cert))
 }
 
 #[cfg(test)]
 mod tests {
-  use super::get_server_name;
+  use super::{backoff, BACKOFF_BASE, BACKOFF_MAX};
 
+  /// Tests if the backoff grows exponentially, stays within its jitter range and is capped
   #[test]
-  fn server_name_creation() {
-    get_server_name();
+  fn backoff_range() {
+    for attempt in 1..=100 {
+      let exp = BACKOFF_BASE
+        .saturating_mul(1 << (attempt - 1).min(16))
+        .min(BACKOFF_MAX);
+      let delay = backoff(attempt);
+      assert!(
+        delay >= exp / 2 && delay <= exp,
+        "\nattempt {} waited {:?} but should wait between {:?} and {:?}",
+        attempt,
+        delay,
+        exp / 2,
+        exp
+      );
+    }
+    assert!(backoff(100) >= BACKOFF_MAX / 2);
   }
 }
@@ -1,4 +1,5 @@
 use std::borrow::Cow;
+use std::io::{self, ErrorKind};
 
 use smoke::{messages::EmbMessage, User};
 use tauri::Window;
@@ -7,7 +8,7 @@ use crate::data::sqlite::user::{try_get, upsert};
 use crate::data::sqlite::{exec, try_exec};
 use crate::data::{config, IdentifiedUserInfo, UserIdentifier, UserInfo};
 use crate::network::ctrl_chnl::RhizomeConnection;
-use crate::network::Networking;
+use crate::network::{blocks, Frontend, Networking};
 
 use super::state;
 
@@ -18,13 +19,25 @@ pub async fn request_room(
   bs58cert: String,
   net: tauri::State<'_, Networking>,
   rc: tauri::State<'_, RhizomeConnection>,
+) -> tauri::Result<()> {
+  send_request(&window, bs58cert, &net, &rc).await
+}
+
+/// Asks rhizome for a room with the user identified by `bs58cert`
+///
+/// Unknown users are added to the database as strangers and announced using the "new-user" event
+pub async fn send_request(
+  frontend: &dyn Frontend,
+  bs58cert: String,
+  net: &Networking,
+  rc: &RhizomeConnection,
 ) -> tauri::Result<()> {
   let ident = UserIdentifier {
     bs58: Cow::Borrowed(&bs58cert),
   };
   let usr: User = (&ident).try_into()?;
 
-  if let Some((cert, _)) = config::PEM_DATA.as_ref() {
+  if let Some((cert, _)) = config::PEM_DATA.read().unwrap().as_ref() {
     if cert.0 == usr.cert_data {
       log::warn!("Cannot request a room with yourself");
       return Ok(());
@@ -34,6 +47,14 @@ pub async fn request_room(
     return Ok(());
   }
 
+  if blocks::is_blocked(&usr).await {
+    log::warn!("Cannot request a room with a blocked user");
+    return Err(tauri::Error::Io(io::Error::new(
+      ErrorKind::PermissionDenied,
+      "The user is blocked",
+    )));
+  }
+
   // try to add to pending list
   let msg = match net.pending.lock().unwrap().entry(usr.clone()) {
     std::collections::hash_map::Entry::Occupied(_) => {
@@ -42,31 +63,34 @@ pub async fn request_room(
     }
     std::collections::hash_map::Entry::Vacant(e) => {
       e.insert(crate::network::RRState::Pending);
-      EmbMessage::Room(usr)
+      EmbMessage::Room(usr.clone())
     }
   };
 
   // When we send a room request check if the user is in the database
   // if thats not the case add it and tell the frontend about it
   if let EmbMessage::Room(_) = msg {
-    let info = exec(try_get, &ident);
+    let lookup = ident.to_static();
+    let info = exec(move |db| try_get(db, &lookup)).await;
     if let Err(rusqlite::Error::QueryReturnedNoRows) = info {
       let ident_info = IdentifiedUserInfo {
         info: UserInfo {
           username: ident.bs58.to_string(),
           relation: crate::data::UserRelation::Stranger,
+          nickname: None,
         },
-        identifier: ident.as_ref(),
+        identifier: ident.to_static(),
       };
-      let new_user_event = |ident_info: &IdentifiedUserInfo| {
-        window
-          .emit("new-user", &ident_info.info.username)
-          .expect("Failed to emit new-user event")
-      };
-      try_exec(upsert, (&ident_info, new_user_event))?;
+      let new_user = ident_info.clone();
+      try_exec(move |db| upsert(db, (&new_user, |_| ()))).await?;
+      frontend.emit("new-user", &ident_info.info.username);
     };
   }
 
-  state::send(&rc, msg).await?;
+  // without a connection to rhizome the request would stay pending forever
+  if let Err(err) = state::send(rc, msg).await {
+    net.pending.lock().unwrap().remove(&usr);
+    return Err(err.into());
+  }
   Ok(())
 }
@@ -18,6 +18,16 @@ pub async fn accept_room(
   accepted: bool,
   net: tauri::State<'_, Networking>,
   rc: tauri::State<'_, RhizomeConnection>,
+) -> Result<()> {
+  send_answer(bs58cert, accepted, &net, &rc).await
+}
+
+/// Answers the room request of the user identified by `bs58cert`
+pub async fn send_answer(
+  bs58cert: String,
+  accepted: bool,
+  net: &Networking,
+  rc: &RhizomeConnection,
 ) -> Result<()> {
   {
     let ident = UserIdentifier {
@@ -40,6 +50,6 @@ pub async fn accept_room(
   }
 
   let msg = EmbMessage::Accept(accepted);
-  state::send(&rc, msg).await?;
+  state::send(rc, msg).await?;
   Ok(())
 }
@@ -1,12 +1,13 @@
 use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
 use rustls::Certificate;
 use smoke::messages::RoomId;
-use smoke::Signal;
 use smoke::User;
 
 use tokio::io::BufReader;
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{mpsc, mpsc::error::SendError, oneshot};
 
 use tokio_kcp::{KcpConfig, KcpStream};
 
@@ -14,10 +15,11 @@ use log::error;
 
 use crate::data::UserIdentifier;
 use crate::network::RRState;
-use crate::network::{Connection, Networking};
+use crate::network::{blocks, outbox};
+use crate::network::{emit_closed, Connection, Frontend, Networking};
 
 use super::super::holepunch::punch_hole;
-use super::super::p2p_tunl::{p2p_loop, tls_kcp};
+use super::super::p2p_tunl::{p2p_loop, packet::Packet, tls_kcp, CloseReason, Outgoing};
 
 /// Default kcp conf as from KcpConfig::default()
 /// default is not const and therefore needs to be inlined manually
@@ -36,6 +38,9 @@ const KCP_CONF: KcpConfig = KcpConfig {
   stream: false,
 };
 
+/// Source of [Connection::generation], counting every tunnel ever opened
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
 #[derive(Clone, serde::Serialize)]
 struct NewRoomPayload {
   room_id: String,
@@ -43,9 +48,9 @@ struct NewRoomPayload {
 }
 
 pub async fn try_holepunch(
-  window: tauri::Window,
-  app_handle: &tauri::AppHandle,
-  net_state: tauri::State<'_, Networking>,
+  frontend: &Arc<dyn Frontend>,
+  net_state: &Networking,
+  server_address: &str,
   room_id: Option<RoomId>,
   usr: &User,
   priority: bool,
@@ -53,7 +58,7 @@ pub async fn try_holepunch(
   if let Some(room_id) = room_id {
     if net_state.pending.lock().unwrap().remove(&usr).is_some() {
       // only hole punch if there is a connection pending
-      hole_punch(window, app_handle, net_state, room_id, usr, priority).await?;
+      hole_punch(frontend, net_state, server_address, room_id, usr, priority).await?;
     } else {
       // This is rather weak protection as a compromized rhizome server could still just send a different room id with a valid user
       // Room id procedure is subject to change in the future. (plan is to use cryptographic signatures to mitigated unwanted ip leak)
@@ -72,29 +77,38 @@ pub async fn try_holepunch(
         }
       }
     }
+    drop(guard);
+
+    // a rejected request for a lost room means the peer does not want to continue it
+    if let Some((id, reason)) = net_state.reconnects.give_up(usr) {
+      emit_closed(&**frontend, "room-closed", &id, reason);
+    }
   }
 
   Ok(())
 }
 
 async fn hole_punch(
-  window: tauri::Window,
-  app_handle: &tauri::AppHandle,
-  state: tauri::State<'_, Networking>,
+  frontend: &Arc<dyn Frontend>,
+  state: &Networking,
+  server_address: &str,
   room_id: RoomId,
   peer: &User,
   priority: bool,
 ) -> tauri::Result<()> {
-  /* Get the server ip from .env */
+  if blocks::is_blocked(peer).await {
+    return Err(tauri::Error::Io(Error::new(
+      ErrorKind::PermissionDenied,
+      "Refusing a P2P connection with a blocked user",
+    )));
+  }
 
   let identity = bs58::encode(&room_id.0).into_string();
 
-  window
-    .emit("punching", &identity)
-    .expect("Failed to emit punching event");
+  frontend.emit("punching", &identity);
 
   /* Holepunch using rhizome */
-  let socket = punch_hole(dotenv!("SERVER_ADDRESS"), &room_id.0).await?;
+  let socket = punch_hole(server_address, &room_id.0).await?;
   let addr = socket.peer_addr()?;
 
   let stream = KcpStream::connect_with_socket(&KCP_CONF, socket, addr)
@@ -119,57 +133,142 @@ async fn hole_punch(
   let mut stream = BufReader::new(stream);
 
   /* Setup the send event for the frontend */
-  let (sender, mut msg_rx) = mpsc::channel::<Signal>(100);
-  let send_handle = window.listen(format!("send_message_{}", identity), move |e| {
+  let (packets, mut msg_rx) = mpsc::channel::<Packet>(100);
+  let sender = packets.clone();
+  let queue_frontend = frontend.clone();
+  let queue_ident = UserIdentifier::from(peer);
+  let listener = move |payload: Option<&str>| {
+    let msg = match payload.map(serde_json::from_str::<Outgoing>) {
+      Some(Ok(msg)) => msg,
+      Some(Err(err)) => return log::warn!("Invalid Json in send_message_<id> event: '{}'", err),
+      None => return log::warn!("Missing payload in send_message_<id> event"),
+    };
     let sender = sender.clone();
-    let msg = serde_json::from_str::<Signal>(
-      e.payload()
-        .expect("Invalid payload in send_message_<id> event"),
-    )
-    .expect("Invalid Json inside of payload from send_message_<id> event");
-    tokio::spawn(async move { sender.send(msg).await });
-  });
+    let frontend = queue_frontend.clone();
+    let peer = queue_ident.clone();
+    tokio::spawn(async move {
+      // the tunnel died, keep chat messages for the next room with this peer
+      if let Err(SendError(Packet::Chat(chat))) = sender.send(Packet::from(msg)).await {
+        if let Err(err) = outbox::queue(&*frontend, &peer, &chat).await {
+          log::error!("failed to queue message for '{}': '{}'", peer.bs58, err);
+        }
+      }
+    });
+  };
 
-  /* Setup the receive loop */
+  /* Pick the room of the tunnel and register it while holding the lock,
+  so the loop of an earlier tunnel never sees the room without a tunnel */
   let (recv_handle, mut rx) = oneshot::channel::<()>();
-  let emit_identity = identity.clone();
-  let spawn_window = window.clone();
-  let app_handle = app_handle.clone();
+  let generation = GENERATION.fetch_add(1, Ordering::Relaxed);
+  let (room_id, identity, event) = {
+    let mut chats = state.chats.lock().unwrap();
+    let live = chats
+      .iter()
+      .find(|(_, con)| con.peer == *peer)
+      .map(|(id, _)| id.clone());
+    let (room_id, event) = match live {
+      // the peer only asks for a new tunnel once its end of the live one is gone
+      Some(id) => {
+        if let Some(old) = chats.remove(&id) {
+          (old.send_handle)();
+          // fails if the loop already ended
+          let _ = old.recv_handle.send(());
+        }
+        (id, "room-reconnected")
+      }
+      /* A new tunnel to a peer whose room was lost continues that room */
+      None => match state.reconnects.resume(peer) {
+        Some(lost) => (lost, "room-reconnected"),
+        None => (room_id, "new-room"),
+      },
+    };
+
+    let identity = bs58::encode(&room_id.0).into_string();
+    let send_handle = frontend.listen(format!("send_message_{}", identity), Box::new(listener));
+    let con = Connection {
+      peer: peer.clone(),
+      packets: packets.clone(),
+      recv_handle,
+      send_handle,
+      generation,
+    };
+    chats.insert(room_id.clone(), con);
+    (room_id, identity, event)
+  };
+
+  frontend.emit(
+    event,
+    NewRoomPayload {
+      room_id: identity.clone(),
+      peer_id: UserIdentifier::from(peer).bs58.into_owned(),
+    },
+  );
+
+  /* Setup the receive loop */
+  let emit_identity = identity;
+  let spawn_frontend = frontend.clone();
+  let groups = state.groups.clone();
+  let chats = state.chats.clone();
+  let reconnects = state.reconnects.clone();
   let ident = UserIdentifier::from(peer);
   tokio::spawn(async move {
-    if let Err(err) = p2p_loop(
+    let reason = match p2p_loop(
       &emit_identity,
       ident,
-      &spawn_window,
-      &app_handle,
+      &*spawn_frontend,
+      &groups,
       &mut stream,
       &mut rx,
       &mut msg_rx,
     )
     .await
     {
-      log::error!(
-        "receive loop for identity '{}' crashed with '{}'",
-        emit_identity,
-        err
-      );
+      Ok(reason) => reason,
+      Err(err) => {
+        log::error!(
+          "receive loop for identity '{}' crashed with '{}'",
+          emit_identity,
+          err
+        );
+        CloseReason::Error {
+          message: err.to_string(),
+        }
+      }
+    };
+
+    let con = {
+      let mut chats = chats.lock().unwrap();
+      let replaced = matches!(chats.get(&room_id), Some(con) if con.generation != generation);
+      // a new tunnel took over the room, there is nothing left to clean up
+      if replaced {
+        return;
+      }
+      chats.remove(&room_id)
+    };
+    match con {
+      Some(con) if matches!(reason, CloseReason::Timeout | CloseReason::Error { .. }) => {
+        log::info!(
+          "lost the tunnel of '{}', requesting a new one",
+          emit_identity
+        );
+        emit_closed(
+          &*spawn_frontend,
+          "room-reconnecting",
+          &room_id,
+          reason.clone(),
+        );
+        reconnects.lose(con.peer, room_id, reason, con.send_handle);
+      }
+      Some(con) => {
+        (con.send_handle)();
+        emit_closed(&*spawn_frontend, "room-closed", &room_id, reason);
+      }
+      // closed locally, the room was cleaned up already
+      None => emit_closed(&*spawn_frontend, "room-closed", &room_id, reason),
     }
   });
 
-  let con = Connection {
-    recv_handle,
-    send_handle,
-  };
-  state.chats.lock().unwrap().insert(room_id.clone(), con);
-
-  window
-    .emit(
-      "new-room",
-      NewRoomPayload {
-        room_id: identity,
-        peer_id: UserIdentifier::from(peer).bs58.into_owned(),
-      },
-    )
-    .expect("Failed to emit WantsRoom event");
+  /* Deliver everything that was written while the peer was offline */
+  outbox::flush(&UserIdentifier::from(peer), &packets).await;
   Ok(())
 }
@@ -0,0 +1,239 @@
+use std::{
+  io::{self, ErrorKind},
+  sync::atomic::Ordering,
+};
+
+use serde::Serialize;
+use tauri::{api::notification::Notification, Manager, Window};
+
+/// Called with the payload of every event the frontend sends to a subscribed name
+pub type Listener = Box<dyn Fn(Option<&str>) + Send + 'static>;
+/// Ends a subscription created by [Frontend::listen] when called
+pub type Unlisten = Box<dyn FnOnce() + Send + 'static>;
+
+/// Everything the networking core needs from the application it runs in
+///
+/// The Tauri app uses its [Window], headless hosts (CLI, bots, tests)
+/// can provide their own implementation.
+pub trait Frontend: Send + Sync {
+  /// Sends `payload` to the frontend under the name `event`
+  ///
+  /// # Errors
+  /// This function will return:</br>
+  /// Any [io::Error] if the frontend cannot be reached
+  fn emit_json(&self, event: &str, payload: serde_json::Value) -> io::Result<()>;
+
+  /// Tells the user about something that happened while they were not looking
+  fn notify(&self, title: &str, body: Option<&str>);
+
+  /// Calls `listener` for every event the frontend sends under the name `event`
+  /// until the returned [Unlisten] is called
+  fn listen(&self, event: String, listener: Listener) -> Unlisten;
+}
+
+impl<'a> dyn Frontend + 'a {
+  /// Serializes `payload` and sends it to the frontend under the name `event`
+  ///
+  /// Failures are only logged as the networking core has no way of recovering from a missing frontend.
+  pub fn emit<S: Serialize>(&self, event: &str, payload: S) {
+    let res = serde_json::to_value(payload)
+      .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))
+      .and_then(|payload| self.emit_json(event, payload));
+    if let Err(err) = res {
+      log::error!("Failed to emit event '{}': '{}'", event, err);
+    }
+  }
+}
+
+impl Frontend for Window {
+  fn emit_json(&self, event: &str, payload: serde_json::Value) -> io::Result<()> {
+    Window::emit(self, event, payload).map_err(|err| io::Error::new(ErrorKind::Other, err))
+  }
+
+  fn notify(&self, title: &str, body: Option<&str>) {
+    if crate::FOCUS.load(Ordering::SeqCst) {
+      return;
+    }
+
+    let mut notification =
+      Notification::new(&self.app_handle().config().tauri.bundle.identifier).title(title);
+    if let Some(body) = body {
+      notification = notification.body(body);
+    }
+    if let Err(err) = notification.show() {
+      log::error!("Failed to send desktop notification: '{}'", err);
+    }
+  }
+
+  fn listen(&self, event: String, listener: Listener) -> Unlisten {
+    let id = Window::listen(self, event, move |e| listener(e.payload()));
+    let window = self.clone();
+    Box::new(move || window.unlisten(id))
+  }
+}
+
+#[cfg(test)]
+pub(crate) use recorder::Recorder;
+
+#[cfg(test)]
+mod recorder {
+  use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, Mutex},
+    time::Duration,
+  };
+
+  use serde_json::Value;
+  use tokio::{sync::Notify, time::Instant};
+
+  use super::{Frontend, Listener, Unlisten};
+
+  /// Frontend without a webview for tests, recording every emitted event and notification
+  #[derive(Default)]
+  pub(crate) struct Recorder {
+    events: Mutex<Vec<(String, Value)>>,
+    notifications: Mutex<Vec<String>>,
+    listeners: Arc<Mutex<HashMap<String, Listener>>>,
+    /** Wakes everyone waiting in [Recorder::wait_for] whenever an event is recorded */
+    emitted: Notify,
+  }
+
+  impl Recorder {
+    /// Longest time [Recorder::wait_for] waits for an event
+    pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Names of every recorded event, oldest first
+    pub fn names(&self) -> Vec<String> {
+      let events = self.events.lock().unwrap();
+      events.iter().map(|(name, _)| name.clone()).collect()
+    }
+
+    /// Payloads of every recorded event, oldest first
+    pub fn payloads(&self) -> Vec<Value> {
+      let events = self.events.lock().unwrap();
+      events.iter().map(|(_, payload)| payload.clone()).collect()
+    }
+
+    /// Titles of every notification, oldest first
+    pub fn notifications(&self) -> Vec<String> {
+      self.notifications.lock().unwrap().clone()
+    }
+
+    /// Hands `payload` to the listener of `event` as if it was sent by a webview
+    ///
+    /// Returns `false` if nobody listens to `event`
+    pub fn dispatch(&self, event: &str, payload: &str) -> bool {
+      match self.listeners.lock().unwrap().get(event) {
+        Some(listener) => {
+          listener(Some(payload));
+          true
+        }
+        None => false,
+      }
+    }
+
+    /// Waits up to [Recorder::TIMEOUT] for the first event called `event` and returns its payload
+    ///
+    /// # Panics
+    /// If there is no such event in time
+    pub async fn wait_for(&self, event: &str) -> Value {
+      self.wait_for_nth(event, 0).await
+    }
+
+    /// Like [Recorder::wait_for] but skips the first `n` events called `event`
+    ///
+    /// # Panics
+    /// If there is no such event in time
+    pub async fn wait_for_nth(&self, event: &str, n: usize) -> Value {
+      let deadline = Instant::now() + Self::TIMEOUT;
+      loop {
+        // created before looking so an event recorded in between still wakes it
+        let emitted = self.emitted.notified();
+        {
+          let events = self.events.lock().unwrap();
+          let mut named = events.iter().filter(|(name, _)| name == event);
+          if let Some((_, payload)) = named.nth(n) {
+            return payload.clone();
+          }
+        }
+        if tokio::time::timeout_at(deadline, emitted).await.is_err() {
+          panic!("no '{}' event, recorded: '{:?}'", event, self.names());
+        }
+      }
+    }
+  }
+
+  impl Frontend for Recorder {
+    fn emit_json(&self, event: &str, payload: Value) -> io::Result<()> {
+      self
+        .events
+        .lock()
+        .unwrap()
+        .push((event.to_string(), payload));
+      self.emitted.notify_waiters();
+      Ok(())
+    }
+
+    fn notify(&self, title: &str, _body: Option<&str>) {
+      self.notifications.lock().unwrap().push(title.to_string());
+    }
+
+    fn listen(&self, event: String, listener: Listener) -> Unlisten {
+      self
+        .listeners
+        .lock()
+        .unwrap()
+        .insert(event.clone(), listener);
+      let listeners = self.listeners.clone();
+      Box::new(move || {
+        listeners.lock().unwrap().remove(&event);
+      })
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashMap;
+
+  use super::*;
+
+  /// Tests if payloads are serialized before they reach the implementation
+  #[test]
+  fn emit_serializes() {
+    let recorder = Recorder::default();
+    let frontend: &dyn Frontend = &recorder;
+    frontend.emit("rz-con", 42u64);
+    frontend.emit("has-route", serde_json::json!({ "pending": true }));
+
+    assert_eq!(
+      recorder.names(),
+      vec!["rz-con".to_string(), "has-route".to_string()],
+      "\nemit recorded 'left' but 'right' was expected"
+    );
+    assert_eq!(
+      recorder.payloads(),
+      vec![
+        serde_json::json!(42),
+        serde_json::json!({ "pending": true })
+      ]
+    );
+  }
+
+  /// Tests if a payload that cannot be shown to the frontend is dropped instead of half sent
+  #[test]
+  fn emit_unserializable() {
+    let recorder = Recorder::default();
+    let frontend: &dyn Frontend = &recorder;
+    // json objects only have string keys
+    let payload: HashMap<(u8, u8), u8> = [((1, 2), 3)].into_iter().collect();
+    frontend.emit("broken", payload);
+
+    assert!(
+      recorder.names().is_empty(),
+      "\nemit recorded '{:?}' for a payload without a json representation",
+      recorder.names()
+    );
+  }
+}
@@ -0,0 +1,516 @@
+//! Group rooms with more than two participants
+//!
+//! A group has no tunnel of its own, every member holds a P2P tunnel to every other member
+//! (the same ones used for direct chats) and group packets are fanned out over them.
+//! Whoever invites a user announces the new member list to all members,
+//! tunnels that are missing afterwards are requested by the control channel (see [Groups::missing_links]).
+//! An announcement of an unknown group is only an invitation, nothing is connected
+//! until the local user joins the group (see [Groups::join]).
+//!
+//! Groups only live as long as the app and their messages are not part of the chat history.
+
+use std::{
+  collections::{HashMap, HashSet},
+  fmt,
+  io::{self, ErrorKind},
+  str::FromStr,
+  sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+use smoke::User;
+use tokio::sync::{mpsc, MutexGuard};
+
+use crate::data::UserIdentifier;
+
+use super::{
+  p2p_tunl::packet::{ChatPacket, GroupPacket, Packet},
+  Frontend, Networking,
+};
+
+/// Random identifier of a group, shown to the frontend as bs58 string
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, Debug)]
+pub struct GroupId(pub [u8; 16]);
+
+pub struct Group {
+  pub name: String,
+  /** Remote members of the group, the local user is not part of this set */
+  pub members: HashSet<User>,
+}
+
+#[derive(Clone, Serialize, Debug, PartialEq, Eq)]
+pub struct GroupInfo {
+  id: String,
+  name: String,
+  /** bs58 identifiers of the remote members */
+  members: Vec<String>,
+}
+
+#[derive(Clone, Serialize)]
+struct InvitationPayload<'a> {
+  inviter: String,
+  #[serde(flatten)]
+  group: &'a GroupInfo,
+}
+
+#[derive(Clone, Serialize)]
+struct GroupMessagePayload<'a> {
+  sender: String,
+  #[serde(flatten)]
+  chat: &'a ChatPacket,
+}
+
+/// Shared state of all groups the local user is a member of
+#[derive(Clone)]
+pub struct Groups {
+  groups: Arc<Mutex<HashMap<GroupId, Group>>>,
+  /** Groups the local user was invited to but did not join yet */
+  invitations: Arc<Mutex<HashMap<GroupId, Group>>>,
+  links: mpsc::UnboundedSender<User>,
+  missing: Arc<tokio::sync::Mutex<mpsc::UnboundedReceiver<User>>>,
+}
+
+impl GroupId {
+  /// Creates a new random [GroupId]
+  pub fn random() -> GroupId {
+    GroupId(rand::random())
+  }
+}
+
+impl fmt::Display for GroupId {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(&bs58::encode(&self.0).into_string())
+  }
+}
+
+impl FromStr for GroupId {
+  type Err = io::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let mut id = [0u8; 16];
+    match bs58::decode(s).into(&mut id) {
+      Ok(16) => Ok(GroupId(id)),
+      _ => Err(io::Error::new(
+        ErrorKind::InvalidInput,
+        format!("'{}' is not a valid group id", s),
+      )),
+    }
+  }
+}
+
+impl Group {
+  fn info(&self, id: &GroupId) -> GroupInfo {
+    GroupInfo {
+      id: id.to_string(),
+      name: self.name.clone(),
+      members: self
+        .members
+        .iter()
+        .map(|usr| UserIdentifier::from(usr).bs58.into_owned())
+        .collect(),
+    }
+  }
+}
+
+impl Default for Groups {
+  fn default() -> Self {
+    let (links, missing) = mpsc::unbounded_channel();
+    Groups {
+      groups: Default::default(),
+      invitations: Default::default(),
+      links,
+      missing: Arc::new(tokio::sync::Mutex::new(missing)),
+    }
+  }
+}
+
+impl Groups {
+  /// Receives group members that were announced to the local user
+  /// and might not have a P2P tunnel to it yet
+  ///
+  /// The receiver is locked for as long as the guard lives,
+  /// so only one control channel can take care of the missing tunnels at a time.
+  pub async fn missing_links(&self) -> MutexGuard<'_, mpsc::UnboundedReceiver<User>> {
+    self.missing.lock().await
+  }
+
+  /// Returns `true` if `usr` is a member of any group the local user is part of,
+  /// used to tell room requests linking a group apart from others
+  pub fn is_member(&self, usr: &User) -> bool {
+    let groups = self.groups.lock().unwrap();
+    groups.values().any(|group| group.members.contains(usr))
+  }
+
+  /// Creates a new group with the local user as only member
+  pub fn create(&self, name: String) -> GroupId {
+    let id = GroupId::random();
+    let group = Group {
+      name,
+      members: HashSet::new(),
+    };
+    self.groups.lock().unwrap().insert(id, group);
+    id
+  }
+
+  /// Returns name and members of every group
+  pub fn infos(&self) -> Vec<GroupInfo> {
+    let groups = self.groups.lock().unwrap();
+    groups.iter().map(|(id, group)| group.info(id)).collect()
+  }
+
+  /// Joins the group `id` the local user was invited to
+  /// and requests tunnels to all of its members
+  ///
+  /// Returns `None` if there is no invitation to `id`
+  pub fn join(&self, id: &GroupId, frontend: &dyn Frontend) -> Option<GroupInfo> {
+    let group = self.invitations.lock().unwrap().remove(id)?;
+    let info = group.info(id);
+    let members: Vec<User> = group.members.iter().cloned().collect();
+    self.groups.lock().unwrap().insert(*id, group);
+    frontend.emit("group-joined", &info);
+
+    self.request_links(members);
+    Some(info)
+  }
+
+  /// Hands `members` to the control channel, which requests the tunnels that are missing
+  fn request_links(&self, members: Vec<User>) {
+    for usr in members {
+      // the control channel was dropped, there is nobody left to connect to the members
+      if self.links.send(usr).is_err() {
+        break;
+      }
+    }
+  }
+
+  /// Applies `packet` received from `peer` and tells the frontend about the changes
+  ///
+  /// An announcement of an unknown group is kept as invitation and reported using the "group-invited" event,
+  /// everything else is ignored unless `peer` is a member of the group.
+  pub fn handle(&self, packet: GroupPacket, peer: &User, frontend: &dyn Frontend) {
+    let mut groups = self.groups.lock().unwrap();
+    match packet {
+      GroupPacket::Members {
+        group,
+        name,
+        members,
+      } => match groups.get_mut(&group) {
+        Some(known) if known.members.contains(peer) => {
+          known.name = name;
+          known.members.extend(members.iter().cloned());
+          let info = known.info(&group);
+          log::trace!("members of group '{}': {:?}", info.id, info.members);
+          frontend.emit(&format!("group_members_{}", group), &info);
+          drop(groups);
+          self.request_links(members);
+        }
+        Some(_) => log::warn!("ignoring member list of group '{}' from non member", group),
+        None => {
+          let mut invitation = Group {
+            name,
+            members: members.into_iter().collect(),
+          };
+          invitation.members.insert(peer.clone());
+          let info = invitation.info(&group);
+          self.invitations.lock().unwrap().insert(group, invitation);
+
+          let payload = InvitationPayload {
+            inviter: UserIdentifier::from(peer).bs58.into_owned(),
+            group: &info,
+          };
+          frontend.emit("group-invited", payload);
+          frontend.notify(&format!("Invitation to {}", info.name), None);
+        }
+      },
+      GroupPacket::Leave { group } => {
+        if let Some(known) = groups.get_mut(&group) {
+          if known.members.remove(peer) {
+            frontend.emit(&format!("group_members_{}", group), known.info(&group));
+          }
+        }
+      }
+      GroupPacket::Chat { group, chat } => match groups.get(&group) {
+        Some(known) if known.members.contains(peer) => {
+          let payload = GroupMessagePayload {
+            sender: UserIdentifier::from(peer).bs58.into_owned(),
+            chat: &chat,
+          };
+          frontend.emit(&format!("group_message_recieved_{}", group), payload);
+          frontend.notify(&format!("Message in {}", known.name), Some(&chat.content));
+        }
+        _ => log::warn!("ignoring message to group '{}' from non member", group),
+      },
+    }
+  }
+}
+
+/// Sends a packet created by `packet` for each member of `members` through the tunnel to that member
+///
+/// Members without a tunnel are skipped.
+///
+/// # Errors
+/// This function will return:</br>
+/// [ErrorKind::NotConnected] if not a single member could be reached
+async fn fan_out<F>(net: &Networking, members: &HashSet<User>, packet: F) -> io::Result<()>
+where
+  F: Fn(&User) -> GroupPacket,
+{
+  let mut reached = 0;
+  for usr in members {
+    let link = match net.link(usr) {
+      Some(link) => link,
+      None => {
+        log::warn!(
+          "no tunnel to group member '{}'",
+          UserIdentifier::from(usr).bs58
+        );
+        continue;
+      }
+    };
+    if link.send(Packet::Group(packet(usr))).await.is_ok() {
+      reached += 1;
+    }
+  }
+
+  if reached == 0 && !members.is_empty() {
+    return Err(io::Error::new(
+      ErrorKind::NotConnected,
+      "no member of the group is reachable",
+    ));
+  }
+  Ok(())
+}
+
+/// Sends the name and member list of `group` to each of its members
+async fn announce(net: &Networking, group: GroupId, name: &str, members: &HashSet<User>) {
+  let res = fan_out(net, members, |receiver| GroupPacket::Members {
+    group,
+    name: name.to_string(),
+    members: members
+      .iter()
+      .filter(|usr| *usr != receiver)
+      .cloned()
+      .collect(),
+  })
+  .await;
+  if let Err(err) = res {
+    log::warn!("failed to announce members of group '{}': '{}'", group, err);
+  }
+}
+
+fn members_of(net: &Networking, group: &GroupId) -> io::Result<(String, HashSet<User>)> {
+  let groups = net.groups.groups.lock().unwrap();
+  match groups.get(group) {
+    Some(group) => Ok((group.name.clone(), group.members.clone())),
+    None => Err(io::Error::new(
+      ErrorKind::NotFound,
+      format!("unknown group '{}'", group),
+    )),
+  }
+}
+
+/// Creates a new group called `name` and returns its id
+#[tauri::command]
+pub fn create_group(name: String, net: tauri::State<'_, Networking>) -> String {
+  net.groups.create(name).to_string()
+}
+
+/// Returns name and members of every group the local user is part of
+#[tauri::command]
+pub fn get_groups(net: tauri::State<'_, Networking>) -> Vec<GroupInfo> {
+  net.groups.infos()
+}
+
+/// Joins `group` after being invited to it, which is reported using the "group-joined" event
+///
+/// # Errors
+/// This function will return:</br>
+/// [ErrorKind::InvalidInput] if `group` is malformed</br>
+/// [ErrorKind::NotFound] if there is no invitation to `group`
+#[tauri::command]
+pub fn join_group(
+  window: tauri::Window,
+  group: String,
+  net: tauri::State<'_, Networking>,
+) -> tauri::Result<GroupInfo> {
+  let group: GroupId = group.parse()?;
+  net.groups.join(&group, &window).ok_or_else(|| {
+    tauri::Error::Io(io::Error::new(
+      ErrorKind::NotFound,
+      format!("no invitation to group '{}'", group),
+    ))
+  })
+}
+
+/// Adds the user identified by `bs58cert` to `group` and announces the new member list to all members
+///
+/// # Errors
+/// This function will return:</br>
+/// [ErrorKind::InvalidInput] if `group` is malformed</br>
+/// [ErrorKind::NotFound] if the local user is not part of `group`</br>
+/// [ErrorKind::NotConnected] if there is no P2P tunnel to the invited user
+#[tauri::command(async)]
+pub async fn invite_to_group(
+  group: String,
+  bs58cert: String,
+  net: tauri::State<'_, Networking>,
+) -> tauri::Result<()> {
+  let group: GroupId = group.parse()?;
+  let ident = UserIdentifier {
+    bs58: std::borrow::Cow::Owned(bs58cert),
+  };
+  let usr: User = (&ident).try_into()?;
+  if net.link(&usr).is_none() {
+    return Err(tauri::Error::Io(io::Error::new(
+      ErrorKind::NotConnected,
+      "open a room with the user before inviting them",
+    )));
+  }
+
+  let (name, mut members) = members_of(&net, &group)?;
+  members.insert(usr.clone());
+  if let Some(known) = net.groups.groups.lock().unwrap().get_mut(&group) {
+    known.members.insert(usr);
+  }
+
+  announce(&net, group, &name, &members).await;
+  Ok(())
+}
+
+/// Leaves `group` and tells all members about it
+///
+/// # Errors
+/// This function will return:</br>
+/// [ErrorKind::InvalidInput] if `group` is malformed</br>
+/// [ErrorKind::NotFound] if the local user is not part of `group`
+#[tauri::command(async)]
+pub async fn leave_group(group: String, net: tauri::State<'_, Networking>) -> tauri::Result<()> {
+  let group: GroupId = group.parse()?;
+  let (_, members) = members_of(&net, &group)?;
+  net.groups.groups.lock().unwrap().remove(&group);
+
+  if let Err(err) = fan_out(&net, &members, |_| GroupPacket::Leave { group }).await {
+    log::warn!("failed to announce leaving group '{}': '{}'", group, err);
+  }
+  Ok(())
+}
+
+/// Sends the chat message `content` to every member of `group`
+/// and returns it including its id and send time
+///
+/// # Errors
+/// This function will return:</br>
+/// [ErrorKind::InvalidInput] if `group` is malformed</br>
+/// [ErrorKind::NotFound] if the local user is not part of `group`</br>
+/// [ErrorKind::NotConnected] if no member of `group` is reachable
+#[tauri::command(async)]
+pub async fn send_group_message(
+  group: String,
+  content: String,
+  net: tauri::State<'_, Networking>,
+) -> tauri::Result<ChatPacket> {
+  let group: GroupId = group.parse()?;
+  let (_, members) = members_of(&net, &group)?;
+  let chat = ChatPacket::new(content);
+
+  fan_out(&net, &members, |_| GroupPacket::Chat {
+    group,
+    chat: chat.clone(),
+  })
+  .await?;
+  Ok(chat)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::network::frontend::Recorder;
+
+  fn usr(byte: u8) -> User {
+    User {
+      cert_data: vec![byte; 8],
+    }
+  }
+
+  fn members(group: GroupId, members: Vec<User>) -> GroupPacket {
+    GroupPacket::Members {
+      group,
+      name: "team".to_string(),
+      members,
+    }
+  }
+
+  /// Tests if a group id survives being shown to the frontend
+  #[test]
+  fn group_id_bs58() {
+    let id = GroupId::random();
+    assert_eq!(id.to_string().parse::<GroupId>().unwrap(), id);
+    assert_eq!(
+      "not bs58!".parse::<GroupId>().unwrap_err().kind(),
+      ErrorKind::InvalidInput
+    );
+  }
+
+  /// Tests if an announcement of an unknown group only joins it once the invitation is accepted
+  #[tokio::test]
+  async fn join_by_invitation() {
+    let groups = Groups::default();
+    let events = Recorder::default();
+    let id = GroupId::random();
+
+    groups.handle(members(id, vec![usr(2), usr(3)]), &usr(1), &events);
+    assert!(groups.infos().is_empty());
+    assert!(!groups.is_member(&usr(1)));
+    assert_eq!(events.names(), vec!["group-invited".to_string()]);
+    assert_eq!(
+      events.payloads()[0]["inviter"],
+      UserIdentifier::from(&usr(1)).bs58.into_owned()
+    );
+    assert!(groups.missing_links().await.try_recv().is_err());
+
+    let info = groups.join(&id, &events).unwrap();
+    assert_eq!(info.members.len(), 3);
+    assert_eq!(groups.infos(), vec![info]);
+    assert!(groups.is_member(&usr(1)));
+    assert!(groups.join(&id, &events).is_none());
+
+    let mut missing = groups.missing_links().await;
+    let mut linked = HashSet::new();
+    while let Ok(usr) = missing.try_recv() {
+      linked.insert(usr);
+    }
+    assert_eq!(linked, HashSet::from([usr(1), usr(2), usr(3)]));
+  }
+
+  /// Tests if packets of known groups are only accepted from members
+  #[test]
+  fn members_only() {
+    let groups = Groups::default();
+    let events = Recorder::default();
+    let id = GroupId::random();
+    groups.handle(members(id, vec![usr(2)]), &usr(1), &events);
+    groups.join(&id, &events).unwrap();
+
+    let chat = GroupPacket::Chat {
+      group: id,
+      chat: ChatPacket::new("hello".to_string()),
+    };
+    groups.handle(chat.clone(), &usr(4), &events);
+    groups.handle(members(id, vec![usr(4)]), &usr(4), &events);
+    assert_eq!(groups.infos()[0].members.len(), 2);
+
+    groups.handle(chat, &usr(2), &events);
+    groups.handle(GroupPacket::Leave { group: id }, &usr(2), &events);
+    assert!(!groups.is_member(&usr(2)));
+    assert_eq!(
+      events.names(),
+      vec![
+        "group-invited".to_string(),
+        "group-joined".to_string(),
+        format!("group_message_recieved_{}", id),
+        format!("group_members_{}", id),
+      ],
+      "\nhandle emitted 'left' but 'right' was expected"
+    );
+  }
+}
@@ -2,9 +2,15 @@ use log::{error, trace};
 use std::{
   io::{self, Error, ErrorKind},
   net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+  time::Duration,
 };
 use tokio::net::UdpSocket;
 
+/// How often a refused PING is sent again before P3 fails
+const P3_RETRIES: usize = 10;
+/// Time to wait before sending a refused PING again
+const P3_RETRY_DELAY: Duration = Duration::from_millis(100);
+
 /** Create a new socket and holepunch it! */
 pub async fn punch_hole<A>(server_addr: A, ident: &[u8]) -> Result<UdpSocket, Error>
 where
@@ -51,7 +57,7 @@ async fn ping_pong_peng(socket: &UdpSocket) -> io::Result<()> {
   socket.send(b"PING").await?;
   let mut ping = false;
   for i in 0..2 {
-    socket.recv(&mut buf).await?;
+    recv_p3(socket, &mut buf).await?;
     trace!("{}: got {}", i, String::from_utf8_lossy(&buf));
     match &buf {
       b"PING" => {
@@ -79,6 +85,31 @@ async fn ping_pong_peng(socket: &UdpSocket) -> io::Result<()> {
   Err(Error::new(io::ErrorKind::Other, "got multiple PING"))
 }
 
+/// Receives the next P3 message, sending PING again while the peer refuses it
+///
+/// Without a NAT in between (LAN, loopback) the first PING can arrive before the peer
+/// switched its socket from rhizome to us, which is reported as [ErrorKind::ConnectionRefused]
+async fn recv_p3(socket: &UdpSocket, buf: &mut [u8; 4]) -> io::Result<usize> {
+  for _ in 0..P3_RETRIES {
+    match socket.recv(buf).await {
+      Err(err) if err.kind() == ErrorKind::ConnectionRefused => {
+        trace!("PING refused, peer not ready yet");
+        tokio::time::sleep(P3_RETRY_DELAY).await;
+        match socket.send(b"PING").await {
+          Err(err) if err.kind() != ErrorKind::ConnectionRefused => return Err(err),
+          _ => (),
+        }
+      }
+      res => return res,
+    }
+  }
+
+  Err(Error::new(
+    ErrorKind::ConnectionRefused,
+    "peer refused PING",
+  ))
+}
+
 /** Parse a collection of bytes to a valid IP address. */
 fn parse_addr(b: &[u8; 512], size: usize) -> Result<SocketAddr, Error> {
   // Parse the bytes into a valid socket address:
@@ -0,0 +1,943 @@
+//! In-process stand-in for rhizome, only used by tests
+//!
+//! Listens on loopback and speaks the TLS control protocol as well as the UDP address exchange
+//! used by [punch_hole](super::holepunch::punch_hole), which allows pairing two clients on one machine.
+
+use std::{
+  collections::{HashMap, VecDeque},
+  io::{self, ErrorKind},
+  net::SocketAddr,
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig};
+use serde::{de::DeserializeOwned, Serialize};
+use smoke::{
+  messages::{EmbMessage, RhizMessage, RoomId},
+  User,
+};
+use tokio::{
+  io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+  net::{TcpListener, TcpStream, UdpSocket},
+  select,
+  sync::mpsc,
+};
+use tokio_kcp::{KcpConfig, KcpStream};
+use tokio_rustls::{client, TlsAcceptor, TlsConnector, TlsStream};
+
+use super::holepunch::punch_hole;
+use super::p2p_tunl::tls_kcp;
+use crate::data::servers::ServerProfile;
+
+/// Time a [MockClient] waits for a message from the mock before giving up
+const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Senders to every identified client of the mock
+type Clients = Arc<Mutex<HashMap<User, mpsc::UnboundedSender<RhizMessage>>>>;
+/// Room requests that were not yet answered, keyed by the requested user
+type Requests = Arc<Mutex<HashMap<User, VecDeque<User>>>>;
+
+pub struct MockRhizome {
+  /** Profile pointing at this mock, usable like any other server profile */
+  pub profile: ServerProfile,
+}
+
+impl MockRhizome {
+  /// Starts the control channel and the address exchange of a new mock on random loopback ports
+  ///
+  /// Both keep running until the tokio runtime of the caller shuts down.
+  pub async fn spawn() -> io::Result<MockRhizome> {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])
+      .map_err(|err| io::Error::new(ErrorKind::Other, err))?;
+    let cert_der = cert
+      .serialize_der()
+      .map_err(|err| io::Error::new(ErrorKind::Other, err))?;
+    let cert_pem = cert
+      .serialize_pem()
+      .map_err(|err| io::Error::new(ErrorKind::Other, err))?;
+
+    let config = ServerConfig::builder()
+      .with_safe_defaults()
+      .with_no_client_auth()
+      .with_single_cert(
+        vec![Certificate(cert_der)],
+        PrivateKey(cert.serialize_private_key_der()),
+      )
+      .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let udp = UdpSocket::bind("127.0.0.1:0").await?;
+    let profile = ServerProfile {
+      name: "mock".to_string(),
+      control_address: listener.local_addr()?.to_string(),
+      server_address: udp.local_addr()?.to_string(),
+      domain: "localhost".to_string(),
+      cert: cert_pem,
+    };
+
+    let clients = Clients::default();
+    let requests = Requests::default();
+    tokio::spawn(async move {
+      loop {
+        let (stream, _) = match listener.accept().await {
+          Ok(conn) => conn,
+          Err(err) => return log::error!("mock rhizome stopped accepting: '{}'", err),
+        };
+        let acceptor = acceptor.clone();
+        let clients = clients.clone();
+        let requests = requests.clone();
+        tokio::spawn(async move {
+          if let Err(err) = serve(stream, acceptor, clients, requests).await {
+            log::trace!("mock rhizome closed a connection: '{}'", err);
+          }
+        });
+      }
+    });
+    tokio::spawn(async move {
+      if let Err(err) = exchange_addrs(udp).await {
+        log::error!("mock rhizome stopped exchanging addresses: '{}'", err);
+      }
+    });
+
+    Ok(MockRhizome { profile })
+  }
+}
+
+/// Greets a new client, reads its certificate and relays its messages until it disconnects
+async fn serve(
+  stream: TcpStream,
+  acceptor: TlsAcceptor,
+  clients: Clients,
+  requests: Requests,
+) -> io::Result<()> {
+  let mut tls = BufReader::new(acceptor.accept(stream).await?);
+  tls.write_all(b"rhizome v0.3.0\n").await?;
+  tls.flush().await?;
+
+  let mut buf = Vec::new();
+  let user = User {
+    cert_data: read_frame(&mut tls, &mut buf).await?,
+  };
+  let (tx, mut rx) = mpsc::unbounded_channel();
+  clients.lock().unwrap().insert(user.clone(), tx);
+
+  let res = loop {
+    select! {
+      Some(msg) = rx.recv() => {
+        if let Err(err) = write_frame(&mut tls, &msg).await {
+          break err;
+        }
+      }
+      msg = read_frame::<_, EmbMessage>(&mut tls, &mut buf) => match msg {
+        Ok(msg) => relay(&user, msg, &clients, &requests),
+        Err(err) => break err,
+      }
+    }
+  };
+
+  clients.lock().unwrap().remove(&user);
+  requests.lock().unwrap().remove(&user);
+  Err(res)
+}
+
+/// Applies a single message of `user` the way rhizome would
+fn relay(user: &User, msg: EmbMessage, clients: &Clients, requests: &Requests) {
+  let clients = clients.lock().unwrap();
+  let send = |to: &User, msg: RhizMessage| {
+    if let Some(tx) = clients.get(to) {
+      let _ = tx.send(msg);
+    }
+  };
+
+  match msg {
+    EmbMessage::Room(target) => {
+      if clients.contains_key(&target) && &target != user {
+        let mut requests = requests.lock().unwrap();
+        requests
+          .entry(target.clone())
+          .or_default()
+          .push_back(user.clone());
+        send(user, RhizMessage::HasRoute(target.clone()));
+        send(&target, RhizMessage::WantsRoom(user.clone()));
+      } else {
+        send(user, RhizMessage::NoRoute(target));
+      }
+    }
+    EmbMessage::Accept(accepted) => {
+      let requester = match requests.lock().unwrap().get_mut(user) {
+        Some(pending) => pending.pop_front(),
+        None => None,
+      };
+      let requester = match requester {
+        Some(requester) => requester,
+        None => return log::warn!("mock rhizome got an answer without a request"),
+      };
+
+      if accepted {
+        let id = RoomId(rand::random());
+        send(
+          &requester,
+          RhizMessage::AcceptedRoom(Some(id.clone()), user.clone()),
+        );
+        send(user, RhizMessage::AcceptedRoom(Some(id), requester));
+      } else {
+        send(&requester, RhizMessage::AcceptedRoom(None, user.clone()));
+      }
+    }
+    msg => log::warn!("mock rhizome ignores: '{:?}'", msg),
+  }
+}
+
+/// Pairs the first two clients that sent the same room id and tells each the address of the other
+async fn exchange_addrs(socket: UdpSocket) -> io::Result<()> {
+  let mut waiting: HashMap<Vec<u8>, SocketAddr> = HashMap::new();
+  let mut buf = [0u8; 512];
+  loop {
+    let (size, addr) = socket.recv_from(&mut buf).await?;
+    let ident = buf[..size].to_vec();
+    match waiting.remove(&ident) {
+      Some(peer) if peer != addr => {
+        socket.send_to(&encode_addr(peer), addr).await?;
+        socket.send_to(&encode_addr(addr), peer).await?;
+      }
+      _ => {
+        waiting.insert(ident, addr);
+      }
+    }
+  }
+}
+
+/// Inverse of the address parsing in [punch_hole](super::holepunch::punch_hole)
+fn encode_addr(addr: SocketAddr) -> Vec<u8> {
+  let mut bytes = match addr {
+    SocketAddr::V4(addr) => {
+      let mut bytes = vec![4];
+      bytes.extend_from_slice(&addr.ip().octets());
+      bytes
+    }
+    SocketAddr::V6(addr) => {
+      let mut bytes = vec![6];
+      bytes.extend_from_slice(&addr.ip().octets());
+      bytes
+    }
+  };
+  bytes.extend_from_slice(&addr.port().to_be_bytes());
+  bytes
+}
+
+async fn write_frame<W, T>(stream: &mut W, msg: &T) -> io::Result<()>
+where
+  W: AsyncWrite + Unpin,
+  T: Serialize,
+{
+  let frame =
+    postcard::to_stdvec_cobs(msg).map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+  stream.write_all(&frame).await?;
+  stream.flush().await
+}
+
+/// Reads a single COBS frame, cancel safe as long as `buf` is kept
+async fn read_frame<R, T>(stream: &mut R, buf: &mut Vec<u8>) -> io::Result<T>
+where
+  R: AsyncBufRead + Unpin,
+  T: DeserializeOwned,
+{
+  stream.read_until(0, buf).await?;
+  if buf.last() != Some(&0) {
+    return Err(io::Error::new(
+      ErrorKind::UnexpectedEof,
+      "mock connection closed",
+    ));
+  }
+
+  let msg = postcard::from_bytes_cobs(&mut buf[..])
+    .map_err(|err| io::Error::new(ErrorKind::InvalidData, err));
+  buf.clear();
+  msg
+}
+
+/// Generates a new identity like the ones of real users
+fn identity() -> io::Result<(User, Certificate, PrivateKey)> {
+  let identity = rcgen::generate_simple_self_signed(vec!["emberry_user".to_string()])
+    .map_err(|err| io::Error::new(ErrorKind::Other, err))?;
+  let cert = identity
+    .serialize_der()
+    .map_err(|err| io::Error::new(ErrorKind::Other, err))?;
+  let user = User {
+    cert_data: cert.clone(),
+  };
+  Ok((
+    user,
+    Certificate(cert),
+    PrivateKey(identity.serialize_private_key_der()),
+  ))
+}
+
+/// Minimal client of the control channel with a freshly generated identity
+pub struct MockClient {
+  pub user: User,
+  key: PrivateKey,
+  tls: BufReader<client::TlsStream<TcpStream>>,
+  buf: Vec<u8>,
+}
+
+impl MockClient {
+  /// Connects to the rhizome of `profile` the same way [connect](super::ctrl_chnl::connect) does
+  pub async fn connect(profile: &ServerProfile) -> io::Result<MockClient> {
+    let (user, _, key) = identity()?;
+
+    let mut root_store = RootCertStore::empty();
+    root_store
+      .add(&profile.certificate()?)
+      .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+    let config = ClientConfig::builder()
+      .with_safe_defaults()
+      .with_root_certificates(root_store)
+      .with_no_client_auth();
+
+    let conn = TlsConnector::from(Arc::new(config));
+    let sock = TcpStream::connect(&profile.control_address).await?;
+    let mut tls = BufReader::new(conn.connect(profile.server_name()?, sock).await?);
+
+    let mut greeting = String::new();
+    tls.read_line(&mut greeting).await?;
+    if greeting != "rhizome v0.3.0\n" {
+      return Err(io::Error::new(ErrorKind::Unsupported, greeting));
+    }
+    write_frame(&mut tls, &user.cert_data).await?;
+
+    Ok(MockClient {
+      user,
+      key,
+      tls,
+      buf: Vec::new(),
+    })
+  }
+
+  pub async fn send(&mut self, msg: EmbMessage) -> io::Result<()> {
+    msg.send_with(&mut self.tls).await
+  }
+
+  /// Waits up to [RECV_TIMEOUT] for the next message from the mock
+  pub async fn recv(&mut self) -> io::Result<RhizMessage> {
+    let msg = RhizMessage::recv_with(&mut self.tls, &mut self.buf);
+    tokio::time::timeout(RECV_TIMEOUT, msg)
+      .await
+      .map_err(|_| io::Error::new(ErrorKind::TimedOut, "mock rhizome did not answer"))?
+  }
+
+  /// Opens the tunnel of the room `id` with `peer` through the rhizome of `profile`
+  /// the same way the control channel does once a room was accepted
+  pub async fn tunnel(
+    &self,
+    profile: &ServerProfile,
+    id: &RoomId,
+    peer: &User,
+  ) -> io::Result<BufReader<TlsStream<KcpStream>>> {
+    let socket = punch_hole(profile.server_address.as_str(), &id.0).await?;
+    let addr = socket.peer_addr()?;
+    let stream = KcpStream::connect_with_socket(&KcpConfig::default(), socket, addr)
+      .await
+      .map_err(|err| io::Error::new(ErrorKind::Other, err.to_string()))?;
+
+    let peer_cert = Certificate(peer.cert_data.clone());
+    let identity = (Certificate(self.user.cert_data.clone()), self.key.clone());
+    // the user with the lower certificate acts as the TLS client
+    let stream = if self.user.cert_data < peer.cert_data {
+      tls_kcp::wrap_client_as(stream, &peer_cert, identity).await?
+    } else {
+      tls_kcp::wrap_server_as(stream, &peer_cert, identity).await?
+    };
+    Ok(BufReader::new(stream))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::future::Future;
+
+  use once_cell::sync::Lazy;
+  use serde_json::json;
+  use tokio::sync::RwLock;
+
+  use super::*;
+  use crate::data::sqlite::policy::{self, RoomPolicy};
+  use crate::data::sqlite::{try_exec, user};
+  use crate::data::{config, IdentifiedUserInfo, UserIdentifier, UserInfo, UserRelation};
+  use crate::network::ctrl_chnl::{
+    disconnect, requests::send_request, responses::send_answer, run_on, RhizomeConnection,
+  };
+  use crate::network::frontend::Recorder;
+  use crate::network::group::GroupId;
+  use crate::network::p2p_tunl::packet::{ChatPacket, GroupPacket, Packet};
+  use crate::network::{Frontend, Networking};
+
+  /// Held by every test running the control channel, as only one may run at a time
+  /// and each replaces the identity of the local user
+  static CONTROL: Lazy<tokio::sync::Mutex<()>> = Lazy::new(Default::default);
+
+  fn init() {
+    let _ = env_logger::builder().is_test(true).try_init();
+  }
+
+  /// Local user running the production control channel
+  struct Local {
+    user: User,
+    events: Arc<Recorder>,
+    net: Networking,
+    rc: RhizomeConnection,
+  }
+
+  impl Local {
+    /// Creates a local user with a freshly generated identity
+    fn new() -> Local {
+      let (user, cert, key) = identity().unwrap();
+      *config::PEM_DATA.write().unwrap() = Some((cert, key));
+      Local {
+        user,
+        events: Default::default(),
+        net: Networking {
+          chats: Default::default(),
+          pending: Default::default(),
+          groups: Default::default(),
+          reconnects: Default::default(),
+        },
+        rc: RwLock::new(None),
+      }
+    }
+
+    /// Connects to `mock`, runs `scenario` once connected and disconnects again
+    async fn run(&self, mock: &MockRhizome, scenario: impl Future<Output = ()>) {
+      let frontend: Arc<dyn Frontend> = self.events.clone();
+      let scenario = async {
+        self.events.wait_for("rz-con").await;
+        scenario.await;
+        assert!(disconnect(&self.rc).await);
+      };
+      let (res, ()) = tokio::join!(
+        run_on(frontend, &self.net, &self.rc, mock.profile.clone()),
+        scenario
+      );
+      res.unwrap();
+    }
+
+    /// Lets `peer` request a room, accepts it and opens the tunnel on the end of `peer`
+    ///
+    /// `requests` is the number of room requests the local user got before this one.
+    async fn accept_room(
+      &self,
+      mock: &MockRhizome,
+      peer: &mut MockClient,
+      requests: usize,
+    ) -> (RoomId, BufReader<TlsStream<KcpStream>>) {
+      peer
+        .send(EmbMessage::Room(self.user.clone()))
+        .await
+        .unwrap();
+      self.events.wait_for_nth("wants-room", requests).await;
+      send_answer(bs58(&peer.user), true, &self.net, &self.rc)
+        .await
+        .unwrap();
+
+      assert!(matches!(
+        peer.recv().await.unwrap(),
+        RhizMessage::HasRoute(_)
+      ));
+      let id = match peer.recv().await.unwrap() {
+        RhizMessage::AcceptedRoom(Some(id), usr) => {
+          assert_eq!(usr, self.user);
+          id
+        }
+        msg => panic!("expected an accepted room but got '{:?}'", msg),
+      };
+      let tunnel = peer.tunnel(&mock.profile, &id, &self.user).await.unwrap();
+      (id, tunnel)
+    }
+  }
+
+  fn bs58(usr: &User) -> String {
+    UserIdentifier::from(usr).bs58.into_owned()
+  }
+
+  /// Stores `usr` as stranger answered according to its own `policy`
+  async fn set_policy(usr: &User, policy: RoomPolicy) {
+    let ident_info = IdentifiedUserInfo {
+      identifier: UserIdentifier::from(usr),
+      info: UserInfo {
+        relation: UserRelation::Stranger,
+        username: bs58(usr),
+        nickname: None,
+      },
+    };
+    let entry = ident_info.clone();
+    try_exec(move |db| user::upsert(db, (&entry, |_| ())))
+      .await
+      .unwrap();
+    let ident = ident_info.identifier;
+    assert!(
+      try_exec(move |db| policy::set_for_user(db, (&ident, Some(policy))))
+        .await
+        .unwrap()
+    );
+  }
+
+  /// Connects two clients and lets `b` answer a room request of `a`
+  async fn request_room(
+    mock: &MockRhizome,
+    accept: bool,
+  ) -> (MockClient, MockClient, RhizMessage, RhizMessage) {
+    let mut a = MockClient::connect(&mock.profile).await.unwrap();
+    let mut b = MockClient::connect(&mock.profile).await.unwrap();
+
+    a.send(EmbMessage::Room(b.user.clone())).await.unwrap();
+    match a.recv().await.unwrap() {
+      RhizMessage::HasRoute(usr) => assert_eq!(usr, b.user),
+      msg => panic!("expected HasRoute but got '{:?}'", msg),
+    }
+    match b.recv().await.unwrap() {
+      RhizMessage::WantsRoom(usr) => assert_eq!(usr, a.user),
+      msg => panic!("expected WantsRoom but got '{:?}'", msg),
+    }
+
+    b.send(EmbMessage::Accept(accept)).await.unwrap();
+    let answer_a = a.recv().await.unwrap();
+    let answer_b = if accept {
+      b.recv().await.unwrap()
+    } else {
+      RhizMessage::ServerError("no answer expected".to_string())
+    };
+    (a, b, answer_a, answer_b)
+  }
+
+  /// Tests if requesting a room with a user that is not connected results in [RhizMessage::NoRoute]
+  #[tokio::test]
+  async fn no_route() {
+    init();
+    let mock = MockRhizome::spawn().await.unwrap();
+    let mut a = MockClient::connect(&mock.profile).await.unwrap();
+    let offline = User {
+      cert_data: vec![1, 2, 3],
+    };
+
+    a.send(EmbMessage::Room(offline.clone())).await.unwrap();
+    match a.recv().await.unwrap() {
+      RhizMessage::NoRoute(usr) => assert_eq!(usr, offline),
+      msg => panic!("expected NoRoute but got '{:?}'", msg),
+    }
+  }
+
+  /// Tests if a rejected room request is reported to the requester without a room id
+  #[tokio::test]
+  async fn reject_room() {
+    init();
+    let mock = MockRhizome::spawn().await.unwrap();
+    let (_a, b, answer, _) = request_room(&mock, false).await;
+
+    match answer {
+      RhizMessage::AcceptedRoom(None, usr) => assert_eq!(usr, b.user),
+      msg => panic!("expected rejection but got '{:?}'", msg),
+    }
+  }
+
+  /// Tests room request, accept, hole punch and a chat message between two local clients
+  #[tokio::test]
+  async fn room_to_chat() {
+    init();
+    let mock = MockRhizome::spawn().await.unwrap();
+    let (a, b, answer_a, answer_b) = request_room(&mock, true).await;
+
+    let (id_a, id_b) = match (answer_a, answer_b) {
+      (
+        RhizMessage::AcceptedRoom(Some(id_a), usr_a),
+        RhizMessage::AcceptedRoom(Some(id_b), usr_b),
+      ) => {
+        assert_eq!(usr_a, b.user);
+        assert_eq!(usr_b, a.user);
+        (id_a, id_b)
+      }
+      msgs => panic!("expected two accepted rooms but got '{:?}'", msgs),
+    };
+    assert_eq!(id_a, id_b, "\nboth clients need to get the same room id");
+
+    let server = mock.profile.server_address.as_str();
+    let (sock_a, sock_b) = tokio::join!(punch_hole(server, &id_a.0), punch_hole(server, &id_b.0));
+    let (sock_a, sock_b) = (sock_a.unwrap(), sock_b.unwrap());
+    let (addr_a, addr_b) = (sock_a.peer_addr().unwrap(), sock_b.peer_addr().unwrap());
+    assert_eq!(addr_a, sock_b.local_addr().unwrap());
+    assert_eq!(addr_b, sock_a.local_addr().unwrap());
+
+    let config = KcpConfig::default();
+    let mut stream_a = KcpStream::connect_with_socket(&config, sock_a, addr_a)
+      .await
+      .unwrap();
+    let stream_b = KcpStream::connect_with_socket(&config, sock_b, addr_b)
+      .await
+      .unwrap();
+    let mut stream_b = BufReader::new(stream_b);
+
+    let chat = ChatPacket::new("hello from a".to_string());
+    Packet::Chat(chat.clone())
+      .send_with(&mut stream_a)
+      .await
+      .unwrap();
+
+    let mut buf = Vec::new();
+    let packet = Packet::recv_with(&mut stream_b, &mut buf);
+    match tokio::time::timeout(RECV_TIMEOUT, packet).await {
+      Ok(Ok(Packet::Chat(result))) => {
+        assert_eq!(result.id, chat.id);
+        assert_eq!(result.content, chat.content);
+      }
+      res => panic!("expected the chat packet but got '{:?}'", res),
+    }
+  }
+
+  /// Tests if requesting a room with a user that is not connected is reported using the "no-route" event
+  #[tokio::test]
+  async fn ctrl_no_route() {
+    init();
+    let _control = CONTROL.lock().await;
+    let mock = MockRhizome::spawn().await.unwrap();
+    let local = Local::new();
+    let offline = bs58(&User {
+      cert_data: vec![1, 2, 3],
+    });
+
+    local
+      .run(&mock, async {
+        send_request(&*local.events, offline.clone(), &local.net, &local.rc)
+          .await
+          .unwrap();
+        let route = local.events.wait_for("no-route").await;
+        assert_eq!(route, json!({ "pending": true, "usr": offline }));
+      })
+      .await;
+    assert!(local.net.pending.lock().unwrap().is_empty());
+  }
+
+  /// Tests if a room request is shown to the local user and a rejection reaches the requester
+  #[tokio::test]
+  async fn ctrl_reject_room() {
+    init();
+    let _control = CONTROL.lock().await;
+    let mock = MockRhizome::spawn().await.unwrap();
+    let local = Local::new();
+    let mut peer = MockClient::connect(&mock.profile).await.unwrap();
+
+    local
+      .run(&mock, async {
+        peer
+          .send(EmbMessage::Room(local.user.clone()))
+          .await
+          .unwrap();
+        let request = local.events.wait_for("wants-room").await;
+        let request: IdentifiedUserInfo = serde_json::from_value(request).unwrap();
+        assert_eq!(request.identifier, UserIdentifier::from(&peer.user));
+        assert_eq!(local.events.notifications().len(), 1);
+
+        send_answer(bs58(&peer.user), false, &local.net, &local.rc)
+          .await
+          .unwrap();
+        match peer.recv().await.unwrap() {
+          RhizMessage::HasRoute(usr) => assert_eq!(usr, local.user),
+          msg => panic!("expected HasRoute but got '{:?}'", msg),
+        }
+        match peer.recv().await.unwrap() {
+          RhizMessage::AcceptedRoom(None, usr) => assert_eq!(usr, local.user),
+          msg => panic!("expected rejection but got '{:?}'", msg),
+        }
+      })
+      .await;
+  }
+
+  /// Tests if room requests are accepted, rejected or shown to the local user as their policy says
+  #[tokio::test]
+  async fn ctrl_room_policy() {
+    init();
+    let _control = CONTROL.lock().await;
+    let mock = MockRhizome::spawn().await.unwrap();
+    let local = Local::new();
+    let mut rejected = MockClient::connect(&mock.profile).await.unwrap();
+    let mut asking = MockClient::connect(&mock.profile).await.unwrap();
+    let mut accepted = MockClient::connect(&mock.profile).await.unwrap();
+    set_policy(&rejected.user, RoomPolicy::Reject).await;
+    set_policy(&asking.user, RoomPolicy::Ask).await;
+    set_policy(&accepted.user, RoomPolicy::Accept).await;
+
+    local
+      .run(&mock, async {
+        rejected
+          .send(EmbMessage::Room(local.user.clone()))
+          .await
+          .unwrap();
+        assert!(matches!(
+          rejected.recv().await.unwrap(),
+          RhizMessage::HasRoute(_)
+        ));
+        match rejected.recv().await.unwrap() {
+          RhizMessage::AcceptedRoom(None, usr) => assert_eq!(usr, local.user),
+          msg => panic!("expected rejection but got '{:?}'", msg),
+        }
+
+        asking
+          .send(EmbMessage::Room(local.user.clone()))
+          .await
+          .unwrap();
+        let request = local.events.wait_for("wants-room").await;
+        assert_eq!(request["group_member"], json!(false));
+        let request: IdentifiedUserInfo = serde_json::from_value(request).unwrap();
+        assert_eq!(request.identifier, UserIdentifier::from(&asking.user));
+        assert_eq!(local.events.notifications().len(), 1);
+        // rhizome answers the requests in order, so this one is answered before the next
+        send_answer(bs58(&asking.user), false, &local.net, &local.rc)
+          .await
+          .unwrap();
+        assert!(matches!(
+          asking.recv().await.unwrap(),
+          RhizMessage::HasRoute(_)
+        ));
+        assert!(matches!(
+          asking.recv().await.unwrap(),
+          RhizMessage::AcceptedRoom(None, _)
+        ));
+
+        accepted
+          .send(EmbMessage::Room(local.user.clone()))
+          .await
+          .unwrap();
+        assert!(matches!(
+          accepted.recv().await.unwrap(),
+          RhizMessage::HasRoute(_)
+        ));
+        let id = match accepted.recv().await.unwrap() {
+          RhizMessage::AcceptedRoom(Some(id), usr) => {
+            assert_eq!(usr, local.user);
+            id
+          }
+          msg => panic!("expected an accepted room but got '{:?}'", msg),
+        };
+        let _tunnel = accepted
+          .tunnel(&mock.profile, &id, &local.user)
+          .await
+          .unwrap();
+        local.events.wait_for("new-room").await;
+
+        let asked = local.events.names();
+        assert_eq!(
+          asked.iter().filter(|name| *name == "wants-room").count(),
+          1,
+          "\nonly the user with the policy to ask has to be asked"
+        );
+        assert_eq!(local.events.notifications().len(), 1);
+      })
+      .await;
+  }
+
+  /// Tests if a request crossing the one of the local user opens a room
+  /// even though the policy for the peer rejects its requests
+  #[tokio::test]
+  async fn ctrl_crossing_request() {
+    init();
+    let _control = CONTROL.lock().await;
+    let mock = MockRhizome::spawn().await.unwrap();
+    let local = Local::new();
+    // the local user has priority, so its answer decides whether there is a room
+    let mut peer = loop {
+      let peer = MockClient::connect(&mock.profile).await.unwrap();
+      if local.user.cert_data < peer.user.cert_data {
+        break peer;
+      }
+    };
+    set_policy(&peer.user, RoomPolicy::Reject).await;
+
+    local
+      .run(&mock, async {
+        send_request(&*local.events, bs58(&peer.user), &local.net, &local.rc)
+          .await
+          .unwrap();
+        match peer.recv().await.unwrap() {
+          RhizMessage::WantsRoom(usr) => assert_eq!(usr, local.user),
+          msg => panic!("expected WantsRoom but got '{:?}'", msg),
+        }
+
+        peer
+          .send(EmbMessage::Room(local.user.clone()))
+          .await
+          .unwrap();
+        peer.send(EmbMessage::Accept(false)).await.unwrap();
+        let id = loop {
+          match peer.recv().await.unwrap() {
+            RhizMessage::AcceptedRoom(Some(id), usr) => {
+              assert_eq!(usr, local.user);
+              break id;
+            }
+            RhizMessage::HasRoute(_) | RhizMessage::AcceptedRoom(None, _) => continue,
+            msg => panic!("expected an accepted room but got '{:?}'", msg),
+          }
+        };
+        let _tunnel = peer.tunnel(&mock.profile, &id, &local.user).await.unwrap();
+        local.events.wait_for("new-room").await;
+        assert!(
+          !local.events.names().iter().any(|name| name == "wants-room"),
+          "\nthe local user requested the room, so it must not be asked"
+        );
+      })
+      .await;
+  }
+
+  /// Tests if a room request of a member of a joined group is marked as such
+  #[tokio::test]
+  async fn ctrl_group_member_request() {
+    init();
+    let _control = CONTROL.lock().await;
+    let mock = MockRhizome::spawn().await.unwrap();
+    let local = Local::new();
+    let mut peer = MockClient::connect(&mock.profile).await.unwrap();
+    set_policy(&peer.user, RoomPolicy::Ask).await;
+
+    let group = GroupId::random();
+    let invitation = GroupPacket::Members {
+      group,
+      name: "team".to_string(),
+      members: Vec::new(),
+    };
+    local
+      .net
+      .groups
+      .handle(invitation, &peer.user, &*local.events);
+    assert!(local.net.groups.join(&group, &*local.events).is_some());
+    // the peer links the group itself, so the local user does not request the link
+    while local.net.groups.missing_links().await.try_recv().is_ok() {}
+
+    local
+      .run(&mock, async {
+        peer
+          .send(EmbMessage::Room(local.user.clone()))
+          .await
+          .unwrap();
+        let request = local.events.wait_for("wants-room").await;
+        assert_eq!(
+          request["group_member"],
+          json!(true),
+          "\nthe request of a group member has to be marked"
+        );
+        assert_eq!(request["identifier"]["bs58"], json!(bs58(&peer.user)));
+
+        send_answer(bs58(&peer.user), false, &local.net, &local.rc)
+          .await
+          .unwrap();
+        assert!(matches!(
+          peer.recv().await.unwrap(),
+          RhizMessage::HasRoute(_)
+        ));
+        assert!(matches!(
+          peer.recv().await.unwrap(),
+          RhizMessage::AcceptedRoom(None, _)
+        ));
+      })
+      .await;
+  }
+
+  /// Tests an accepted room request up to chat messages in both directions of the tunnel
+  #[tokio::test]
+  async fn ctrl_room_to_chat() {
+    init();
+    let _control = CONTROL.lock().await;
+    let mock = MockRhizome::spawn().await.unwrap();
+    let local = Local::new();
+    let mut peer = MockClient::connect(&mock.profile).await.unwrap();
+
+    local
+      .run(&mock, async {
+        let (id, mut tunnel) = local.accept_room(&mock, &mut peer, 0).await;
+
+        let room_id = bs58::encode(&id.0).into_string();
+        let room = local.events.wait_for("new-room").await;
+        assert_eq!(
+          room,
+          json!({ "room_id": room_id, "peer_id": bs58(&peer.user) })
+        );
+
+        let chat = ChatPacket::new("hello from the peer".to_string());
+        Packet::Chat(chat.clone())
+          .send_with(&mut tunnel)
+          .await
+          .unwrap();
+        let received = local
+          .events
+          .wait_for(&format!("message_recieved_{}", room_id))
+          .await;
+        assert_eq!(received["id"], json!(chat.id));
+
+        let sent = local.events.dispatch(
+          &format!("send_message_{}", room_id),
+          r#"{ "Chat": "hello from local" }"#,
+        );
+        assert!(
+          sent,
+          "\nthe room has to listen for messages of the local user"
+        );
+
+        // the receipt for the message of the peer arrives first
+        let mut buf = Vec::new();
+        let chat = loop {
+          let packet = Packet::recv_with(&mut tunnel, &mut buf);
+          match tokio::time::timeout(RECV_TIMEOUT, packet).await {
+            Ok(Ok(Packet::Chat(chat))) => break chat,
+            Ok(Ok(_)) => continue,
+            res => panic!("expected the chat packet but got '{:?}'", res),
+          }
+        };
+        assert_eq!(chat.content, "hello from local");
+      })
+      .await;
+    assert!(local.net.link(&peer.user).is_some());
+  }
+
+  /// Tests if a new tunnel requested by the peer of a live room takes over that room
+  #[tokio::test]
+  async fn ctrl_take_over_room() {
+    init();
+    let _control = CONTROL.lock().await;
+    let mock = MockRhizome::spawn().await.unwrap();
+    let local = Local::new();
+    let mut peer = MockClient::connect(&mock.profile).await.unwrap();
+
+    local
+      .run(&mock, async {
+        // the first tunnel stays open on the end of the local user
+        let (id, _lost) = local.accept_room(&mock, &mut peer, 0).await;
+        local.events.wait_for("new-room").await;
+        let (_, mut tunnel) = local.accept_room(&mock, &mut peer, 1).await;
+
+        let room_id = bs58::encode(&id.0).into_string();
+        let room = local.events.wait_for("room-reconnected").await;
+        assert_eq!(
+          room,
+          json!({ "room_id": room_id, "peer_id": bs58(&peer.user) }),
+          "\nthe new tunnel has to continue the room of the old one"
+        );
+
+        let chat = ChatPacket::new("hello again".to_string());
+        Packet::Chat(chat.clone())
+          .send_with(&mut tunnel)
+          .await
+          .unwrap();
+        let received = local
+          .events
+          .wait_for(&format!("message_recieved_{}", room_id))
+          .await;
+        assert_eq!(received["id"], json!(chat.id));
+      })
+      .await;
+
+    assert_eq!(local.net.chats.lock().unwrap().len(), 1);
+    assert!(local.net.link(&peer.user).is_some());
+    assert!(
+      !local.events.names().contains(&"room-closed".to_string()),
+      "\nthe loop of the old tunnel must not close the room it handed over"
+    );
+  }
+}
@@ -1,20 +1,49 @@
 use std::collections::HashMap;
-use std::sync::Mutex;
+use std::io::{self, ErrorKind};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 
 use smoke::messages::RoomId;
 use smoke::User;
-use tauri::EventHandler;
 
-use tokio::sync::oneshot;
+use tokio::sync::{mpsc, oneshot};
 
+pub mod blocks;
 pub mod ctrl_chnl;
+pub mod frontend;
+pub mod group;
 mod holepunch;
+#[cfg(test)]
+mod mock_rhizome;
+pub mod outbox;
 mod p2p_tunl;
+pub mod reconnect;
+pub mod rotation;
+
+pub use frontend::Frontend;
+use frontend::Unlisten;
+use group::Groups;
+use p2p_tunl::packet::{FilePacket, Packet};
+use p2p_tunl::transfer::{self, FileId};
+use p2p_tunl::CloseReason;
+use reconnect::Reconnects;
 
 type ConnectionMap = HashMap<RoomId, Connection>;
 pub struct Connection {
-  pub send_handle: EventHandler,
+  pub peer: User,
+  /** Sends packets through the tunnel without going through the frontend */
+  pub packets: mpsc::Sender<Packet>,
+  pub send_handle: Unlisten,
   pub recv_handle: oneshot::Sender<()>,
+  /** Tells this tunnel apart from the other tunnels the room had or will have */
+  pub generation: u64,
+}
+
+#[derive(Clone, serde::Serialize)]
+struct RoomClosedPayload {
+  room_id: String,
+  #[serde(flatten)]
+  reason: CloseReason,
 }
 
 pub enum RRState {
@@ -23,8 +52,54 @@ pub enum RRState {
 }
 
 pub struct Networking {
-  pub chats: Mutex<ConnectionMap>,
+  /** Shared with the p2p loops so they can remove their room once they end */
+  pub chats: Arc<Mutex<ConnectionMap>>,
   pub pending: Mutex<HashMap<User, RRState>>,
+  pub groups: Groups,
+  pub reconnects: Reconnects,
+}
+
+impl Networking {
+  /// Closes the room `id`, the peer is told about it and the end of the room
+  /// is reported using the "room-closed" event
+  ///
+  /// Returns `false` if there is no room `id`
+  pub fn close(&self, id: &RoomId, frontend: &dyn Frontend) -> bool {
+    let con = self.chats.lock().unwrap().remove(id);
+    match con {
+      Some(con) => {
+        (con.send_handle)();
+        // fails if the loop already ended, it reports that on its own
+        let _ = con.recv_handle.send(());
+        true
+      }
+      // a lost room has no loop that could report its end
+      None if self.reconnects.give_up_room(id) => {
+        emit_closed(frontend, "room-closed", id, CloseReason::Local);
+        true
+      }
+      None => false,
+    }
+  }
+
+  /// Returns a sender for packets to `peer` if there is a P2P tunnel to them
+  pub fn link(&self, peer: &User) -> Option<mpsc::Sender<Packet>> {
+    let chats = self.chats.lock().unwrap();
+    chats
+      .values()
+      .find(|con| con.peer == *peer)
+      .map(|con| con.packets.clone())
+  }
+}
+
+/// Tells the frontend that the room `id` ended because of `reason`
+/// using the event `event` ("room-closed" or "room-reconnecting")
+pub(crate) fn emit_closed(frontend: &dyn Frontend, event: &str, id: &RoomId, reason: CloseReason) {
+  let payload = RoomClosedPayload {
+    room_id: bs58::encode(&id.0).into_string(),
+    reason,
+  };
+  frontend.emit(event, payload);
 }
 
 #[tauri::command]
@@ -35,3 +110,73 @@ pub fn chat_exists(state: tauri::State<'_, Networking>, id: RoomId) -> bool {
     Err(_) => false,
   }
 }
+
+/// Closes the room `id` and tells its peer about it
+///
+/// Returns `false` if there is no room `id`
+#[tauri::command]
+pub fn close_room(window: tauri::Window, state: tauri::State<'_, Networking>, id: RoomId) -> bool {
+  state.close(&id, &window)
+}
+
+/// Offers the file at `path` to the peer of the room `id`
+///
+/// Returns the id of the transfer, its progress is reported using the "file_transfer_<room id>" event.
+///
+/// # Errors
+/// This function will return:</br>
+/// [ErrorKind::NotConnected] if there is no room `id`</br>
+/// Any [io::Error] from reading the file
+#[tauri::command(async)]
+pub async fn send_file(
+  state: tauri::State<'_, Networking>,
+  id: RoomId,
+  path: PathBuf,
+) -> tauri::Result<String> {
+  let offer = tokio::task::spawn_blocking(move || transfer::offer(path)).await??;
+  let file = offer.id.to_string();
+  send_file_packet(&state, &id, FilePacket::Offer(offer)).await?;
+  Ok(file)
+}
+
+/// Accepts or rejects the file `file` offered by the peer of the room `id`
+///
+/// # Errors
+/// This function will return:</br>
+/// [ErrorKind::InvalidInput] if `file` is malformed</br>
+/// [ErrorKind::NotConnected] if there is no room `id`
+#[tauri::command(async)]
+pub async fn answer_file(
+  state: tauri::State<'_, Networking>,
+  id: RoomId,
+  file: String,
+  accepted: bool,
+) -> tauri::Result<()> {
+  let file: FileId = file.parse()?;
+  let packet = if accepted {
+    // the offset of partial downloads is filled in by the p2p loop
+    FilePacket::Accept {
+      id: file,
+      offset: 0,
+    }
+  } else {
+    FilePacket::Reject { id: file }
+  };
+
+  send_file_packet(&state, &id, packet).await?;
+  Ok(())
+}
+
+async fn send_file_packet(net: &Networking, id: &RoomId, packet: FilePacket) -> io::Result<()> {
+  let link = net
+    .chats
+    .lock()
+    .unwrap()
+    .get(id)
+    .map(|con| con.packets.clone());
+  let link = link.ok_or_else(|| io::Error::new(ErrorKind::NotConnected, "unknown room"))?;
+  link
+    .send(Packet::File(packet))
+    .await
+    .map_err(|_| io::Error::new(ErrorKind::NotConnected, "room was closed"))
+}
@@ -0,0 +1,131 @@
+use std::borrow::Cow;
+use std::io;
+
+use serde::Serialize;
+use smoke::User;
+use tauri::Window;
+use tokio::sync::mpsc;
+
+use crate::data::sqlite::{outbox, try_exec};
+use crate::data::UserIdentifier;
+use crate::history::msg::{MessageState, MsgId};
+
+use super::p2p_tunl::packet::{ChatPacket, Packet};
+use super::{Frontend, Networking};
+
+/// Name of the event that reports the [MessageState] of chat messages sent by the local user
+pub const MESSAGE_STATE: &str = "message-state";
+
+#[derive(Clone, Serialize)]
+struct MessageStatePayload<'a> {
+  peer: &'a str,
+  id: &'a MsgId,
+  state: MessageState,
+}
+
+/// Tells the frontend that the message `id` to `peer` reached `state`
+pub fn emit_state(frontend: &dyn Frontend, peer: &UserIdentifier, id: &MsgId, state: MessageState) {
+  let payload = MessageStatePayload {
+    peer: &peer.bs58,
+    id,
+    state,
+  };
+  frontend.emit(MESSAGE_STATE, payload);
+}
+
+/// Stores `chat` until there is a room with `peer` and reports it as [MessageState::Queued]
+///
+/// # Errors
+/// This function will return:</br>
+/// [io::ErrorKind::Other] if the message could not be stored
+pub async fn queue(
+  frontend: &dyn Frontend,
+  peer: &UserIdentifier<'_>,
+  chat: &ChatPacket,
+) -> io::Result<()> {
+  let msg = outbox::Queued {
+    id: chat.id,
+    content: chat.content.clone(),
+    time: chat.time,
+  };
+  let ident = peer.to_static();
+  try_exec(move |db| outbox::push(db, (&ident, &msg))).await?;
+  emit_state(frontend, peer, &chat.id, MessageState::Queued);
+  Ok(())
+}
+
+/// Hands every message waiting for `peer` to the P2P tunnel behind `packets`, oldest first
+///
+/// Messages stay in the outbox until the tunnel actually sent them,
+/// so nothing is lost if the room closes before the flush is done
+pub async fn flush(peer: &UserIdentifier<'_>, packets: &mpsc::Sender<Packet>) {
+  let ident = peer.to_static();
+  let queued = match try_exec(move |db| outbox::get_all(db, &ident)).await {
+    Ok(queued) => queued,
+    Err(err) => return log::warn!("failed to read outbox of '{}': '{}'", peer.bs58, err),
+  };
+
+  if !queued.is_empty() {
+    log::trace!(
+      "flushing {} queued messages to '{}'",
+      queued.len(),
+      peer.bs58
+    );
+  }
+  for msg in queued {
+    let chat = ChatPacket {
+      id: msg.id,
+      time: msg.time,
+      content: msg.content,
+    };
+    if packets.send(Packet::Chat(chat)).await.is_err() {
+      return log::warn!("room with '{}' closed while flushing the outbox", peer.bs58);
+    }
+  }
+}
+
+/// Sends the chat message `content` to the user identified by `bs58cert`
+///
+/// If there is no P2P tunnel to the user the message is kept in the outbox
+/// and sent as soon as a room with them is created.
+/// The progress of the message is reported using the "message-state" event.
+///
+/// # Errors
+/// This function will return:</br>
+/// [io::ErrorKind::InvalidData] if `bs58cert` is malformed</br>
+/// [io::ErrorKind::Other] if the message could not be queued
+#[tauri::command(async)]
+pub async fn queue_message(
+  window: Window,
+  bs58cert: String,
+  content: String,
+  net: tauri::State<'_, Networking>,
+) -> tauri::Result<ChatPacket> {
+  let peer = UserIdentifier {
+    bs58: Cow::Owned(bs58cert),
+  };
+  let usr: User = (&peer).try_into()?;
+  let chat = ChatPacket::new(content);
+
+  let sent = match net.link(&usr) {
+    Some(link) => link.send(Packet::Chat(chat.clone())).await.is_ok(),
+    None => false,
+  };
+  if !sent {
+    queue(&window, &peer, &chat).await?;
+  }
+
+  Ok(chat)
+}
+
+/// Returns the messages waiting for a room with the user identified by `bs58cert`, oldest first
+#[tauri::command(async)]
+pub async fn get_outbox(bs58cert: String) -> tauri::Result<Vec<outbox::Queued>> {
+  let peer = UserIdentifier {
+    bs58: Cow::Owned(bs58cert),
+  };
+
+  try_exec(move |db| outbox::get_all(db, &peer))
+    .await
+    .map_err(tauri::Error::Io)
+}
@@ -1,5 +1,10 @@
 mod p2p_loop;
+pub mod packet;
 mod resolver;
-pub use p2p_loop::p2p_loop;
+pub use p2p_loop::{p2p_loop, CloseReason};
+pub use packet::Outgoing;
 pub mod signal;
 pub mod tls_kcp; // todo : put in nicer format
+pub mod transfer;
+mod typing;
+mod verifier;
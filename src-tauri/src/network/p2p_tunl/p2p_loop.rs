@@ -1,9 +1,7 @@
 use std::{io, time::Duration};
 
-use smoke::{
-  messages::{Drain, Source},
-  Signal,
-};
+use serde::Serialize;
+use smoke::{Signal, User};
 use tokio::{
   io::{AsyncRead, AsyncWrite, BufReader},
   select,
@@ -12,86 +10,238 @@ use tokio::{
   time::Instant,
 };
 
-use tauri::{AppHandle, Window};
-
 use crate::{
   data::{
-    sqlite::{exec, user::get},
-    IdentifiedUserInfo, UserIdentifier,
+    settings::SETTINGS,
+    sqlite::{exec, message, outbox, receipt, try_exec, user::get},
+    IdentifiedUserInfo, UserIdentifier, DOWNLOADS,
   },
-  network::p2p_tunl,
+  history::msg::{MessageState, Msg, Text, LOCAL_SENDER},
+  network::{group::Groups, outbox::emit_state, p2p_tunl, rotation, Frontend},
 };
 
+use super::packet::{ChatPacket, Packet, ReceiptPacket};
+use super::transfer::Transfers;
+use super::typing::{Indicator, Throttle};
+
+/// Why a p2p loop ended, payload of the `room-closed` event
+#[derive(Clone, Serialize, Debug, PartialEq, Eq)]
+#[serde(tag = "reason", rename_all = "lowercase")]
+pub enum CloseReason {
+  /// The local user closed the room
+  Local,
+  /// The peer closed the room
+  Remote,
+  /// Nothing was received from the peer for too long
+  Timeout,
+  /// Reading from or writing to the tunnel failed
+  Error { message: String },
+}
+
 pub struct EventNames {
   pub msg_recv: String,
+  pub msg_sent: String,
+  pub remojis: String,
   pub usr_name: String,
 }
 
 pub async fn p2p_loop<'a, T>(
   emit_identity: &str,
   peer_ident: UserIdentifier<'a>,
-  spawn_window: &Window,
-  app_handle: &AppHandle,
+  frontend: &dyn Frontend,
+  groups: &Groups,
   stream: &mut BufReader<T>,
   rx: &mut oneshot::Receiver<()>,
-  msg_rx: &mut Receiver<Signal>,
-) -> Result<(), io::Error>
+  msg_rx: &mut Receiver<Packet>,
+) -> Result<CloseReason, io::Error>
 where
   T: AsyncRead + AsyncWrite + Unpin,
 {
   let msg_recv = format!("message_recieved_{}", emit_identity);
+  let msg_sent = format!("message_sent_{}", emit_identity);
+  let remojis = format!("remojis_{}", emit_identity);
   let usr_name = format!("usr_name_{}", peer_ident.bs58);
-  let events = EventNames { msg_recv, usr_name };
+  let events = EventNames {
+    msg_recv,
+    msg_sent,
+    remojis,
+    usr_name,
+  };
 
-  let info = exec(get, &peer_ident);
+  let peer: User = (&peer_ident).try_into()?;
+  let ident = peer_ident.to_static();
+  let info = exec(move |db| get(db, &ident)).await;
 
-  let mut msg_from = format!("Message from {}", info.username);
+  let mut msg_from = format!("Message from {}", info.display_name());
 
   let mut usr_status_cache = IdentifiedUserInfo {
     identifier: peer_ident,
     info,
   };
 
-  let mut ser_buf = [0u8; smoke::messages::signal::MAX_SIGNAL_BUF_SIZE];
-  let mut de_buf = Vec::with_capacity(smoke::messages::signal::MAX_SIGNAL_BUF_SIZE);
+  let mut de_buf = Vec::new();
+  let files = format!("file_transfer_{}", emit_identity);
+  let (mut transfers, mut verified) = Transfers::new(files, DOWNLOADS.clone());
+  let mut throttle = Throttle::default();
+  let mut typing = Indicator::default();
 
   // Anonymous function to avoid redundant code and have the seconds controlled in a single space
   let kap_timeout = || Instant::now() + Duration::from_secs(20);
   let mut next_kap = kap_timeout();
+  // The peer sends keep alives just like we do, so missing a few of them means the link is dead
+  let peer_timeout = || Instant::now() + Duration::from_secs(60);
+  let mut peer_deadline = peer_timeout();
   loop {
+    let typing_expiry = typing.deadline();
     select! {
-      msg = stream.read_message_cancelable(&mut de_buf) => {
+      msg = Packet::recv_with(stream, &mut de_buf) => {
         let msg = msg?;
-        next_kap = kap_timeout();
+        peer_deadline = peer_timeout();
         log::trace!("Received message: {:?} in {}", msg, emit_identity);
-        if let Err(err) = p2p_tunl::signal::handle_signal(
-          &msg,
-          spawn_window,
-          app_handle,
-          &events,
-          &mut msg_from,
-          &mut usr_status_cache,
-        )
-        .await
-        {
-          log::warn!("failed to handle signal: '{:?}' with error: '{}'", msg, err);
+        match msg {
+          Packet::Group(packet) => groups.handle(packet, &peer, frontend),
+          Packet::File(packet) => {
+            transfers.handle(packet, frontend, usr_status_cache.info.display_name()).await
+          }
+          Packet::Typing(started) => {
+            typing.update(started, Instant::now(), frontend, &events.msg_recv)
+          }
+          Packet::Close => {
+            log::trace!("p2ploop {} closed by peer", emit_identity);
+            return Ok(CloseReason::Remote);
+          }
+          Packet::Rotation(notice) => rotation::handle(&peer, &notice, frontend).await,
+          msg => {
+            if let Packet::Chat(_) = msg {
+              // sending a message ends typing it
+              typing.update(false, Instant::now(), frontend, &events.msg_recv);
+            }
+            if let Err(err) = p2p_tunl::signal::handle_signal(
+              &msg,
+              frontend,
+              &events,
+              &mut msg_from,
+              &mut usr_status_cache,
+            )
+            .await
+            {
+              log::warn!("failed to handle signal: '{:?}' with error: '{}'", msg, err);
+            } else if let Packet::Chat(chat) = msg {
+              Packet::Receipt(ReceiptPacket::Delivered(chat.id)).send_with(stream).await?;
+            }
+          }
         }
       },
       Ok(_) = &mut *rx => {
         log::trace!("p2ploop {} closed by handle", emit_identity);
-        return Ok(())
+        // the room is gone locally either way, the peer will notice by timeout if this fails
+        if let Err(err) = Packet::Close.send_with(stream).await {
+          log::debug!("failed to tell peer about closing {}: '{}'", emit_identity, err);
+        }
+        return Ok(CloseReason::Local)
+      },
+      _ = tokio::time::sleep_until(peer_deadline) => {
+        log::warn!("p2ploop {} timed out waiting for the peer", emit_identity);
+        return Ok(CloseReason::Timeout)
       },
       _ = tokio::time::sleep_until(next_kap) => {
-        let msg = Signal::Kap;
+        let msg = Packet::Signal(Signal::Kap);
         next_kap = kap_timeout();
         log::trace!("Sending message: {:?} in {}", msg, emit_identity);
-        msg.serialize_to(stream, &mut ser_buf).expect("unable to serialize kap message").await?
+        msg.send_with(stream).await?
       }
-      Some(msg) = msg_rx.recv() => {
+      Some(mut msg) = msg_rx.recv() => {
         next_kap = kap_timeout();
+        match &mut msg {
+          Packet::File(packet) => {
+            if let Err(err) = transfers.prepare(packet).await {
+              log::warn!("not sending file packet in {}: '{}'", emit_identity, err);
+              continue;
+            }
+          }
+          Packet::Receipt(ReceiptPacket::Read(_)) if !SETTINGS.read().unwrap().read_receipts => {
+            log::trace!("read receipts are disabled, not sending: {:?}", msg);
+            continue;
+          }
+          Packet::Typing(started) if !throttle.pass(*started, Instant::now()) => continue,
+          _ => (),
+        }
         log::trace!("Sending message: {:?} in {}", msg, emit_identity);
-        msg.serialize_to(stream, &mut ser_buf).map_err(|err| io::Error::new(io::ErrorKind::Other, err))?.await?
+        msg.send_with(stream).await?;
+        let peer = &usr_status_cache.identifier;
+        match msg {
+          Packet::Chat(chat) => {
+            throttle.reset();
+            persist_sent(frontend, &events, peer, chat).await
+          }
+          Packet::React(react) => {
+            let res =
+              p2p_tunl::signal::react_as(frontend, &events, peer, LOCAL_SENDER, &react).await;
+            if let Err(err) = res {
+              log::warn!("failed to persist sent reaction in {}: '{}'", emit_identity, err);
+            }
+          }
+          Packet::Rotation(_) => {
+            // the tunnel is authenticated using the replaced identity
+            log::trace!("p2ploop {} closed after rotating the identity", emit_identity);
+            Packet::Close.send_with(stream).await?;
+            return Ok(CloseReason::Local);
+          }
+          Packet::Signal(_)
+          | Packet::Group(_)
+          | Packet::File(_)
+          | Packet::Receipt(_)
+          | Packet::Typing(_)
+          | Packet::Close => (),
+        }
+      },
+      Some(chunk) = transfers.next_chunk(frontend), if transfers.uploading() => {
+        next_kap = kap_timeout();
+        Packet::File(chunk).send_with(stream).await?;
+      },
+      Some(res) = verified.recv() => transfers.report(frontend, res),
+      _ = tokio::time::sleep_until(typing_expiry.unwrap_or(next_kap)),
+        if typing_expiry.is_some() => {
+        log::trace!("typing indicator expired in {}", emit_identity);
+        typing.update(false, Instant::now(), frontend, &events.msg_recv);
       },
     }
   }
 }
+
+/// Stores a chat message sent by the local user in the history,
+/// takes it out of the outbox and tells the frontend about its id and send time
+async fn persist_sent(
+  frontend: &dyn Frontend,
+  events: &EventNames,
+  peer: &UserIdentifier<'_>,
+  chat: ChatPacket,
+) {
+  frontend.emit(&events.msg_sent, &chat);
+  emit_state(frontend, peer, &chat.id, MessageState::Sent);
+  let id = chat.id;
+  if let Err(err) = try_exec(move |db| outbox::remove(db, &id)).await {
+    log::warn!("failed to remove sent message from outbox: '{}'", err);
+  }
+
+  let text = Msg::Text(Text {
+    id: chat.id,
+    sender: LOCAL_SENDER,
+    content: chat.content,
+    time: chat.time,
+    remojis: Vec::new(),
+  });
+  let ident = peer.to_static();
+  let persisted = try_exec(move |db| {
+    message::insert(db, (&ident, &text))?;
+    receipt::advance(db, (&ident, &id, MessageState::Sent))
+  })
+  .await;
+  if let Err(err) = persisted {
+    log::warn!(
+      "failed to persist sent message for '{}': '{}'",
+      peer.bs58,
+      err
+    );
+  }
+}
@@ -0,0 +1,322 @@
+use std::{
+  io::{self, ErrorKind},
+  path::PathBuf,
+};
+
+use serde::{Deserialize, Serialize};
+use smoke::{Signal, User};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::data::cert_gen::RotationNotice;
+use crate::history::msg::{unix_millis, MessageState, MsgId};
+use crate::network::group::GroupId;
+
+use super::transfer::FileId;
+
+/// Largest COBS frame (including the terminating zero) accepted from a peer
+pub const MAX_PACKET_SIZE: usize = 64 * 1024;
+
+/// Everything that is sent through a P2P tunnel.
+///
+/// Extends the [Signal] protocol by messages that only emberry clients understand.
+/// Packets are serialized using postcard and framed using COBS,
+/// just like the certificate sent to rhizome.
+/// There is no fallback for clients that only speak plain [Signal]s, both ends of a tunnel have to send packets.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Packet {
+  /// Plain smoke signal (keep alive, username, ...)
+  Signal(Signal),
+  /// Chat message with its unique id and send time
+  Chat(ChatPacket),
+  /// Adds or removes a reaction of the sender to a message
+  React(ReactPacket),
+  /// Membership changes and messages of a group room
+  Group(GroupPacket),
+  /// Offer, answer or part of a file transfer
+  File(FilePacket),
+  /// Acknowledges a chat message the receiver of this packet wrote
+  Receipt(ReceiptPacket),
+  /// The sender started (`true`) or stopped (`false`) typing
+  Typing(bool),
+  /// The sender closed the room and stops reading from the tunnel
+  Close,
+  /// The sender replaced its identity, followed by [Packet::Close]
+  Rotation(RotationNotice),
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ChatPacket {
+  pub id: MsgId,
+  /** Time at which the message was send (UTC unix time in milliseconds) */
+  pub time: u64,
+  pub content: String,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ReactPacket {
+  /** ID of the message that is reacted to */
+  pub target: MsgId,
+  pub emoji: String,
+  /** `true` to add the reaction, `false` to remove it */
+  pub add: bool,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug)]
+pub enum ReceiptPacket {
+  /// The sender stored the message in its history
+  Delivered(MsgId),
+  /// The user of the sender has seen the message
+  Read(MsgId),
+}
+
+/// Part of a group room that is sent to each member through its own tunnel
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum GroupPacket {
+  /// Announces the name and members of `group`,
+  /// `members` lists everyone except the sender and the receiver
+  Members {
+    group: GroupId,
+    name: String,
+    members: Vec<User>,
+  },
+  /// The sender left `group`
+  Leave { group: GroupId },
+  /// Chat message to all members of `group`
+  Chat { group: GroupId, chat: ChatPacket },
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct FileOffer {
+  pub id: FileId,
+  /** File name without any directories */
+  pub name: String,
+  /** Size of the file in bytes */
+  pub size: u64,
+  /** SHA-256 of the whole file, checked by the receiver once all chunks arrived */
+  pub sha256: [u8; 32],
+  /** Location of the offered file, only known to the sender and never sent */
+  #[serde(skip)]
+  pub path: Option<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum FilePacket {
+  /// Asks the receiver if it wants the file
+  Offer(FileOffer),
+  /// The receiver wants the offered file starting at byte `offset`,
+  /// which is the size of its partial download if there is one
+  Accept { id: FileId, offset: u64 },
+  /// The receiver does not want the offered file
+  Reject { id: FileId },
+  /// Part of the file starting at byte `offset`, chunks are sent in order
+  Chunk {
+    id: FileId,
+    offset: u64,
+    data: Vec<u8>,
+  },
+}
+
+/// Messages the frontend wants to send through a P2P tunnel,
+/// payload of the `send_message_<id>` event
+#[derive(Deserialize, Debug)]
+pub enum Outgoing {
+  Chat(String),
+  Username(String),
+  React(ReactPacket),
+  /// The local user has seen the message of the peer with this id
+  Read(MsgId),
+  /// The local user started (`true`) or stopped (`false`) typing
+  Typing(bool),
+}
+
+impl From<Outgoing> for Packet {
+  fn from(msg: Outgoing) -> Self {
+    match msg {
+      Outgoing::Chat(content) => Packet::Chat(ChatPacket::new(content)),
+      Outgoing::Username(name) => Packet::Signal(Signal::Username(name)),
+      Outgoing::React(react) => Packet::React(react),
+      Outgoing::Read(id) => Packet::Receipt(ReceiptPacket::Read(id)),
+      Outgoing::Typing(typing) => Packet::Typing(typing),
+    }
+  }
+}
+
+impl ChatPacket {
+  /// Creates a new chat message sent right now with a fresh [MsgId]
+  pub fn new(content: String) -> ChatPacket {
+    let time = unix_millis();
+    ChatPacket {
+      id: MsgId::new(time),
+      time,
+      content,
+    }
+  }
+}
+
+impl ReceiptPacket {
+  /// ID of the acknowledged message
+  pub fn target(&self) -> &MsgId {
+    match self {
+      ReceiptPacket::Delivered(id) | ReceiptPacket::Read(id) => id,
+    }
+  }
+
+  /// State the acknowledged message reached
+  pub fn state(&self) -> MessageState {
+    match self {
+      ReceiptPacket::Delivered(_) => MessageState::Delivered,
+      ReceiptPacket::Read(_) => MessageState::Read,
+    }
+  }
+}
+
+impl Packet {
+  /// Serializes `self` into a single COBS frame and writes it to `stream`
+  ///
+  /// # Errors
+  /// This function will return:</br>
+  /// [ErrorKind::InvalidData] if `self` cannot be serialized or exceeds [MAX_PACKET_SIZE]</br>
+  /// Any [io::Error] from writing to `stream`
+  pub async fn send_with<W>(&self, stream: &mut W) -> io::Result<()>
+  where
+    W: AsyncWrite + Unpin,
+  {
+    let frame = postcard::to_stdvec_cobs(self).map_err(|err| {
+      log::error!("Error serializing packet: '{}'", err);
+      io::Error::new(ErrorKind::InvalidData, "cannot serialize packet")
+    })?;
+    if frame.len() > MAX_PACKET_SIZE {
+      return Err(io::Error::new(ErrorKind::InvalidData, "packet too large"));
+    }
+
+    stream.write_all(&frame).await?;
+    stream.flush().await
+  }
+
+  /// Reads a single COBS frame from `stream` and deserializes it
+  ///
+  /// `buf` holds partially read frames and has to be reused for every call on the same `stream`.
+  /// Therefore this function is cancel safe as long as `buf` is kept.
+  ///
+  /// # Errors
+  /// This function will return:</br>
+  /// [ErrorKind::UnexpectedEof] if `stream` is closed</br>
+  /// [ErrorKind::InvalidData] if the frame exceeds [MAX_PACKET_SIZE] or is malformed</br>
+  /// Any [io::Error] from reading `stream`
+  pub async fn recv_with<R>(stream: &mut R, buf: &mut Vec<u8>) -> io::Result<Packet>
+  where
+    R: AsyncBufRead + Unpin,
+  {
+    loop {
+      let limit = MAX_PACKET_SIZE.saturating_sub(buf.len()) as u64;
+      let read = (&mut *stream).take(limit).read_until(0, buf).await?;
+
+      if buf.last() == Some(&0) {
+        let packet = postcard::from_bytes_cobs(&mut buf[..]).map_err(|err| {
+          log::error!("Error deserializing packet: '{}'", err);
+          io::Error::new(ErrorKind::InvalidData, "malformed packet")
+        });
+        buf.clear();
+        return packet;
+      }
+
+      if buf.len() >= MAX_PACKET_SIZE {
+        buf.clear();
+        return Err(io::Error::new(ErrorKind::InvalidData, "packet too large"));
+      }
+
+      if read == 0 {
+        return Err(io::Error::new(
+          ErrorKind::UnexpectedEof,
+          "p2p tunnel closed",
+        ));
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use tokio::io::BufReader;
+
+  use super::*;
+
+  fn sample_chat(content: &str) -> ChatPacket {
+    ChatPacket::new(content.to_string())
+  }
+
+  /// Tests if multiple packets written to a stream are read back in order
+  #[tokio::test]
+  async fn round_trip() {
+    let (mut client, server) = tokio::io::duplex(MAX_PACKET_SIZE);
+    let mut server = BufReader::new(server);
+    let first = sample_chat("first");
+    let second = sample_chat("second");
+
+    Packet::Chat(first.clone())
+      .send_with(&mut client)
+      .await
+      .unwrap();
+    Packet::Chat(second.clone())
+      .send_with(&mut client)
+      .await
+      .unwrap();
+
+    let mut buf = Vec::new();
+    for expected in [first, second] {
+      match Packet::recv_with(&mut server, &mut buf).await.unwrap() {
+        Packet::Chat(chat) => {
+          assert_eq!(chat.id, expected.id);
+          assert_eq!(chat.content, expected.content);
+        }
+        packet => panic!("expected chat packet but got '{:?}'", packet),
+      }
+    }
+  }
+
+  /// Tests if the frontend payloads of the `send_message_<id>` event are understood
+  #[test]
+  fn outgoing_json() {
+    let chat: Outgoing = serde_json::from_str(r#"{ "Chat": "hello" }"#).unwrap();
+    assert!(matches!(chat, Outgoing::Chat(content) if content == "hello"));
+
+    let name: Outgoing = serde_json::from_str(r#"{ "Username": "emberry" }"#).unwrap();
+    assert!(matches!(name, Outgoing::Username(name) if name == "emberry"));
+
+    let id = MsgId::new(unix_millis());
+    let json = format!(
+      r#"{{ "React": {{ "target": "{}", "emoji": "😃", "add": true }} }}"#,
+      id
+    );
+    let react: Outgoing = serde_json::from_str(&json).unwrap();
+    assert!(matches!(react, Outgoing::React(react) if react.target == id && react.add));
+  }
+
+  /// Tests if a closed stream is reported as [ErrorKind::UnexpectedEof]
+  #[tokio::test]
+  async fn closed_stream() {
+    let (client, server) = tokio::io::duplex(MAX_PACKET_SIZE);
+    let mut server = BufReader::new(server);
+    drop(client);
+
+    let mut buf = Vec::new();
+    let err = Packet::recv_with(&mut server, &mut buf).await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+  }
+
+  /// Tests if frames longer then [MAX_PACKET_SIZE] are rejected
+  #[tokio::test]
+  async fn oversized_frame() {
+    let (mut client, server) = tokio::io::duplex(MAX_PACKET_SIZE);
+    let mut server = BufReader::new(server);
+
+    tokio::spawn(async move {
+      let junk = vec![1u8; MAX_PACKET_SIZE + 1];
+      let _ = client.write_all(&junk).await;
+    });
+
+    let mut buf = Vec::new();
+    let err = Packet::recv_with(&mut server, &mut buf).await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+  }
+}
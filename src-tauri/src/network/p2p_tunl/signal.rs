@@ -1,18 +1,33 @@
-use std::{borrow::Borrow, io, sync::atomic::Ordering};
+use std::io;
 
 use crate::data::{
-  sqlite::{try_exec, user::upsert},
-  IdentifiedUserInfo,
+  sqlite::{message, reaction, receipt, try_exec, user::rename},
+  IdentifiedUserInfo, UserIdentifier,
 };
+use crate::history::msg::{Msg, MsgId, Remoji, Text, PEER_SENDER};
+use crate::network::outbox::emit_state;
+use crate::network::Frontend;
 
 use smoke::Signal;
-use tauri::{api::notification::Notification, AppHandle, Window};
 
 use super::p2p_loop::EventNames;
+use super::packet::{ChatPacket, Packet, ReactPacket, ReceiptPacket};
 
 #[derive(Clone, serde::Serialize)]
 struct MessageRecievedPayload<'a> {
   message: &'a Signal,
+  /** Only present for messages that are part of the chat history */
+  #[serde(skip_serializing_if = "Option::is_none")]
+  id: Option<MsgId>,
+  /** Only present for messages that are part of the chat history */
+  #[serde(skip_serializing_if = "Option::is_none")]
+  time: Option<u64>,
+}
+
+#[derive(Clone, serde::Serialize)]
+struct RemojisChangedPayload<'a> {
+  target: &'a MsgId,
+  remojis: Vec<Remoji>,
 }
 
 #[derive(Clone, serde::Serialize)]
@@ -21,53 +36,136 @@ struct UsernameChangedPayload<'a> {
 }
 
 pub async fn handle_signal(
-  signal: &Signal,
-  spawn_window: &Window,
-  app_handle: &AppHandle,
+  packet: &Packet,
+  frontend: &dyn Frontend,
   events: &EventNames,
   msg_from: &mut String,
   cache: &mut IdentifiedUserInfo<'_>,
 ) -> Result<(), io::Error> {
+  let signal = match packet {
+    Packet::Signal(signal) => signal,
+    Packet::Chat(chat) => return handle_chat(chat, frontend, events, msg_from, cache).await,
+    Packet::React(react) => {
+      return react_as(frontend, events, &cache.identifier, PEER_SENDER, react).await
+    }
+    // these packets need state that only the p2p loop has and are handled there
+    Packet::Group(_)
+    | Packet::File(_)
+    | Packet::Typing(_)
+    | Packet::Close
+    | Packet::Rotation(_) => return Ok(()),
+    Packet::Receipt(receipt) => return handle_receipt(receipt, frontend, &cache.identifier).await,
+  };
+
   match signal {
     Signal::Kap => (),
     Signal::Username(name) => {
       if &cache.info.username != name {
         cache.info.username = name.to_string();
-        let input = (cache.borrow(), |info: &IdentifiedUserInfo| {
-          *msg_from = format!("Message from {}", cache.info.username);
-          emit_username(spawn_window, &events.usr_name, &info.info.username)
-        });
-        try_exec(upsert, input)?;
+        let info = IdentifiedUserInfo {
+          identifier: cache.identifier.to_static(),
+          info: cache.info.clone(),
+        };
+        // a nickname given locally stays in place of the new username
+        cache.info = try_exec(move |db| rename(db, &info)).await?;
+        *msg_from = format!("Message from {}", cache.info.display_name());
+        frontend.emit(&events.usr_name, cache.info.display_name());
       }
     }
     Signal::Chat(text) => {
-      emit_msg(spawn_window, &events.msg_recv, signal);
-
-      /* Create a new notification for the message */
-      if !crate::FOCUS.load(Ordering::SeqCst) {
-        Notification::new(&app_handle.config().tauri.bundle.identifier)
-          .title(msg_from.as_str())
-          .body(text)
-          .show()
-          .expect("Failed to send desktop notification");
-      }
+      // a chat sent as a plain signal carries no id, so we assign one on arrival
+      let chat = ChatPacket::new(text.clone());
+      handle_chat(&chat, frontend, events, msg_from, cache).await?;
     }
-    _ => emit_msg(spawn_window, &events.msg_recv, signal),
+    _ => emit_msg(frontend, &events.msg_recv, signal, None),
   }
 
   Ok(())
 }
 
-#[inline]
-fn emit_username(window: &Window, event_name: &str, name: &str) {
-  if let Err(err) = window.emit(event_name, name) {
-    log::error!("Failed to emit event: '{}'", err);
+async fn handle_chat(
+  chat: &ChatPacket,
+  frontend: &dyn Frontend,
+  events: &EventNames,
+  msg_from: &str,
+  cache: &IdentifiedUserInfo<'_>,
+) -> Result<(), io::Error> {
+  let signal = Signal::Chat(chat.content.clone());
+  emit_msg(frontend, &events.msg_recv, &signal, Some(chat));
+
+  /* Create a new notification for the message */
+  frontend.notify(msg_from, Some(&chat.content));
+
+  let text = Msg::Text(Text {
+    id: chat.id,
+    sender: PEER_SENDER,
+    content: chat.content.clone(),
+    time: chat.time,
+    remojis: Vec::new(),
+  });
+  let peer = cache.identifier.to_static();
+  try_exec(move |db| message::insert(db, (&peer, &text))).await
+}
+
+/// Stores the state acknowledged by `receipt` and tells the frontend about it
+///
+/// Receipts for messages that were not sent to `peer` by the local user
+/// or that would move a message back to an earlier state are ignored
+async fn handle_receipt(
+  receipt: &ReceiptPacket,
+  frontend: &dyn Frontend,
+  peer: &UserIdentifier<'_>,
+) -> Result<(), io::Error> {
+  let (target, state) = (*receipt.target(), receipt.state());
+  let ident = peer.to_static();
+  if try_exec(move |db| receipt::advance(db, (&ident, &target, state))).await? {
+    emit_state(frontend, peer, &target, state);
+  }
+  Ok(())
+}
+
+/// Applies the reaction `react` of `sender` to the history with `peer`
+/// and tells the frontend about the new reaction counts of the target message
+///
+/// Reactions with invalid emojis or to messages that are not part of the history with `peer` are ignored
+pub async fn react_as(
+  frontend: &dyn Frontend,
+  events: &EventNames,
+  peer: &UserIdentifier<'_>,
+  sender: u8,
+  react: &ReactPacket,
+) -> Result<(), io::Error> {
+  if !Remoji::is_valid(&react.emoji) {
+    log::warn!("ignoring reaction with invalid emoji: '{:?}'", react.emoji);
+    return Ok(());
+  }
+
+  let (ident, target, emoji) = (peer.to_static(), react.target, react.emoji.clone());
+  let changed = if react.add {
+    try_exec(move |db| reaction::add(db, (&ident, &target, sender, emoji.as_str()))).await?
+  } else {
+    try_exec(move |db| reaction::remove(db, (&ident, &target, sender, emoji.as_str()))).await?
+  };
+
+  if changed {
+    let (ident, target) = (peer.to_static(), react.target);
+    let remojis = try_exec(move |db| reaction::get_remojis(db, (&ident, &target))).await?;
+    let payload = RemojisChangedPayload {
+      target: &react.target,
+      remojis,
+    };
+    frontend.emit(&events.remojis, payload);
   }
+
+  Ok(())
 }
 
 #[inline]
-fn emit_msg(window: &Window, event_name: &str, signal: &Signal) {
-  window
-    .emit(event_name, MessageRecievedPayload { message: signal })
-    .expect("Failed to emit event")
+fn emit_msg(frontend: &dyn Frontend, event_name: &str, signal: &Signal, chat: Option<&ChatPacket>) {
+  let payload = MessageRecievedPayload {
+    message: signal,
+    id: chat.map(|chat| chat.id),
+    time: chat.map(|chat| chat.time),
+  };
+  frontend.emit(event_name, payload)
 }
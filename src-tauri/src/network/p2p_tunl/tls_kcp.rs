@@ -4,40 +4,51 @@ use std::{
 };
 
 use super::resolver::ClientCertResolver;
+use super::verifier::PinnedCertVerifier;
 use crate::data::config::PEM_DATA;
-use once_cell::sync::Lazy;
-use rustls::{server::AllowAnyAuthenticatedClient, Certificate, ClientConfig, RootCertStore};
+use rustls::{
+  server::AllowAnyAuthenticatedClient, Certificate, ClientConfig, PrivateKey, RootCertStore,
+  ServerName,
+};
 use tokio_kcp::KcpStream;
 use tokio_rustls::{TlsAcceptor, TlsConnector, TlsStream};
 
-static CAC_RESOLVER: Lazy<Arc<ClientCertResolver>> = Lazy::new(cacr);
-
-fn cacr() -> Arc<ClientCertResolver> {
-  //                    we can unsafe unwrap here because we know that PEM_DATA is not None because the ctrl_chnl loop
-  //                    only starts if PEM_DATA is Some() (from which this func is called)
-  let (cert, key) = unsafe { &PEM_DATA.as_ref().unwrap_unchecked() };
-  Arc::new(ClientCertResolver::new(cert.clone(), key.clone()))
+/// Certificate and key of the local user,
+/// read for every tunnel as the identity can be rotated while the app is running
+fn identity() -> Result<(Certificate, PrivateKey), io::Error> {
+  match PEM_DATA.read().unwrap().as_ref() {
+    Some(identity) => Ok(identity.clone()),
+    None => Err(io::Error::new(
+      ErrorKind::NotFound,
+      "Identity needed to open a tunnel",
+    )),
+  }
 }
 
 pub async fn wrap_client(
   stream: KcpStream,
   peer_cert: &Certificate,
 ) -> Result<TlsStream<KcpStream>, io::Error> {
-  let mut root_store = RootCertStore::empty();
-
-  root_store.add(peer_cert).map_err(|err| {
-    log::error!("Error creating root store for peer_cert, Err: '{}'", err);
-    io::Error::new(ErrorKind::InvalidData, "Invalid peer cert")
-  })?;
+  wrap_client_as(stream, peer_cert, identity()?).await
+}
 
-  let cac_resolver = CAC_RESOLVER.clone();
+/// [wrap_client] using the certificate and key of `identity` instead of the ones of the local user
+pub async fn wrap_client_as(
+  stream: KcpStream,
+  peer_cert: &Certificate,
+  identity: (Certificate, PrivateKey),
+) -> Result<TlsStream<KcpStream>, io::Error> {
+  let (cert, key) = identity;
+  let cac_resolver = Arc::new(ClientCertResolver::new(cert, key));
+  let verifier = Arc::new(PinnedCertVerifier::new(peer_cert.clone()));
 
   let config = ClientConfig::builder()
     .with_safe_defaults()
-    .with_root_certificates(root_store)
+    .with_custom_certificate_verifier(verifier)
     .with_client_cert_resolver(cac_resolver);
 
-  let cert_name = "emberry_user".try_into().unwrap(); // FIXME get peername from certificate
+  // the peer is verified by its pinned certificate, the name is only needed for the handshake
+  let cert_name = ServerName::try_from("emberry_user").unwrap();
   let conn = TlsConnector::from(Arc::new(config));
 
   Ok(TlsStream::Client(conn.connect(cert_name, stream).await?))
@@ -46,6 +57,15 @@ pub async fn wrap_client(
 pub async fn wrap_server(
   stream: KcpStream,
   peer_cert: &Certificate,
+) -> Result<TlsStream<KcpStream>, io::Error> {
+  wrap_server_as(stream, peer_cert, identity()?).await
+}
+
+/// [wrap_server] using the certificate and key of `identity` instead of the ones of the local user
+pub async fn wrap_server_as(
+  stream: KcpStream,
+  peer_cert: &Certificate,
+  identity: (Certificate, PrivateKey),
 ) -> Result<TlsStream<KcpStream>, io::Error> {
   let mut client_cert_store = RootCertStore::empty();
   client_cert_store.add(peer_cert).map_err(|err| {
@@ -54,16 +74,12 @@ pub async fn wrap_server(
   })?;
 
   let client_cert_verifier = AllowAnyAuthenticatedClient::new(client_cert_store);
-  //                    we can unsafe unwrap here because we know that PEM_DATA is not None because the ctrl_chnl loop
-  //                    only starts if PEM_DATA is Some() (from which this func is called)
-  let (cert, key) = unsafe { &PEM_DATA.as_ref().unwrap_unchecked() };
+  let (cert, key) = identity;
 
   // Build TLS configuration.
   let tls_cfg = {
     // Load public certificate.
-    let certs = vec![cert.clone()];
-    // Load private key.
-    let key = key.clone();
+    let certs = vec![cert];
     // Do not use client certificate authentication.
     let cfg = rustls::ServerConfig::builder()
       .with_safe_defaults()
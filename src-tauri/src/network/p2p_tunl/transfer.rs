@@ -0,0 +1,625 @@
+//! Sending and receiving files through a P2P tunnel
+//!
+//! Files are offered with their size and SHA-256 and only sent once the peer accepted them.
+//! Received data is appended to a partial file named after the hash of the file,
+//! so accepting the same file again later resumes the download where it stopped.
+//! Chunks are sent by the p2p loop in between all other packets,
+//! which keeps chat and keep alives flowing during large transfers.
+
+use std::{
+  collections::{HashMap, VecDeque},
+  fmt, fs,
+  io::{self, ErrorKind, SeekFrom},
+  path::{Path, PathBuf},
+  str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{
+  fs::{File, OpenOptions},
+  io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+  sync::mpsc,
+};
+
+use crate::network::Frontend;
+
+use super::packet::{FileOffer, FilePacket};
+
+/// Largest amount of file data sent in a single [FilePacket::Chunk],
+/// keeps chunks well below [super::packet::MAX_PACKET_SIZE]
+pub const CHUNK_SIZE: usize = 16 * 1024;
+
+/// Result of checking a completed download, see [Transfers::report]
+pub type Verified = (FileId, io::Result<PathBuf>);
+
+/// Random identifier of a file transfer, shown to the frontend as bs58 string
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, Debug)]
+pub struct FileId(pub [u8; 16]);
+
+/// Payload of the `file_transfer_<id>` event
+#[derive(Clone, Serialize, Debug, PartialEq, Eq)]
+#[serde(tag = "state", rename_all = "lowercase")]
+pub enum FileEvent {
+  /// The peer offered a file
+  Offered {
+    id: String,
+    name: String,
+    size: u64,
+  },
+  /// `done` of `size` bytes were sent or received
+  Progress {
+    id: String,
+    done: u64,
+    size: u64,
+  },
+  /// The transfer is complete, `path` is only present for received files
+  Done {
+    id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<PathBuf>,
+  },
+  /// The peer does not want the offered file
+  Rejected {
+    id: String,
+  },
+  Failed {
+    id: String,
+    reason: String,
+  },
+}
+
+struct Download {
+  offer: FileOffer,
+  /** Partial file the data is appended to */
+  part: PathBuf,
+  file: File,
+  received: u64,
+}
+
+struct Upload {
+  id: FileId,
+  file: File,
+  /** Bytes handed to the tunnel, including the offset the receiver asked for */
+  sent: u64,
+  size: u64,
+  /** Data read for the next chunk, kept when reading is cancelled */
+  buf: Vec<u8>,
+}
+
+/// State of all file transfers of one P2P tunnel
+pub struct Transfers {
+  event: String,
+  /** Directory received files are stored in */
+  dir: PathBuf,
+  /** Files offered to the peer that were not answered yet */
+  offered: HashMap<FileId, FileOffer>,
+  /** Files offered by the peer that were not answered yet */
+  incoming: HashMap<FileId, FileOffer>,
+  downloads: HashMap<FileId, Download>,
+  /** Accepted files in the order they are sent in */
+  uploads: VecDeque<Upload>,
+  verified: mpsc::UnboundedSender<Verified>,
+}
+
+impl FileId {
+  /// Creates a new random [FileId]
+  pub fn random() -> FileId {
+    FileId(rand::random())
+  }
+}
+
+impl fmt::Display for FileId {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(&bs58::encode(&self.0).into_string())
+  }
+}
+
+impl FromStr for FileId {
+  type Err = io::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let mut id = [0u8; 16];
+    match bs58::decode(s).into(&mut id) {
+      Ok(16) => Ok(FileId(id)),
+      _ => Err(io::Error::new(
+        ErrorKind::InvalidInput,
+        format!("'{}' is not a valid file id", s),
+      )),
+    }
+  }
+}
+
+impl Download {
+  /// Appends the chunk `data` starting at byte `offset` to the partial file
+  ///
+  /// # Errors
+  /// This function will return:</br>
+  /// [ErrorKind::InvalidData] if the chunk does not continue the download or exceeds the offered size</br>
+  /// Any [io::Error] from writing the partial file
+  async fn append(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+    if offset != self.received || self.received + data.len() as u64 > self.offer.size {
+      return Err(io::Error::new(
+        ErrorKind::InvalidData,
+        "chunk does not continue the download",
+      ));
+    }
+
+    self.file.write_all(data).await?;
+    self.received += data.len() as u64;
+    if self.received == self.offer.size {
+      self.file.flush().await?;
+    }
+    Ok(())
+  }
+}
+
+impl Upload {
+  /// Opens the offered file and skips the first `offset` bytes the receiver already has
+  async fn open(offer: &FileOffer, offset: u64) -> io::Result<Upload> {
+    let path = match &offer.path {
+      Some(path) => path,
+      None => return Err(io::Error::new(ErrorKind::NotFound, "offer without a path")),
+    };
+    if offset > offer.size {
+      return Err(io::Error::new(
+        ErrorKind::InvalidInput,
+        "offset beyond the end of the file",
+      ));
+    }
+
+    let mut file = File::open(path).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    Ok(Upload {
+      id: offer.id,
+      file,
+      sent: offset,
+      size: offer.size,
+      buf: Vec::with_capacity(CHUNK_SIZE),
+    })
+  }
+
+  /// Reads from the file until a whole chunk or the rest of the file is buffered
+  ///
+  /// Cancel safe, everything read so far stays in the buffer.
+  async fn fill(&mut self) -> io::Result<()> {
+    loop {
+      let missing = self.size - self.sent - self.buf.len() as u64;
+      let want = missing.min((CHUNK_SIZE - self.buf.len()) as u64);
+      if want == 0 {
+        return Ok(());
+      }
+
+      if (&mut self.file).take(want).read_buf(&mut self.buf).await? == 0 {
+        return Err(io::Error::new(
+          ErrorKind::UnexpectedEof,
+          "file got smaller after it was offered",
+        ));
+      }
+    }
+  }
+}
+
+impl Transfers {
+  /// Creates the transfer state of a tunnel that reports using the frontend event `event`
+  /// and stores received files in `dir`
+  ///
+  /// Completed downloads are verified in the background,
+  /// the results are sent to the returned receiver and have to be handed to [Transfers::report].
+  pub fn new(event: String, dir: PathBuf) -> (Transfers, mpsc::UnboundedReceiver<Verified>) {
+    let (verified, rx) = mpsc::unbounded_channel();
+    let transfers = Transfers {
+      event,
+      dir,
+      offered: HashMap::new(),
+      incoming: HashMap::new(),
+      downloads: HashMap::new(),
+      uploads: VecDeque::new(),
+      verified,
+    };
+    (transfers, rx)
+  }
+
+  /// Returns `true` while accepted files were not sent completely
+  pub fn uploading(&self) -> bool {
+    !self.uploads.is_empty()
+  }
+
+  /// Updates the state for `packet` that is about to be sent by the local user
+  ///
+  /// Fills in the offset of accepted files that were partially downloaded before.
+  ///
+  /// # Errors
+  /// This function will return:</br>
+  /// [ErrorKind::NotFound] when answering an offer that does not exist</br>
+  /// [ErrorKind::AlreadyExists] when accepting a file that is being downloaded already</br>
+  /// [ErrorKind::InvalidInput] for offers without a path and chunks, which are created by [Transfers::next_chunk]</br>
+  /// Any [io::Error] from opening the partial download
+  pub async fn prepare(&mut self, packet: &mut FilePacket) -> io::Result<()> {
+    match packet {
+      FilePacket::Offer(offer) => {
+        if offer.path.is_none() {
+          return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "cannot offer a file without its path",
+          ));
+        }
+        self.offered.insert(offer.id, offer.clone());
+      }
+      FilePacket::Accept { id, offset } => {
+        let offer = self.incoming.remove(id).ok_or_else(not_offered)?;
+        if self
+          .downloads
+          .values()
+          .any(|download| download.offer.sha256 == offer.sha256)
+        {
+          return Err(io::Error::new(
+            ErrorKind::AlreadyExists,
+            "this file is being downloaded already",
+          ));
+        }
+
+        tokio::fs::create_dir_all(&self.dir).await?;
+        let sha256 = bs58::encode(&offer.sha256).into_string();
+        let part = self.dir.join(format!(".{}.part", sha256));
+        let file = OpenOptions::new()
+          .create(true)
+          .append(true)
+          .open(&part)
+          .await?;
+
+        let mut received = file.metadata().await?.len();
+        if received > offer.size {
+          file.set_len(0).await?;
+          received = 0;
+        }
+
+        *offset = received;
+        let download = Download {
+          offer,
+          part,
+          file,
+          received,
+        };
+        self.downloads.insert(*id, download);
+      }
+      FilePacket::Reject { id } => {
+        self.incoming.remove(id).ok_or_else(not_offered)?;
+      }
+      FilePacket::Chunk { .. } => {
+        return Err(io::Error::new(
+          ErrorKind::InvalidInput,
+          "chunks are only sent for accepted files",
+        ))
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Applies `packet` received from the peer called `peer_name` and tells the frontend about it
+  ///
+  /// Transfers that cannot continue are reported as failed.
+  pub async fn handle(&mut self, packet: FilePacket, frontend: &dyn Frontend, peer_name: &str) {
+    match packet {
+      FilePacket::Offer(mut offer) => {
+        offer.name = file_name(&offer.name);
+        let title = format!("{} wants to send you a file", peer_name);
+        frontend.notify(&title, Some(&offer.name));
+        self.emit(
+          frontend,
+          FileEvent::Offered {
+            id: offer.id.to_string(),
+            name: offer.name.clone(),
+            size: offer.size,
+          },
+        );
+        self.incoming.insert(offer.id, offer);
+      }
+      FilePacket::Accept { id, offset } => match self.offered.remove(&id) {
+        Some(offer) => match Upload::open(&offer, offset).await {
+          Ok(upload) => self.uploads.push_back(upload),
+          Err(err) => self.fail(frontend, &id, err),
+        },
+        None => log::warn!("peer accepted file '{}' that was not offered", id),
+      },
+      FilePacket::Reject { id } => {
+        if self.offered.remove(&id).is_some() {
+          let id = id.to_string();
+          self.emit(frontend, FileEvent::Rejected { id });
+        }
+      }
+      FilePacket::Chunk { id, offset, data } => self.receive(frontend, id, offset, &data).await,
+    }
+  }
+
+  async fn receive(&mut self, frontend: &dyn Frontend, id: FileId, offset: u64, data: &[u8]) {
+    let mut download = match self.downloads.remove(&id) {
+      Some(download) => download,
+      None => return log::warn!("received chunk of file '{}' that was not accepted", id),
+    };
+    if let Err(err) = download.append(offset, data).await {
+      return self.fail(frontend, &id, err);
+    }
+
+    let size = download.offer.size;
+    self.progress(frontend, &id, offset, download.received, size);
+    if download.received < size {
+      self.downloads.insert(id, download);
+      return;
+    }
+
+    // hashing reads the whole file, which must not hold up the tunnel
+    let verified = self.verified.clone();
+    let dir = self.dir.clone();
+    let Download { offer, part, .. } = download;
+    tokio::task::spawn_blocking(move || {
+      let res = finish(&part, &offer, &dir);
+      // the tunnel is closed, the partial file is kept for the next attempt anyway
+      let _ = verified.send((id, res));
+    });
+  }
+
+  /// Reads the next chunk of the first accepted file
+  ///
+  /// Cancel safe, data read before being cancelled becomes part of the next chunk.
+  /// Never completes while [Transfers::uploading] is `false`.
+  pub async fn next_chunk(&mut self, frontend: &dyn Frontend) -> Option<FilePacket> {
+    let upload = match self.uploads.front_mut() {
+      Some(upload) => upload,
+      None => return std::future::pending().await,
+    };
+    if let Err(err) = upload.fill().await {
+      let id = upload.id;
+      self.uploads.pop_front();
+      self.fail(frontend, &id, err);
+      return None;
+    }
+
+    let data = std::mem::take(&mut upload.buf);
+    let offset = upload.sent;
+    upload.sent += data.len() as u64;
+    let (id, sent, size) = (upload.id, upload.sent, upload.size);
+
+    self.progress(frontend, &id, offset, sent, size);
+    if sent >= size {
+      self.uploads.pop_front();
+      let id = id.to_string();
+      self.emit(frontend, FileEvent::Done { id, path: None });
+    }
+    Some(FilePacket::Chunk { id, offset, data })
+  }
+
+  /// Tells the frontend about the result of verifying a completed download
+  pub fn report(&self, frontend: &dyn Frontend, verified: Verified) {
+    match verified {
+      (id, Ok(path)) => {
+        let id = id.to_string();
+        self.emit(
+          frontend,
+          FileEvent::Done {
+            id,
+            path: Some(path),
+          },
+        );
+      }
+      (id, Err(err)) => self.fail(frontend, &id, err),
+    }
+  }
+
+  /// Emits the progress of a transfer whenever another percent of the file was transferred
+  fn progress(&self, frontend: &dyn Frontend, id: &FileId, before: u64, done: u64, size: u64) {
+    let percent = |bytes: u64| (bytes * 100).checked_div(size).unwrap_or(100);
+    if percent(before) != percent(done) || done == size {
+      let id = id.to_string();
+      self.emit(frontend, FileEvent::Progress { id, done, size });
+    }
+  }
+
+  fn fail(&self, frontend: &dyn Frontend, id: &FileId, err: io::Error) {
+    log::warn!("file transfer '{}' failed: '{}'", id, err);
+    let event = FileEvent::Failed {
+      id: id.to_string(),
+      reason: err.to_string(),
+    };
+    self.emit(frontend, event);
+  }
+
+  fn emit(&self, frontend: &dyn Frontend, event: FileEvent) {
+    frontend.emit(&self.event, event);
+  }
+}
+
+/// Describes the file at `path` so it can be offered to a peer
+///
+/// Reads the whole file to hash it and should therefore be run using [tokio::task::spawn_blocking].
+///
+/// # Errors
+/// This function will return:</br>
+/// [ErrorKind::InvalidInput] if `path` does not end in a file name</br>
+/// Any [io::Error] from reading the file
+pub fn offer(path: PathBuf) -> io::Result<FileOffer> {
+  let name = match path.file_name() {
+    Some(name) => name.to_string_lossy().into_owned(),
+    None => {
+      return Err(io::Error::new(
+        ErrorKind::InvalidInput,
+        "path does not point to a file",
+      ))
+    }
+  };
+
+  let mut hasher = Sha256::new();
+  let size = io::copy(&mut fs::File::open(&path)?, &mut hasher)?;
+  Ok(FileOffer {
+    id: FileId::random(),
+    name,
+    size,
+    sha256: hasher.finalize().into(),
+    path: Some(path),
+  })
+}
+
+/// Checks the hash of the completed download `part` and moves it into `dir`
+///
+/// Downloads that do not match the offer are deleted.
+fn finish(part: &Path, offer: &FileOffer, dir: &Path) -> io::Result<PathBuf> {
+  let mut hasher = Sha256::new();
+  io::copy(&mut fs::File::open(part)?, &mut hasher)?;
+  if hasher.finalize()[..] != offer.sha256[..] {
+    fs::remove_file(part)?;
+    return Err(io::Error::new(
+      ErrorKind::InvalidData,
+      "SHA-256 of the received file does not match the offer",
+    ));
+  }
+
+  let target = unused_path(dir, &offer.name);
+  fs::rename(part, &target)?;
+  Ok(target)
+}
+
+/// Returns `dir/name` or, if that exists already, the first free `dir/name (n).ext`
+fn unused_path(dir: &Path, name: &str) -> PathBuf {
+  let target = dir.join(name);
+  if !target.exists() {
+    return target;
+  }
+
+  let name = Path::new(name);
+  let stem = name.file_stem().unwrap_or_default().to_string_lossy();
+  let ext = match name.extension() {
+    Some(ext) => format!(".{}", ext.to_string_lossy()),
+    None => String::new(),
+  };
+  (1..)
+    .map(|n| dir.join(format!("{} ({}){}", stem, n, ext)))
+    .find(|path| !path.exists())
+    .unwrap()
+}
+
+/// Strips everything but the file name from `name`,
+/// so a peer cannot choose where a download is stored
+fn file_name(name: &str) -> String {
+  let name = name.replace('\\', "/");
+  match Path::new(&name).file_name() {
+    Some(name) => name.to_string_lossy().into_owned(),
+    None => "download".to_string(),
+  }
+}
+
+fn not_offered() -> io::Error {
+  io::Error::new(ErrorKind::NotFound, "file was not offered")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::network::frontend::Recorder;
+
+  fn states(events: &Recorder) -> Vec<String> {
+    events
+      .payloads()
+      .iter()
+      .map(|event| event["state"].as_str().unwrap().to_string())
+      .collect()
+  }
+
+  fn temp_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("emberry-transfer-{}", rand::random::<u64>()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  /// Offers `offer` from `sender` to `receiver` and returns the offset the receiver asked for
+  async fn offer_accept(sender: &mut Transfers, receiver: &mut Transfers, offer: FileOffer) -> u64 {
+    let events = Recorder::default();
+    let id = offer.id;
+    let mut packet = FilePacket::Offer(offer);
+    sender.prepare(&mut packet).await.unwrap();
+    receiver.handle(packet, &events, "peer").await;
+
+    let mut accept = FilePacket::Accept { id, offset: 0 };
+    receiver.prepare(&mut accept).await.unwrap();
+    let offset = match accept {
+      FilePacket::Accept { offset, .. } => offset,
+      _ => unreachable!(),
+    };
+    sender.handle(accept, &events, "peer").await;
+    offset
+  }
+
+  /// Tests if names sent by a peer cannot escape the downloads directory
+  #[test]
+  fn strip_directories() {
+    assert_eq!(file_name("../../.bashrc"), ".bashrc");
+    assert_eq!(file_name("C:\\Windows\\evil.dll"), "evil.dll");
+    assert_eq!(file_name(".."), "download");
+    assert_eq!(file_name("notes.txt"), "notes.txt");
+  }
+
+  /// Tests if an interrupted download is resumed and verified once it is complete
+  #[tokio::test]
+  async fn resume_download() {
+    let dir = temp_dir();
+    let source = dir.join("source.bin");
+    let content: Vec<u8> = (0..CHUNK_SIZE * 3 + 42).map(|i| i as u8).collect();
+    fs::write(&source, &content).unwrap();
+    let offer = offer(source).unwrap();
+    assert_eq!(offer.size, content.len() as u64);
+
+    let downloads = dir.join("downloads");
+    let sender_events = Recorder::default();
+    let receiver_events = Recorder::default();
+    let (mut sender, _) = Transfers::new("sender".to_string(), dir.clone());
+
+    // the first tunnel breaks after a single chunk
+    let (mut receiver, _) = Transfers::new("receiver".to_string(), downloads.clone());
+    let offset = offer_accept(&mut sender, &mut receiver, offer.clone()).await;
+    assert_eq!(offset, 0);
+    let chunk = sender.next_chunk(&sender_events).await.unwrap();
+    receiver.handle(chunk, &receiver_events, "peer").await;
+    sender.uploads.clear();
+
+    let (mut receiver, mut verified) = Transfers::new("receiver".to_string(), downloads.clone());
+    let mut again = offer.clone();
+    again.id = FileId::random();
+    let offset = offer_accept(&mut sender, &mut receiver, again).await;
+    assert_eq!(offset, CHUNK_SIZE as u64);
+
+    while sender.uploading() {
+      let chunk = sender.next_chunk(&sender_events).await.unwrap();
+      receiver.handle(chunk, &receiver_events, "peer").await;
+    }
+    let res = verified.recv().await.unwrap();
+    receiver.report(&receiver_events, res);
+
+    assert_eq!(states(&sender_events).last().unwrap(), "done");
+    assert_eq!(states(&receiver_events).last().unwrap(), "done");
+    assert_eq!(fs::read(downloads.join("source.bin")).unwrap(), content);
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  /// Tests if a download that does not match the offered hash is deleted and reported as failed
+  #[tokio::test]
+  async fn corrupt_download() {
+    let dir = temp_dir();
+    let source = dir.join("source.bin");
+    fs::write(&source, b"emberry").unwrap();
+    let mut offer = offer(source).unwrap();
+    offer.sha256 = [0; 32];
+
+    let events = Recorder::default();
+    let (mut sender, _) = Transfers::new("sender".to_string(), dir.clone());
+    let (mut receiver, mut verified) =
+      Transfers::new("receiver".to_string(), dir.join("downloads"));
+    offer_accept(&mut sender, &mut receiver, offer).await;
+
+    let chunk = sender.next_chunk(&events).await.unwrap();
+    receiver.handle(chunk, &events, "peer").await;
+    let res = verified.recv().await.unwrap();
+    assert_eq!(res.1.unwrap_err().kind(), ErrorKind::InvalidData);
+    assert_eq!(fs::read_dir(dir.join("downloads")).unwrap().count(), 0);
+    fs::remove_dir_all(dir).unwrap();
+  }
+}
@@ -0,0 +1,149 @@
+//! Typing indicators of the users on both ends of a P2P tunnel
+//!
+//! The frontend may report typing on every key stroke, the [Throttle] only lets a start through
+//! every [TYPING_THROTTLE] while the user keeps typing. The receiving [Indicator] treats every
+//! start as valid for [TYPING_EXPIRY], so it clears itself if the stop never arrives.
+
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::time::Instant;
+
+use crate::network::Frontend;
+
+/// Shortest time between two typing starts sent to the peer
+pub const TYPING_THROTTLE: Duration = Duration::from_secs(3);
+/// Time after which the typing indicator of the peer expires without a new start
+pub const TYPING_EXPIRY: Duration = Duration::from_secs(8);
+
+#[derive(Clone, Copy, Serialize)]
+enum TypingSignal {
+  TypingStarted,
+  TypingStopped,
+}
+
+/// Payload of the `message_recieved_<id>` event for typing changes of the peer,
+/// shaped like the payload of signals without content
+#[derive(Clone, Serialize)]
+struct TypingPayload {
+  message: TypingSignal,
+}
+
+/// Limits the typing signals of the local user that are sent to the peer
+#[derive(Default)]
+pub struct Throttle {
+  last_start: Option<Instant>,
+}
+
+impl Throttle {
+  /// Returns `true` if the typing change `typing` of the local user at `now` has to be sent
+  ///
+  /// Starts are only sent once every [TYPING_THROTTLE],
+  /// stops are only sent if the peer was told about a start
+  pub fn pass(&mut self, typing: bool, now: Instant) -> bool {
+    match (typing, self.last_start) {
+      (true, Some(last)) if now < last + TYPING_THROTTLE => false,
+      (true, _) => {
+        self.last_start = Some(now);
+        true
+      }
+      (false, Some(_)) => {
+        self.last_start = None;
+        true
+      }
+      (false, None) => false,
+    }
+  }
+
+  /// Forgets the last start as the peer stops showing the indicator once a chat message arrives
+  pub fn reset(&mut self) {
+    self.last_start = None;
+  }
+}
+
+/// Whether the peer is typing right now
+#[derive(Default)]
+pub struct Indicator {
+  until: Option<Instant>,
+}
+
+impl Indicator {
+  /// Applies the typing change `typing` of the peer received at `now`
+  /// and tells the frontend about it if the indicator changed
+  pub fn update(&mut self, typing: bool, now: Instant, frontend: &dyn Frontend, event: &str) {
+    let changed = self.until.is_some() != typing;
+    self.until = typing.then(|| now + TYPING_EXPIRY);
+    if changed {
+      let message = if typing {
+        TypingSignal::TypingStarted
+      } else {
+        TypingSignal::TypingStopped
+      };
+      frontend.emit(event, TypingPayload { message });
+    }
+  }
+
+  /// Time at which the indicator expires, `None` if the peer is not typing
+  pub fn deadline(&self) -> Option<Instant> {
+    self.until
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use super::*;
+  use crate::network::frontend::Recorder;
+
+  /// Tests if starts are throttled while typing and stops only follow sent starts
+  #[test]
+  fn throttle() {
+    let start = Instant::now();
+    let mut throttle = Throttle::default();
+    let steps = [
+      (false, Duration::ZERO, false),
+      (true, Duration::ZERO, true),
+      (true, Duration::from_secs(1), false),
+      (true, TYPING_THROTTLE, true),
+      (false, TYPING_THROTTLE, true),
+      (false, TYPING_THROTTLE, false),
+      (true, TYPING_THROTTLE, true),
+    ];
+    for (i, (typing, after, expected)) in steps.into_iter().enumerate() {
+      assert_eq!(
+        throttle.pass(typing, start + after),
+        expected,
+        "\nstep {}: pass returned 'left' but 'right' was expected",
+        i
+      );
+    }
+  }
+
+  /// Tests if the frontend only hears about changes and repeated starts extend the indicator
+  #[test]
+  fn indicator() {
+    let start = Instant::now();
+    let events = Recorder::default();
+    let mut indicator = Indicator::default();
+
+    indicator.update(true, start, &events, "typing");
+    indicator.update(true, start + TYPING_THROTTLE, &events, "typing");
+    assert_eq!(
+      indicator.deadline(),
+      Some(start + TYPING_THROTTLE + TYPING_EXPIRY)
+    );
+    indicator.update(false, start + TYPING_EXPIRY, &events, "typing");
+    indicator.update(false, start + TYPING_EXPIRY, &events, "typing");
+    assert_eq!(indicator.deadline(), None);
+
+    assert_eq!(
+      events.payloads(),
+      vec![
+        json!({ "message": "TypingStarted" }),
+        json!({ "message": "TypingStopped" })
+      ],
+      "\nemitted 'left' but 'right' was expected"
+    );
+  }
+}
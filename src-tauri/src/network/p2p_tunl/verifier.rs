@@ -0,0 +1,80 @@
+use std::time::SystemTime;
+
+use rustls::{
+  client::{ServerCertVerified, ServerCertVerifier},
+  Certificate, Error, ServerName,
+};
+
+/// Verifies the certificate of a peer by comparing it to the one rhizome told us about
+///
+/// The peer is identified by its certificate alone, so the names it carries are not checked.
+/// The handshake signatures are still verified against the pinned certificate.
+pub struct PinnedCertVerifier {
+  pinned: Certificate,
+}
+
+impl PinnedCertVerifier {
+  pub fn new(pinned: Certificate) -> PinnedCertVerifier {
+    PinnedCertVerifier { pinned }
+  }
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+  fn verify_server_cert(
+    &self,
+    end_entity: &Certificate,
+    _intermediates: &[Certificate],
+    _server_name: &ServerName,
+    _scts: &mut dyn Iterator<Item = &[u8]>,
+    _ocsp_response: &[u8],
+    _now: SystemTime,
+  ) -> Result<ServerCertVerified, Error> {
+    if *end_entity == self.pinned {
+      Ok(ServerCertVerified::assertion())
+    } else {
+      Err(Error::InvalidCertificateData(
+        "peer presented a certificate other than the pinned one".to_string(),
+      ))
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use rcgen::generate_simple_self_signed;
+
+  use super::*;
+
+  fn sample_cert(name: &str) -> Certificate {
+    let cert = generate_simple_self_signed(vec![name.to_string()]).unwrap();
+    Certificate(cert.serialize_der().unwrap())
+  }
+
+  fn verify(verifier: &PinnedCertVerifier, cert: &Certificate) -> bool {
+    let name = ServerName::try_from("emberry_user").unwrap();
+    verifier
+      .verify_server_cert(
+        cert,
+        &[],
+        &name,
+        &mut std::iter::empty(),
+        &[],
+        SystemTime::now(),
+      )
+      .is_ok()
+  }
+
+  /// Tests if the pinned certificate is accepted whatever name it carries and any other one is rejected
+  #[test]
+  fn pinned_only() {
+    let old = sample_cert("embery_user");
+    let new = sample_cert("emberry_user");
+
+    assert!(verify(&PinnedCertVerifier::new(old.clone()), &old));
+    assert!(verify(&PinnedCertVerifier::new(new.clone()), &new));
+    assert!(
+      !verify(&PinnedCertVerifier::new(old), &new),
+      "\nverify_server_cert accepted a certificate that was not pinned"
+    );
+  }
+}
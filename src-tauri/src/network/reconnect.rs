@@ -0,0 +1,200 @@
+//! Re-establishing P2P tunnels that dropped without either side closing the room
+//!
+//! A room whose p2p loop ends with a timeout or an I/O error is kept as lost,
+//! the control channel requests a new room with the peer (see [Reconnects::requests])
+//! and the peer accepts it without asking its user as it lost the room as well.
+//! The new tunnel continues under the id of the lost room, so the frontend keeps its chat.
+//! Until then the `send_message_<id>` subscription of the lost room stays active
+//! and chat messages written in between end up in the outbox.
+
+use std::{
+  collections::HashMap,
+  sync::{Arc, Mutex},
+};
+
+use smoke::{messages::RoomId, User};
+use tokio::sync::{mpsc, MutexGuard};
+
+use super::frontend::Unlisten;
+use super::p2p_tunl::CloseReason;
+
+struct Lost {
+  room: RoomId,
+  reason: CloseReason,
+  /** Ends the `send_message_<id>` subscription of the lost room */
+  unlisten: Unlisten,
+}
+
+/// Rooms whose P2P tunnel dropped and that wait for a new one
+#[derive(Clone)]
+pub struct Reconnects {
+  lost: Arc<Mutex<HashMap<User, Lost>>>,
+  requests: mpsc::UnboundedSender<User>,
+  pending: Arc<tokio::sync::Mutex<mpsc::UnboundedReceiver<User>>>,
+}
+
+impl Default for Reconnects {
+  fn default() -> Self {
+    let (requests, pending) = mpsc::unbounded_channel();
+    Reconnects {
+      lost: Default::default(),
+      requests,
+      pending: Arc::new(tokio::sync::Mutex::new(pending)),
+    }
+  }
+}
+
+impl Reconnects {
+  /// Receives peers that a new room has to be requested with
+  ///
+  /// The receiver is locked for as long as the guard lives,
+  /// so only one control channel can take care of the lost rooms at a time.
+  pub async fn requests(&self) -> MutexGuard<'_, mpsc::UnboundedReceiver<User>> {
+    self.pending.lock().await
+  }
+
+  /// Requests a new room for every lost room again,
+  /// used by a new control channel as requests sent without one got lost
+  pub fn request_all(&self) {
+    let lost = self.lost.lock().unwrap();
+    for peer in lost.keys() {
+      let _ = self.requests.send(peer.clone());
+    }
+  }
+
+  /// Keeps the room `room` with `peer` that ended because of `reason` until a new tunnel continues it
+  pub(crate) fn lose(&self, peer: User, room: RoomId, reason: CloseReason, unlisten: Unlisten) {
+    let lost = Lost {
+      room,
+      reason,
+      unlisten,
+    };
+    let replaced = self.lost.lock().unwrap().insert(peer.clone(), lost);
+    if let Some(replaced) = replaced {
+      (replaced.unlisten)();
+    }
+    // the control channel only requests rooms that are lost, so the entry has to exist first
+    let _ = self.requests.send(peer);
+  }
+
+  /// Returns `true` if the room with `peer` was lost and waits for a new tunnel
+  pub fn is_lost(&self, peer: &User) -> bool {
+    self.lost.lock().unwrap().contains_key(peer)
+  }
+
+  /// Returns `true` if `room` was lost and waits for a new tunnel
+  pub fn contains(&self, room: &RoomId) -> bool {
+    let lost = self.lost.lock().unwrap();
+    lost.values().any(|lost| lost.room == *room)
+  }
+
+  /// Takes the lost room with `peer` so a new tunnel can continue it
+  ///
+  /// Ends the subscription of the lost room, the new tunnel has to subscribe again.
+  pub(crate) fn resume(&self, peer: &User) -> Option<RoomId> {
+    let lost = self.lost.lock().unwrap().remove(peer)?;
+    (lost.unlisten)();
+    Some(lost.room)
+  }
+
+  /// Stops waiting for a new tunnel to `peer`
+  ///
+  /// Returns the lost room and the reason it was lost, `None` if there is no lost room with `peer`
+  pub(crate) fn give_up(&self, peer: &User) -> Option<(RoomId, CloseReason)> {
+    let lost = self.lost.lock().unwrap().remove(peer)?;
+    (lost.unlisten)();
+    Some((lost.room, lost.reason))
+  }
+
+  /// Stops waiting for a new tunnel for `room`
+  ///
+  /// Returns `false` if `room` was not lost
+  pub(crate) fn give_up_room(&self, room: &RoomId) -> bool {
+    let peer = {
+      let lost = self.lost.lock().unwrap();
+      lost
+        .iter()
+        .find(|(_, lost)| lost.room == *room)
+        .map(|(peer, _)| peer.clone())
+    };
+    peer.and_then(|peer| self.give_up(&peer)).is_some()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::atomic::{AtomicUsize, Ordering};
+
+  use super::*;
+
+  fn sample_peer(byte: u8) -> User {
+    User {
+      cert_data: vec![byte; 8],
+    }
+  }
+
+  fn counting_unlisten(count: &Arc<AtomicUsize>) -> Unlisten {
+    let count = count.clone();
+    Box::new(move || {
+      count.fetch_add(1, Ordering::SeqCst);
+    })
+  }
+
+  /// Tests if a lost room is requested again and continued under its old id
+  #[tokio::test]
+  async fn resume_lost() {
+    let reconnects = Reconnects::default();
+    let unlistened = Arc::new(AtomicUsize::new(0));
+    let (peer, room) = (sample_peer(1), RoomId([7; 32]));
+
+    reconnects.lose(
+      peer.clone(),
+      room.clone(),
+      CloseReason::Timeout,
+      counting_unlisten(&unlistened),
+    );
+    assert_eq!(reconnects.requests().await.recv().await, Some(peer.clone()));
+    assert!(reconnects.is_lost(&peer) && reconnects.contains(&room));
+    assert_eq!(unlistened.load(Ordering::SeqCst), 0);
+
+    assert_eq!(
+      reconnects.resume(&peer),
+      Some(room.clone()),
+      "\nresume returned 'left' but 'right' was expected"
+    );
+    assert_eq!(unlistened.load(Ordering::SeqCst), 1);
+    assert_eq!(reconnects.resume(&peer), None);
+    assert!(!reconnects.contains(&room));
+  }
+
+  /// Tests if giving up a lost room ends its subscription and reports why it was lost
+  #[tokio::test]
+  async fn give_up_lost() {
+    let reconnects = Reconnects::default();
+    let unlistened = Arc::new(AtomicUsize::new(0));
+    let (peer, room) = (sample_peer(1), RoomId([7; 32]));
+    let (other, other_room) = (sample_peer(2), RoomId([8; 32]));
+
+    reconnects.lose(
+      peer.clone(),
+      room.clone(),
+      CloseReason::Timeout,
+      counting_unlisten(&unlistened),
+    );
+    let reason = CloseReason::Error {
+      message: "reset".to_string(),
+    };
+    reconnects.lose(
+      other.clone(),
+      other_room.clone(),
+      reason.clone(),
+      counting_unlisten(&unlistened),
+    );
+
+    assert_eq!(reconnects.give_up(&other), Some((other_room, reason)));
+    assert!(reconnects.give_up_room(&room));
+    assert!(!reconnects.give_up_room(&room));
+    assert!(!reconnects.is_lost(&peer));
+    assert_eq!(unlistened.load(Ordering::SeqCst), 2);
+  }
+}
@@ -0,0 +1,137 @@
+//! Replacing the identity of the local user without losing the contacts
+//!
+//! The new certificate is signed using the old key and sent to every peer with an open room.
+//! Peers check the signature and move the entry of the user to the new certificate.
+//! The rooms are closed afterwards as their tunnels were authenticated using the old identity.
+
+use std::io::{self, ErrorKind};
+
+use rustls::Certificate;
+use smoke::User;
+
+use crate::data::{
+  cert_gen::{self, RotationNotice},
+  config,
+  identity::SEALING,
+  sqlite::{try_exec, user},
+  UserIdentifier,
+};
+use crate::network::ctrl_chnl::{EmberryMessage, RhizomeConnection};
+use crate::network::{p2p_tunl::packet::Packet, Frontend, Networking};
+
+/// Payload of the "identity-rotated" and "user-rotated" events
+#[derive(Clone, serde::Serialize)]
+struct RotatedPayload {
+  old: String,
+  new: String,
+}
+
+/// Tauri command wrapper around [rotate] using `window` as [Frontend]
+///
+/// Returns the bs58 encoded certificate of the new identity
+///
+/// # Errors
+/// See [rotate]
+#[tauri::command(async)]
+pub async fn rotate_identity(
+  window: tauri::Window,
+  net: tauri::State<'_, Networking>,
+  rc: tauri::State<'_, RhizomeConnection>,
+) -> tauri::Result<String> {
+  let identity = rotate(&window, &net, &rc).await?;
+  Ok(identity.bs58.into_owned())
+}
+
+/// Replaces the identity of the local user by a new one and tells every peer with an open room,
+/// the change is reported using the "identity-rotated" event
+///
+/// The connection to rhizome is reestablished to identify using the new certificate.
+/// Peers without an open room keep knowing the user by the old identity.
+///
+/// # Errors
+/// This function will return:</br>
+/// [ErrorKind::NotFound] if there is no identity to replace</br>
+/// Any error from signing or storing the new identity, see [cert_gen::rotate_cert]
+pub async fn rotate(
+  frontend: &dyn Frontend,
+  net: &Networking,
+  rc: &RhizomeConnection,
+) -> io::Result<UserIdentifier<'static>> {
+  let (old, notice) = {
+    let mut pem_data = config::PEM_DATA.write().unwrap();
+    let (old_cert, old_key) = pem_data
+      .as_ref()
+      .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "There is no identity to rotate"))?;
+    // a protected identity stays protected by the same passphrase
+    let sealing = SEALING.read().unwrap();
+    let (cert, key, notice) =
+      cert_gen::rotate_cert(&config::PEM.filepath, old_key, sealing.as_ref())?;
+    let old = UserIdentifier::from(&User {
+      cert_data: old_cert.0.clone(),
+    });
+    *pem_data = Some((cert, key));
+    (old, notice)
+  };
+  let new = UserIdentifier::from(&User {
+    cert_data: notice.cert.clone(),
+  });
+
+  // the entry of the local user holds its username
+  let (from, to) = (old.clone(), new.clone());
+  if let Err(err) = try_exec(move |db| user::migrate(db, (&from, &to))).await {
+    log::warn!("Could not migrate the local user entry: '{}'", err);
+  }
+  if let Some(local) = config::IDI.write().unwrap().as_mut() {
+    local.identifier = new.clone();
+  }
+
+  let links: Vec<_> = {
+    let chats = net.chats.lock().unwrap();
+    chats.values().map(|con| con.packets.clone()).collect()
+  };
+  for link in links {
+    if link.send(Packet::Rotation(notice.clone())).await.is_err() {
+      log::debug!("Room closed before it was told about the new identity");
+    }
+  }
+
+  if let Some(rc) = &*rc.read().await {
+    let _ = rc.channel.send(EmberryMessage::Reconnect()).await;
+  }
+
+  frontend.emit(
+    "identity-rotated",
+    RotatedPayload {
+      old: old.bs58.into_owned(),
+      new: new.bs58.to_string(),
+    },
+  );
+  Ok(new)
+}
+
+/// Moves the entry of `peer` to the identity announced in `notice`
+/// if it was signed by `peer`, the change is reported using the "user-rotated" event
+pub(crate) async fn handle(peer: &User, notice: &RotationNotice, frontend: &dyn Frontend) {
+  let old_cert = Certificate(peer.cert_data.clone());
+  let new = match cert_gen::verify_rotation(&old_cert, notice) {
+    Ok(cert) => UserIdentifier::from(&User { cert_data: cert.0 }),
+    Err(err) => return log::warn!("Ignoring invalid identity rotation: '{}'", err),
+  };
+  let old = UserIdentifier::from(peer);
+
+  let (from, to) = (old.clone(), new.clone());
+  match try_exec(move |db| user::migrate(db, (&from, &to))).await {
+    Ok(_) => frontend.emit(
+      "user-rotated",
+      RotatedPayload {
+        old: old.bs58.into_owned(),
+        new: new.bs58.into_owned(),
+      },
+    ),
+    Err(err) => log::error!(
+      "Could not migrate '{}' to its new identity: '{}'",
+      old.bs58,
+      err
+    ),
+  }
+}